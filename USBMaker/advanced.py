@@ -0,0 +1,166 @@
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+from PyQt5 import QtWidgets, QtGui
+
+from PyQt5 import QtWidgets, QtGui
+
+
+class AdvancedOptions(QtWidgets.QDialog):
+    # The options that are only needed in some cases, so they don't take space in the main window.
+    # The dialog isn't in a .ui file, its options are added in __init__ (with the add_* functions),
+    # and get_options returns their values in the format used by worker.Worker.
+    def __init__(self):
+        super(AdvancedOptions, self).__init__()
+
+        self.setWindowTitle('Advanced options')
+        if QtGui.QIcon.hasThemeIcon('usbmaker'):
+            self.setWindowIcon(QtGui.QIcon.fromTheme('usbmaker'))
+
+        self.verticalLayout = QtWidgets.QVBoxLayout(self)
+        self.formLayout = QtWidgets.QFormLayout()
+        self.verticalLayout.addLayout(self.formLayout)
+
+        # The widget of each option, by the name of the option.
+        self.checkboxes = {}
+        self.comboboxes = {}
+        self.line_edits = {}
+
+        self.add_checkbox('check_image_state', 'Stop if the image is changed while it\'s written',
+                          'Compare the size, modification time and inode of the image before and after writing it, '
+                          'to detect if it was changed or replaced meanwhile (for example, on a network share)')
+        self.add_checkbox('check_filesystem', 'Check the new filesystem',
+                          'Check the filesystem for errors after creating it (with fsck.fat, fsck.exfat, ntfsfix, '
+                          'e2fsck or btrfs check), which can find a failing usb drive')
+        self.add_checkbox('check_capacity', 'Check for a counterfeit usb drive before writing to it',
+                          'Check that the device can store as much data as it reports, which fake drives can\'t. '
+                          'This erases the device, and takes a while')
+        self.add_checkbox('discard', 'Discard (TRIM) the device before partitioning it',
+                          'Tell the device that all of its data is unused (with blkdiscard), which can make writing '
+                          'faster on SSDs and some usb drives. Devices that don\'t support it are only partitioned')
+        self.add_checkbox('rescan', 'Rescan the device after writing the image (DD Image)',
+                          'Make the kernel read the device again, for enclosures that keep showing the old data '
+                          'until they\'re reconnected')
+        self.add_checkbox('partition_hashes', 'Show the hashes of the partitions when completed',
+                          'Compute the hash of the contents of each partition after creating the usb drive, to '
+                          'record its state')
+        self.add_checkbox('wait_for_reconnect', 'Wait for the device to reconnect after writing the image (DD Image)',
+                          'Some devices disconnect and connect again after their partition table is changed. Wait '
+                          'up to a minute for the device to come back, instead of stopping')
+        self.add_checkbox('eject', 'Eject the device when completed',
+                          'Unmount the partitions of the device and eject it, so that it can be unplugged')
+        self.add_checkbox('update', 'Only update the files of the usb drive (ISO Image)',
+                          'Copy the files that are new or changed in the iso file to a usb drive created from an '
+                          'older version of it, instead of recreating the drive. The selected filesystem must be the '
+                          'one of the drive')
+        self.add_checkbox('delete_removed', 'Delete the files that aren\'t in the iso file anymore when updating',
+                          'Only the files that were copied from the previous iso file are deleted')
+        self.add_checkbox('write_info_file', 'Write a file recording the iso file to the usb drive (ISO Image)',
+                          'Write the name and the hash of the iso file, the date and the version of USBMaker to a '
+                          'file at the root of the usb drive, to identify it later')
+        self.add_line_edit('info_file_name', 'Name of the info file', '',
+                           'If empty, it\'s USBMAKER_INFO.txt')
+        self.add_checkbox('set_ownership', 'Give the files to the user who started USBMaker (ext4 and Btrfs)',
+                          'Make the user who started USBMaker with pkexec or sudo the owner of the files, so they '
+                          'can be edited without root')
+        self.add_combobox('verify', 'Verification',
+                          [['Don\'t verify the written data', ''],
+                           ['Compare all of the written data', 'full'],
+                           ['Compare some parts of the written image (faster, DD Image)', 'sampled']],
+                          'Read the image back from the device after writing it, and compare it with the image file. '
+                          'With the ISO Image method, all the copied files are compared with the ones in the iso file')
+        self.add_line_edit('disk_identifier', 'Disk identifier', '',
+                           'The disk signature (8 hexadecimal digits, like 1234ABCD) of an MBR partition table, or '
+                           'the disk GUID of a GPT partition table. If empty, it\'s random')
+        self.add_line_edit('deterministic_name', 'Name for reproducible partition tables', '',
+                           'Derive the disk identifier and the GUID of the partition from this name instead of '
+                           'making them random, so that drives or disk images created with the same name have '
+                           'identical partition tables')
+        self.add_line_edit('gpt_attributes', 'GPT partition attributes', '',
+                           'A comma-separated list of the attribute bits to set on the partition of a GPT partition '
+                           'table (for example, 62,63 to hide it and stop Windows from mounting it)')
+        self.add_line_edit('gpt_entries', 'GPT partition entries', '',
+                           'The number of partition entries of a GPT partition table, a multiple of 4 from 128 to '
+                           '8128. If empty, it\'s 128')
+        self.add_line_edit('ext4_features', 'ext4 features', '',
+                           'A comma-separated list of features to enable, or to disable if prefixed with "^" (for '
+                           'example, ^64bit,^metadata_csum for older bootloaders)')
+        self.add_checkbox('verify_checksum', 'Verify the checksum of the image before writing it',
+                          'Compare the SHA-256 hash of the image with the one in a SHA256SUMS file, like the ones '
+                          'published by most distributions')
+        self.add_line_edit('sums_file', 'SHA256SUMS file', 'file',
+                           'The file with the hash of the image. If empty, it\'s searched next to the image')
+        self.add_line_edit('custom_config', 'Boot menu config file (ISO Image)', 'file',
+                           'A syslinux.cfg or grub.cfg file that replaces the one of the iso file, for example for '
+                           'unattended installs')
+        self.add_line_edit('archive', 'Archive to extract (Format)', 'file',
+                           'A tar archive (like .tar, .tar.gz or .tar.zst) to extract to the usb drive after '
+                           'formatting it')
+        self.add_line_edit('work_dir', 'Temporary directory', 'directory',
+                           'Where the image is read back to when comparing all of it (it needs as much free space as '
+                           'the image\'s size). The default is the system\'s temporary directory')
+
+        self.buttonBox = QtWidgets.QDialogButtonBox(QtWidgets.QDialogButtonBox.Close)
+        self.buttonBox.rejected.connect(self.close)
+        self.verticalLayout.addWidget(self.buttonBox)
+
+    def add_checkbox(self, name, text, tooltip=''):
+        checkbox = QtWidgets.QCheckBox(text, self)
+        checkbox.setToolTip(tooltip)
+        self.formLayout.addRow(checkbox)
+        self.checkboxes[name] = checkbox
+
+    def add_combobox(self, name, text, items, tooltip=''):
+        # items is a list of [text, value] pairs.
+        combobox = QtWidgets.QComboBox(self)
+        combobox.setToolTip(tooltip)
+        for item_text, value in items:
+            combobox.addItem(item_text, value)
+        self.formLayout.addRow(text, combobox)
+        self.comboboxes[name] = combobox
+
+    def add_line_edit(self, name, text, browse='', tooltip=''):
+        # If browse is 'file' or 'directory', a button to select one is added after the line edit.
+        line_edit = QtWidgets.QLineEdit(self)
+        line_edit.setToolTip(tooltip)
+        if browse == '':
+            self.formLayout.addRow(text, line_edit)
+        else:
+            button = QtWidgets.QPushButton('Browse...', self)
+            button.clicked.connect(lambda: self.browse(line_edit, browse))
+            layout = QtWidgets.QHBoxLayout()
+            layout.addWidget(line_edit)
+            layout.addWidget(button)
+            self.formLayout.addRow(text, layout)
+        self.line_edits[name] = line_edit
+
+    def browse(self, line_edit, browse):
+        if browse == 'directory':
+            path = QtWidgets.QFileDialog.getExistingDirectory(self, directory=line_edit.text())
+        else:
+            path = QtWidgets.QFileDialog.getOpenFileName(self, directory=line_edit.text())[0]
+        # The path is '' if the user cancelled.
+        if path != '':
+            line_edit.setText(path)
+
+    def get_options(self):
+        options = {}
+        for name, checkbox in self.checkboxes.items():
+            options[name] = checkbox.isChecked()
+        for name, combobox in self.comboboxes.items():
+            options[name] = combobox.currentData()
+        for name, line_edit in self.line_edits.items():
+            options[name] = line_edit.text()
+        return options
@@ -0,0 +1,101 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import os
+import re
+import hashlib
+import usb_info
+
+
+# algorithm can be any algorithm supported by hashlib (see hashlib.algorithms_available).
+# SHA-256 is the default, since it's what most distributions publish. SHA-1 and MD5 are
+# faster, but only useful against corruption, not tampering. BLAKE2 is both fast and secure.
+# DEFAULT_ALGORITHM is used by every function that computes hashes (here and in the other modules),
+# unless another algorithm is given.
+DEFAULT_ALGORITHM = 'sha256'
+
+
+def get_file_hash(path, algorithm=DEFAULT_ALGORITHM):
+    # Returns the hash of the file, as a hexadecimal string.
+    file_hash = hashlib.new(algorithm)
+    with open(path, 'rb') as file:
+        file_buffer = file.read(2**23)
+        while len(file_buffer) > 0:
+            file_hash.update(file_buffer)
+            file_buffer = file.read(2**23)
+    return file_hash.hexdigest()
+
+
+def get_partition_hashes(device, algorithm=DEFAULT_ALGORITHM):
+    # Returns a dictionary with the hash of the contents of each partition of the device
+    # (for example: {'sdb1': '...'}), to record the state of a drive after it's written.
+    # The partitions should be unmounted, so their contents don't change while being read.
+    partition_hashes = {}
+    for partition in sorted(usb_info.get_partitions(device)):
+        partition_hashes[partition] = get_file_hash('/dev/' + partition, algorithm)
+    return partition_hashes
+
+
+def get_expected_hash(sums_file, iso, algorithm=DEFAULT_ALGORITHM):
+    # Returns the hash of the iso file listed in a SHA256SUMS file (or SHA512SUMS, etc.,
+    # depending on the algorithm), like the ones published by most distributions next to their iso files.
+    # Lines can be in the format used by sha256sum ("<hash>  <file>", or "<hash> *<file>"
+    # in binary mode) or in the BSD format ("SHA256 (<file>) = <hash>").
+    iso_name = os.path.basename(iso)
+    hash_length = str(hashlib.new(algorithm).digest_size * 2)
+
+    with open(sums_file, mode='r', encoding='utf_8') as sums:
+        for line in sums:
+            line = line.rstrip('\r\n')
+            bsd_line = re.match('([A-Za-z0-9-]+) \\((.+)\\) = ([0-9a-fA-F]{' + hash_length + '})$', line)
+            gnu_line = re.match('([0-9a-fA-F]{' + hash_length + '}) [ *](.+)$', line)
+
+            # The BSD format names the algorithm (for example: SHA256 or BLAKE2b), which has to match.
+            if bsd_line is not None and bsd_line.group(1).lower().replace('-', '') == algorithm.lower() and \
+                    os.path.basename(bsd_line.group(2)) == iso_name:
+                return bsd_line.group(3).lower()
+            elif gnu_line is not None and os.path.basename(gnu_line.group(2)) == iso_name:
+                return gnu_line.group(1).lower()
+
+    raise HashNotFoundError
+
+
+def find_sums_file(iso, algorithm=DEFAULT_ALGORITHM):
+    # Returns the path of a sums file that lists the iso file, in the same directory as it
+    # (for example: SHA256SUMS, sha256sum.txt or image.iso.sha256), or '' if there's none.
+    directory = os.path.dirname(os.path.abspath(iso))
+    names = [algorithm.upper() + 'SUMS', algorithm.upper() + 'SUMS.txt', algorithm.lower() + 'sum.txt',
+             algorithm.lower() + 'sums.txt', os.path.basename(iso) + '.' + algorithm.lower(),
+             os.path.basename(iso) + '.' + algorithm.lower() + 'sum']
+    for name in names:
+        sums_file = os.path.join(directory, name)
+        if os.path.isfile(sums_file):
+            try:
+                get_expected_hash(sums_file, iso, algorithm)
+                return sums_file
+            except (HashNotFoundError, UnicodeDecodeError):
+                pass
+    return ''
+
+
+def verify_iso(iso, sums_file, algorithm=DEFAULT_ALGORITHM):
+    # Returns True if the iso file's hash matches the one in the sums file.
+    return get_file_hash(iso, algorithm) == get_expected_hash(sums_file, iso, algorithm)
+
+
+class HashNotFoundError(Exception):
+    pass
@@ -16,35 +16,264 @@
 #   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
 
 import subprocess
-import hashlib
 import os
+import re
+import shutil
+import usb_info
+import checksum
+import mount
 
+# 4096 bytes, so the blocks checked by check_capacity are aligned on devices with 4096 byte sectors too.
+CAPACITY_BLOCK_SIZE = 4096
 
-def dd(iso, device):
-    subprocess.run(['dd', 'if=' + iso, 'of=/dev/' + device])
 
+def dd(iso, device, sparse=False, partition='', flush='fdatasync', offset=0, progress=None):
+    # Returns the number of bytes written to the device.
+    # iso can be '-' to read the image from stdin (for example, piped from a download).
+    # progress is a function that is called with the number of bytes written so far, while dd runs.
+    # If partition is given, the image is written to that partition instead of the
+    # whole device (for example, to refresh the image of a boot partition).
+    # offset is the position (in bytes) in the device or partition where the image starts.
+    # This is only needed in advanced cases, like cloning an image made for a device with
+    # a different sector size, and it must be a multiple of the logical sector size.
+    if partition != '':
+        target = usb_info.get_partition_name(device, partition)
+        target_size = usb_info.get_partition_size(device, partition)
+    else:
+        target = device
+        target_size = usb_info.get_size(device)
+
+    if offset < 0 or offset >= target_size or offset % usb_info.get_block_size(device) != 0:
+        raise InvalidOffsetError
+    # The kernel already stops writes at the end of the device or partition, but then
+    # the image would be cut short, so it's refused before writing anything.
+    if iso != '-' and offset + os.path.getsize(iso) > target_size:
+        raise ImageTooLargeError
+
+    if iso == '-':
+        # The image is read from stdin, so it can be piped in without a temporary file.
+        args = ['dd', 'of=/dev/' + target, 'bs=' + str(usb_info.get_write_block_size(device))]
+    else:
+        args = ['dd', 'if=' + iso, 'of=/dev/' + target, 'bs=' + str(usb_info.get_write_block_size(device))]
+
+    if offset != 0:
+        args += ['seek=' + str(offset), 'oflag=seek_bytes']
+
+    conv = []
+    if sparse:
+        # Blocks of zeros are skipped instead of written, which is faster and saves flash wear.
+        # The skipped areas keep whatever they contained before, so this should only be used
+        # if the device was discarded (or zeroed) beforehand, or is a new image file.
+        conv.append('sparse')
+    if flush == 'fdatasync' or flush == 'fsync':
+        # dd flushes the data to the device before exiting, so when it finishes the image was
+        # really written (and not just cached). fdatasync is enough for a block device, since
+        # there's no metadata to flush. fsync also flushes the metadata, which only matters when
+        # writing to an image file. If flush is '', the data is flushed later by the system.
+        conv.append(flush)
+    if len(conv) > 0:
+        args.append('conv=' + ','.join(conv))
+
+    if progress is not None:
+        # dd prints the number of bytes written every second, on the same line (separated by "\r").
+        args.append('status=progress')
+
+    # The number of bytes written is read from the summary dd prints when it
+    # finishes (even if it fails), so the locale is set to C to keep it in English.
+    bytes_written = 0
+    with subprocess.Popen(args, stderr=subprocess.PIPE, env=dict(os.environ, LC_ALL='C')) as dd_process:
+        dd_output = b''
+        while True:
+            output = dd_process.stderr.read1(4096)
+            if output == b'':
+                break
+            # The last line may be incomplete, so it's kept until the rest of it is read.
+            lines = re.split(b'[\r\n]', dd_output + output)
+            dd_output = lines.pop()
+            for line in lines:
+                line_match = re.match(b'([0-9]+) bytes', line)
+                if line_match is not None:
+                    bytes_written = int(line_match.group(1))
+                    if progress is not None:
+                        progress(bytes_written)
+
+    return bytes_written
+
+
+def get_image_state(iso):
+    # Returns the size, modification time and inode of the image, which are compared
+    # before and after it's written to detect if it was changed or replaced meanwhile
+    # (for example, on a network share).
+    image_stat = os.stat(iso)
+    return [image_stat.st_size, image_stat.st_mtime_ns, image_stat.st_ino]
 
-def dd_check(iso, device):
-    subprocess.run(['dd', 'if=/dev/' + device, 'of=/tmp/usbmaker_image_' + device + '.iso',
-                    'count=' + str(os.path.getsize(iso)/512)])
-    dd_hash = hashlib.sha512()
-    dd_iso = open('/tmp/usbmaker_image_' + device + '.iso', 'rb')
-    dd_iso_buffer = dd_iso.read(2**23)
-    while len(dd_iso_buffer) > 0:
-        dd_hash.update(dd_iso_buffer)
-        dd_iso_buffer = dd_iso.read(2**23)
-    dd_iso_hash = dd_hash.hexdigest()
-    os.remove('/tmp/usbmaker_image_' + device + '.iso')
 
-    iso_hash = hashlib.sha512()
-    orig_iso = open(iso, 'rb')
-    orig_iso_buffer = orig_iso.read(2**23)
-    while len(orig_iso_buffer) > 0:
-        iso_hash.update(orig_iso_buffer)
-        orig_iso_buffer = orig_iso.read(2**23)
-    orig_iso_hash = iso_hash.hexdigest()
+def drop_cache(device):
+    # Drops the device's data from the page cache, so it's read again from the device. Otherwise
+    # a verification could read the data that was just written from RAM, and miss write errors.
+    device_fd = os.open('/dev/' + device, os.O_RDONLY)
+    try:
+        os.posix_fadvise(device_fd, 0, 0, os.POSIX_FADV_DONTNEED)
+    finally:
+        os.close(device_fd)
+
+
+def dd_check(iso, device, algorithm=checksum.DEFAULT_ALGORITHM, work_dir=''):
+    # The image is read twice (to get its size and its hash), so this
+    # can't be used when the image was read from stdin.
+    # The contents of the device are copied to a temporary file in work_dir (by default, the system's
+    # temporary directory, which is often in RAM), so it needs as much free space as the image's size.
+    image_path = mount.get_temp_path('image_' + device + '.iso', work_dir)
+    if shutil.disk_usage(os.path.dirname(image_path)).free < os.path.getsize(iso):
+        raise InsufficientTempSpaceError(os.path.dirname(image_path))
+
+    drop_cache(device)
+    subprocess.run(['dd', 'if=/dev/' + device, 'of=' + image_path, 'iflag=count_bytes',
+                    'count=' + str(os.path.getsize(iso))])
+    dd_iso_hash = checksum.get_file_hash(image_path, algorithm)
+    os.remove(image_path)
+
+    orig_iso_hash = checksum.get_file_hash(iso, algorithm)
 
     if dd_iso_hash == orig_iso_hash:
         return True
     else:
         return False
+
+
+def get_sample_offsets(image_size, windows=8, window_size=1048576):
+    # Returns the offsets of the windows compared by dd_check_sampled: the first and
+    # the last window_size bytes of the image, and the given number of windows evenly
+    # spread between them.
+    if image_size <= window_size:
+        # The whole image fits in one window.
+        return [0]
+
+    last_offset = image_size - window_size
+    offsets = [0]
+    for i in range(1, windows + 1):
+        offsets.append(i * last_offset // (windows + 1))
+    offsets.append(last_offset)
+    return offsets
+
+
+def dd_check_sampled(iso, device, windows=8, window_size=1048576):
+    # A faster alternative to dd_check, which only compares some windows of the image with
+    # the device instead of all of it. It doesn't prove the whole image was written correctly,
+    # but catches most failed writes in a fraction of the time.
+    # Returns a dictionary with the windows that were checked ('checked') and the ones that
+    # didn't match ('mismatched'), as lists of (offset, size) tuples. The image was written
+    # correctly (as far as this can tell) if 'mismatched' is empty.
+    image_size = os.path.getsize(iso)
+    # The window can't be bigger than the image.
+    window_size = min(window_size, image_size)
+
+    result = {'checked': [], 'mismatched': []}
+    drop_cache(device)
+    with open(iso, 'rb') as orig_iso, open('/dev/' + device, 'rb') as dd_iso:
+        for offset in get_sample_offsets(image_size, windows, window_size):
+            orig_iso.seek(offset)
+            dd_iso.seek(offset)
+            result['checked'].append((offset, window_size))
+            if orig_iso.read(window_size) != dd_iso.read(window_size):
+                result['mismatched'].append((offset, window_size))
+
+    return result
+
+
+def probe_capacity(read_block, write_block, blocks):
+    # Finds the real capacity of a device, in blocks, like f3probe. read_block(index) and
+    # write_block(index, data) read and write the block with that index from the device itself.
+    # Counterfeit drives report a bigger size than their real capacity, and writes past it are
+    # either lost or wrap around to the beginning of the drive, overwriting other blocks. The
+    # wrapping is assumed to happen at a power of two, because the drive ignores the highest bits
+    # of the address.
+    def get_pattern(index):
+        # The same data in the wrong place must not match, so the index is part of the pattern.
+        return (os.urandom(16) + index.to_bytes(16, 'little')) * (CAPACITY_BLOCK_SIZE // 32)
+
+    written = {}
+
+    def probe(index):
+        # The block is good if it keeps the data written to it, and writing it didn't change
+        # any of the good blocks found before it (which means it wrapped around to them).
+        pattern = get_pattern(index)
+        write_block(index, pattern)
+        is_good = read_block(index) == pattern
+        for other_index, other_pattern in written.items():
+            if read_block(other_index) != other_pattern:
+                is_good = False
+                write_block(other_index, other_pattern)
+        if is_good:
+            written[index] = pattern
+        return is_good
+
+    if not probe(0):
+        return 0
+
+    # If a write wraps around at a power of two, reading that block returns the first block.
+    end = blocks
+    index = 1
+    while index < blocks:
+        if read_block(index) == written[0]:
+            end = index
+            break
+        index *= 2
+
+    if probe(end - 1):
+        return end
+
+    # The last good block is between the first block and end - 1 (which is bad).
+    good = 0
+    bad = end - 1
+    while bad - good > 1:
+        middle = (good + bad) // 2
+        if probe(middle):
+            good = middle
+        else:
+            bad = middle
+    return good + 1
+
+
+def check_capacity(device):
+    # Returns an estimate of the real capacity of the device (in bytes), which is its
+    # reported size if it isn't counterfeit. See probe_capacity.
+    # THIS DESTROYS THE DATA ON THE DEVICE.
+    return get_file_capacity('/dev/' + device, usb_info.get_size(device))
+
+
+def get_file_capacity(path, device_size):
+    # Like check_capacity, for the device (or file) at path, which reports device_size bytes.
+    device_fd = os.open(path, os.O_RDWR)
+    try:
+        # The blocks must be read from the device, not from the page cache, which would
+        # still have the data that was written, even if the device lost it.
+        def read_block(index):
+            os.posix_fadvise(device_fd, index * CAPACITY_BLOCK_SIZE, CAPACITY_BLOCK_SIZE, os.POSIX_FADV_DONTNEED)
+            return os.pread(device_fd, CAPACITY_BLOCK_SIZE, index * CAPACITY_BLOCK_SIZE)
+
+        def write_block(index, data):
+            os.pwrite(device_fd, data, index * CAPACITY_BLOCK_SIZE)
+            os.fsync(device_fd)
+            os.posix_fadvise(device_fd, index * CAPACITY_BLOCK_SIZE, CAPACITY_BLOCK_SIZE, os.POSIX_FADV_DONTNEED)
+
+        blocks = device_size // CAPACITY_BLOCK_SIZE
+        real_blocks = probe_capacity(read_block, write_block, blocks)
+    finally:
+        os.close(device_fd)
+
+    if real_blocks == blocks:
+        return device_size
+    return real_blocks * CAPACITY_BLOCK_SIZE
+
+
+class ImageTooLargeError(OSError):
+    pass
+
+
+class InvalidOffsetError(OSError):
+    pass
+
+
+class InsufficientTempSpaceError(OSError):
+    pass
@@ -0,0 +1,125 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import shutil
+
+# The commands of each dependency (finding any of them is enough):
+# - grub2: grub-install
+# - syslinux: extlinux
+# - grub4dos: bootlace.com or bootlace64.com (the 64 bit version)
+# - systemd-boot: bootctl
+# - cdrtools: isoinfo
+COMMANDS = {
+    'parted': ['parted'],
+    'grub2': ['grub-install'],
+    'syslinux': ['extlinux'],
+    'grub4dos': ['bootlace.com', 'bootlace64.com'],
+    'systemd-boot': ['bootctl'],
+    'dd': ['dd'],
+    'mkfs.fat': ['mkfs.fat'],
+    'mkfs.exfat': ['mkfs.exfat'],
+    'mkfs.ntfs': ['mkfs.ntfs'],
+    'mkfs.udf': ['mkfs.udf'],
+    'mkfs.ext4': ['mkfs.ext4'],
+    'mkfs.btrfs': ['mkfs.btrfs'],
+    'badblocks': ['badblocks'],
+    'cdrtools': ['isoinfo'],
+    'losetup': ['losetup'],
+    'blkdiscard': ['blkdiscard'],
+    'sfdisk': ['sfdisk'],
+    'tar': ['tar']
+}
+
+
+def get_dependencies():
+    # Returns a dictionary with whether each dependency is installed (its command is in PATH).
+    dependencies = {}
+    for dependency, commands in COMMANDS.items():
+        dependencies[dependency] = False
+        for command in commands:
+            if shutil.which(command) is not None:
+                dependencies[dependency] = True
+    return dependencies
+
+
+def get_missing_dependencies(dependencies, filesystem, target='none', bootloader=('', '')):
+    # Returns a list of every dependency that needs to be installed to create the
+    # filesystem and install the bootloaders for the target, so the user can be told
+    # about all of them before anything is done to the device.
+    # dependencies is the dictionary returned by get_dependencies.
+    missing_deps = []
+
+    if filesystem == 'fat32' or filesystem == 'fat16':
+        mkfs = 'mkfs.fat'
+    elif filesystem == 'exfat' or filesystem == 'ntfs' or filesystem == 'udf' or filesystem == 'ext4' or \
+            filesystem == 'btrfs':
+        mkfs = 'mkfs.' + filesystem
+    else:
+        mkfs = ''
+        missing_deps.append('Unknown filesystem: "' + filesystem + '"')
+
+    if mkfs != '' and not dependencies[mkfs]:
+        missing_deps.append(mkfs)
+
+    # bootloader is in the format [uefi, bios].
+    required = []
+
+    if target == 'uefi' or target == 'both':
+        if bootloader[0] == 'syslinux' or bootloader[0] == 'grub2' or bootloader[0] == 'systemd-boot':
+            required.append(bootloader[0])
+        else:
+            missing_deps.append('Unknown bootloader (UEFI)')
+
+    if target == 'bios' or target == 'both':
+        if bootloader[1] == 'syslinux':
+            # dd is used to install the SYSLINUX MBR.
+            required.append('syslinux')
+            required.append('dd')
+        elif bootloader[1] == 'grub4dos':
+            required.append('grub4dos')
+        else:
+            missing_deps.append('Unknown bootloader (BIOS)')
+
+    for dependency in required:
+        if not dependencies[dependency] and dependency not in missing_deps:
+            missing_deps.append(dependency)
+
+    if not dependencies['parted']:
+        missing_deps.append('parted')
+
+    return missing_deps
+
+
+def get_missing_option_dependencies(dependencies, options):
+    # Returns the dependencies needed by the advanced options (see worker.DEFAULT_OPTIONS)
+    # of the Format and ISO Image methods. options only needs to have the options that are set.
+    missing_deps = []
+
+    if options.get('discard', False) and not dependencies['blkdiscard']:
+        missing_deps.append('blkdiscard')
+
+    # The disk identifier, the GUIDs and attributes of the partitions, and a GPT partition table
+    # with more than 128 entries (parted always creates 128) are set by sfdisk.
+    if (options.get('disk_identifier', '') != '' or options.get('deterministic_name', '') != '' or
+            options.get('gpt_attributes', '') != '' or options.get('gpt_entries', '') not in ['', '128']) and \
+            not dependencies['sfdisk']:
+        missing_deps.append('sfdisk')
+
+    if options.get('archive', '') != '' and not dependencies['tar']:
+        missing_deps.append('tar')
+
+    return missing_deps
@@ -16,168 +16,337 @@
 #   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
 
 import subprocess
+import os
+import shutil
+import re
 import usb_info
 
 
-def create_fat32_filesystem(device, partition, label='', badblocks_file='', clustersize='-1'):
-    if clustersize == '-1':
-        if label == '':
-            if badblocks_file == '':
-                subprocess.run(['mkfs.fat', '-F32', '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.fat', '-F32', '-l', badblocks_file, '/dev/' + device + partition])
-        else:
-            if badblocks_file == '':
-                subprocess.run(['mkfs.fat', '-F32', '-n', label.upper(), '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.fat', '-F32', '-n', label.upper(), '-l', badblocks_file,
-                                '/dev/' + device + partition])
-    else:
-        if label == '':
-            if badblocks_file == '':
-                subprocess.run(['mkfs.fat', '-F32', '-s', str(int(clustersize) / usb_info.get_block_size(device)),
-                                '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.fat', '-F32', '-s', str(int(clustersize) / usb_info.get_block_size(device)),
-                                '-l', badblocks_file, '/dev/' + device + partition])
-        else:
-            if badblocks_file == '':
-                subprocess.run(['mkfs.fat', '-F32', '-n', label.upper(),
-                                '-s', str(int(clustersize) / usb_info.get_block_size(device)),
-                                '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.fat', '-F32', '-n', label.upper(), '-s',
-                                str(int(clustersize) / usb_info.get_block_size(device)), '-l', badblocks_file,
-                                '/dev/' + device + partition])
-
-
-def create_fat16_filesystem(device, partition, label='', badblocks_file='', clustersize='-1'):
-    if clustersize == '-1':
-        if label == '':
-            if badblocks_file == '':
-                subprocess.run(['mkfs.fat', '-F16', '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.fat', '-F16', '-l', badblocks_file, '/dev/' + device + partition])
+# The ext4 features known to mke2fs, which can be enabled or disabled with -O.
+EXT4_FEATURES = ['64bit', 'bigalloc', 'casefold', 'dir_index', 'dir_nlink', 'ea_inode', 'encrypt', 'ext_attr',
+                 'extent', 'extra_isize', 'filetype', 'flex_bg', 'has_journal', 'huge_file', 'inline_data',
+                 'large_dir', 'large_file', 'meta_bg', 'metadata_csum', 'metadata_csum_seed', 'mmp', 'orphan_file',
+                 'project', 'quota', 'resize_inode', 'sparse_super', 'sparse_super2', 'stable_inodes', 'uninit_bg',
+                 'verity']
+
+
+# Characters that can't be used in FAT labels.
+FAT_INVALID_LABEL_CHARS = '"*+,./:;<=>?[\\]|'
+
+
+# The time (in seconds) after which mkfs is stopped, plus MKFS_TIMEOUT_PER_GIB seconds for each GiB
+# of the partition. Creating a filesystem only writes its structures, but some of them (like the FATs)
+# grow with the partition, so it can take a while on big and slow drives even if mkfs isn't stuck.
+MKFS_TIMEOUT = 600
+MKFS_TIMEOUT_PER_GIB = 10
+
+
+def truncate_bytes(text, length, encoding):
+    # Truncates text to length bytes when encoded, without splitting a character.
+    return text.encode(encoding)[:length].decode(encoding, 'ignore')
+
+
+def normalize_label(label, filesystem, strict=False):
+    # Applies the label rules of each filesystem, so that mkfs doesn't reject or silently mangle it.
+    # Returns the normalized label and a message explaining how it was changed (to show to the user),
+    # which is '' if it wasn't. If strict is True, InvalidOptionError is raised instead of changing it.
+    if filesystem.lower() == 'fat32' or filesystem.lower() == 'fat16':
+        # Uppercase (which isn't counted as a change), 11 characters, no control or reserved characters.
+        label = label.upper()
+        normalized = ''.join(c for c in label if ord(c) >= 32 and c not in FAT_INVALID_LABEL_CHARS)[:11]
+    elif filesystem.lower() == 'exfat':
+        # 15 UTF-16 code units.
+        normalized = truncate_bytes(label, 30, 'utf-16-le')
+    elif filesystem.lower() == 'ntfs':
+        normalized = label[:32]
+    elif filesystem.lower() == 'ext4':
+        normalized = truncate_bytes(label, 16, 'utf-8')
+    elif filesystem.lower() == 'btrfs':
+        normalized = truncate_bytes(label, 255, 'utf-8')
+    elif filesystem.lower() == 'udf':
+        # The logical volume identifier holds 126 characters if they all fit in
+        # one byte, 63 otherwise.
+        if all(ord(c) < 256 for c in label):
+            normalized = label[:126]
         else:
-            if badblocks_file == '':
-                subprocess.run(['mkfs.fat', '-F16', '-n', label.upper(), '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.fat', '-F16', '-n', label.upper(), '-l', badblocks_file,
-                                '/dev/' + device + partition])
+            normalized = label[:63]
     else:
-        if label == '':
-            if badblocks_file == '':
-                subprocess.run(['mkfs.fat', '-F16', '-s', str(int(clustersize) / usb_info.get_block_size(device)),
-                                '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.fat', '-F16', '-s', str(int(clustersize) / usb_info.get_block_size(device)),
-                                '-l', badblocks_file, '/dev/' + device + partition])
-        else:
-            if badblocks_file == '':
-                subprocess.run(['mkfs.fat', '-F16', '-n', label.upper(),
-                                '-s', str(int(clustersize) / usb_info.get_block_size(device)),
-                                '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.fat', '-F16', '-n', label.upper(),
-                                '-s', str(int(clustersize) / usb_info.get_block_size(device)), '-l', badblocks_file,
-                                '/dev/' + device + partition])
+        normalized = label
+
+    if normalized == label:
+        return normalized, ''
+    if strict:
+        raise InvalidOptionError
+    return normalized, 'The label "' + label + '" is changed to "' + normalized + '", since it\'s not valid for ' + \
+        filesystem + '.'
+
+
+def get_fat_geometry_args(fat_count='-1', hidden_sectors='-1', geometry=''):
+    # Returns the mkfs.fat arguments for the number of FATs (2 by default), the number of
+    # hidden sectors before the partition and the geometry (in the "heads/sectors-per-track"
+    # format, for example: 255/63). The defaults are fine for almost everything, but some
+    # old BIOSes only boot drives with a specific geometry.
+    args = []
+    if fat_count != '-1':
+        if not re.match('[1-4]$', fat_count):
+            raise InvalidOptionError
+        args += ['-f', fat_count]
+    if hidden_sectors != '-1':
+        if not re.match('[0-9]+$', hidden_sectors) or int(hidden_sectors) >= 2**32:
+            raise InvalidOptionError
+        args += ['-h', hidden_sectors]
+    if geometry != '':
+        geometry_match = re.match('([0-9]+)/([0-9]+)$', geometry)
+        if geometry_match is None or not 1 <= int(geometry_match.group(1)) <= 255 or \
+                not 1 <= int(geometry_match.group(2)) <= 63:
+            raise InvalidOptionError
+        args += ['-g', geometry]
+    return args
+
+
+def has_c_utf8_locale():
+    # glibc lists the locale as "C.utf8", and other C libraries as "C.UTF-8".
+    try:
+        locales = subprocess.check_output(['locale', '-a']).decode().split()
+    except (OSError, subprocess.CalledProcessError):
+        return False
+    return 'c.utf8' in [locale.lower().replace('-', '') for locale in locales]
+
+
+def get_mkfs_environment():
+    # The locale is set to C.UTF-8, so labels with non-ASCII characters are always read as UTF-8,
+    # whatever the user's locale is. mkfs.fat converts them to the DOS codepage (850 by default),
+    # mkfs.exfat, mkfs.ntfs and mkfs.udf to UTF-16, and ext4 and Btrfs store them as UTF-8.
+    # Older systems don't have C.UTF-8, so only the messages are set to English there, and the labels
+    # are read in the user's locale (which overrides the other categories if it's set in LC_ALL).
+    if has_c_utf8_locale():
+        return dict(os.environ, LC_ALL='C.UTF-8')
+
+    environment = dict(os.environ, LC_MESSAGES='C', LANGUAGE='C')
+    if 'LC_ALL' in environment:
+        environment['LC_CTYPE'] = environment.pop('LC_ALL')
+    return environment
+
+
+def get_mkfs_timeout(device, partition):
+    return MKFS_TIMEOUT + MKFS_TIMEOUT_PER_GIB * (usb_info.get_partition_size(device, partition) // 1073741824)
+
+
+def run_mkfs(args, timeout=MKFS_TIMEOUT):
+    # stdin is closed so that mkfs can't wait forever for an answer if it asks
+    # for confirmation (for example, when it finds an existing filesystem).
+    # If mkfs fails, subprocess.CalledProcessError is raised. If it doesn't finish in timeout seconds
+    # (see get_mkfs_timeout), it's killed and subprocess.TimeoutExpired is raised.
+    subprocess.run(args, stdin=subprocess.DEVNULL, env=get_mkfs_environment(), timeout=timeout, check=True)
+
+
+def create_fat32_filesystem(device, partition, label='', badblocks_file='', clustersize='-1', volume_id='',
+                            fat_count='-1', hidden_sectors='-1', geometry=''):
+    args = ['mkfs.fat', '-F32'] + get_fat_geometry_args(fat_count, hidden_sectors, geometry)
+    if label != '':
+        args += ['-n', label.upper()]
+    if clustersize != '-1':
+        # mkfs.fat takes the number of sectors per cluster.
+        args += ['-s', str(int(clustersize) // usb_info.get_block_size(device))]
+    if badblocks_file != '':
+        args += ['-l', badblocks_file]
+    if volume_id != '':
+        # The volume ID (serial number) is 32-bit, written as 8 hex digits.
+        if not re.match('[0-9a-fA-F]{8}$', volume_id):
+            raise InvalidOptionError
+        args += ['-i', volume_id]
+
+    run_mkfs(args + ['/dev/' + usb_info.get_partition_name(device, partition)], get_mkfs_timeout(device, partition))
+
+
+def create_fat16_filesystem(device, partition, label='', badblocks_file='', clustersize='-1', volume_id='',
+                            fat_count='-1', hidden_sectors='-1', geometry=''):
+    args = ['mkfs.fat', '-F16'] + get_fat_geometry_args(fat_count, hidden_sectors, geometry)
+    if label != '':
+        args += ['-n', label.upper()]
+    if clustersize != '-1':
+        # mkfs.fat takes the number of sectors per cluster.
+        args += ['-s', str(int(clustersize) // usb_info.get_block_size(device))]
+    if badblocks_file != '':
+        args += ['-l', badblocks_file]
+    if volume_id != '':
+        # The volume ID (serial number) is 32-bit, written as 8 hex digits.
+        if not re.match('[0-9a-fA-F]{8}$', volume_id):
+            raise InvalidOptionError
+        args += ['-i', volume_id]
+
+    run_mkfs(args + ['/dev/' + usb_info.get_partition_name(device, partition)], get_mkfs_timeout(device, partition))
 
 
 def create_exfat_filesystem(device, partition, label=''):
-    if label == '':
-        subprocess.run(['mkfs.exfat', '/dev/' + device + partition])
-    else:
-        subprocess.run(['mkfs.exfat', '-n', label[:15], '/dev/' + device + partition])
+    args = ['mkfs.exfat']
+    if label != '':
+        args += ['-n', label]
+
+    run_mkfs(args + ['/dev/' + usb_info.get_partition_name(device, partition)], get_mkfs_timeout(device, partition))
 
 
 def create_ntfs_filesystem(device, partition, label='', clustersize='-1'):
-    if clustersize == '-1':
-        if label == '':
-            subprocess.run(['mkfs.ntfs', '-Q', '/dev/' + device + partition])
-        else:
-            subprocess.run(['mkfs.ntfs', '-Q', '-L', label, '/dev/' + device + partition])
-    else:
-        if label == '':
-            subprocess.run(['mkfs.ntfs', '-Q', '-c', clustersize, '/dev/' + device + partition])
-        else:
-            subprocess.run(['mkfs.ntfs', '-Q', '-L', label, '-c', clustersize, '/dev/' + device + partition])
+    args = ['mkfs.ntfs', '-Q']
+    if label != '':
+        args += ['-L', label]
+    if clustersize != '-1':
+        args += ['-c', clustersize]
+
+    run_mkfs(args + ['/dev/' + usb_info.get_partition_name(device, partition)], get_mkfs_timeout(device, partition))
 
 
 def create_udf_filesystem(device, partition, label='', clustersize='-1'):
-    if clustersize == '-1':
-        subprocess.run(['mkfs.udf', '-l', label, '/dev/' + device + partition])
-    else:
-        subprocess.run(['mkfs.udf', '-l', label, '-b', clustersize, '/dev/' + device + partition])
+    args = ['mkfs.udf', '-l', label]
+    if clustersize != '-1':
+        args += ['-b', clustersize]
 
+    run_mkfs(args + ['/dev/' + usb_info.get_partition_name(device, partition)], get_mkfs_timeout(device, partition))
 
-def create_ext4_filesystem(device, partition, label='', badblocks_file='', clustersize='-1'):
-    if clustersize == '-1':
-        if label == '':
-            if badblocks_file == '':
-                subprocess.run(['mkfs.ext4', '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.ext4', '-l', badblocks_file, '/dev/' + device + partition])
-        else:
-            if badblocks_file == '':
-                subprocess.run(['mkfs.ext4', '-L', label, '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.ext4', '-L', label, '-l', badblocks_file, '/dev/' + device + partition])
-    else:
-        if label == '':
-            if badblocks_file == '':
-                subprocess.run(['mkfs.ext4', '-b', clustersize, '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.ext4', '-b', clustersize, '-l', badblocks_file, '/dev/' + device + partition])
-        else:
-            if badblocks_file == '':
-                subprocess.run(['mkfs.ext4', '-L', label, '-b', clustersize, '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.ext4', '-L', label, '-b', clustersize, '-l', badblocks_file,
-                                '/dev/' + device + partition])
+
+def create_ext4_filesystem(device, partition, label='', badblocks_file='', clustersize='-1', reserved_percentage='-1',
+                           features=None, uuid=''):
+    # -F stops mke2fs from asking for confirmation.
+    args = ['mkfs.ext4', '-F']
+    if label != '':
+        args += ['-L', label]
+    if clustersize != '-1':
+        args += ['-b', clustersize]
+    if badblocks_file != '':
+        args += ['-l', badblocks_file]
+    if reserved_percentage != '-1':
+        # The percentage of blocks reserved for root (5% by default),
+        # which can be set to 0 on drives only used for storing data.
+        if not re.match('[0-9]+$', reserved_percentage) or int(reserved_percentage) > 50:
+            raise InvalidOptionError
+        args += ['-m', reserved_percentage]
+    if features is not None and len(features) > 0:
+        # features is a list of features to enable, or to disable if prefixed with "^"
+        # (for example: ['^64bit', '^metadata_csum'] for compatibility with older bootloaders).
+        # Unknown features (see get_unknown_ext4_features) are passed anyway, since newer versions
+        # of mke2fs may support them.
+        args += ['-O', ','.join(features)]
+    if uuid != '':
+        # A fixed UUID makes the partition always appear at the same /dev/disk/by-uuid/ path.
+        if not re.match('[0-9a-fA-F]{8}-([0-9a-fA-F]{4}-){3}[0-9a-fA-F]{12}$', uuid):
+            raise InvalidOptionError
+        args += ['-U', uuid]
+
+    run_mkfs(args + ['/dev/' + usb_info.get_partition_name(device, partition)], get_mkfs_timeout(device, partition))
+
+
+def get_ext4_features(text):
+    # Splits a comma-separated list of ext4 features (as written by the user) into the list used by
+    # create_ext4_filesystem.
+    return [feature.strip() for feature in text.split(',') if feature.strip() != '']
+
+
+def get_unknown_ext4_features(features):
+    # Returns the features that aren't in EXT4_FEATURES, which are either misspelled
+    # or only supported by a newer version of mke2fs.
+    return [feature for feature in features if feature.lstrip('^') not in EXT4_FEATURES]
 
 
 def create_btrfs_filesystem(device, partition, label='', badblocks_file='', clustersize='-1'):
-    if clustersize == '-1':
-        if label == '':
-            if badblocks_file == '':
-                subprocess.run(['mkfs.btrfs', '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.btrfs', '-l', badblocks_file, '/dev/' + device + partition])
-        else:
-            if badblocks_file == '':
-                subprocess.run(['mkfs.btrfs', '-L', label, '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.btrfs', '-L', label, '-l', badblocks_file, '/dev/' + device + partition])
-    else:
-        if label == '':
-            if badblocks_file == '':
-                subprocess.run(['mkfs.btrfs', '-n', clustersize, '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.btrfs', '-n', clustersize, '-l', badblocks_file, '/dev/' + device + partition])
-        else:
-            if badblocks_file == '':
-                subprocess.run(['mkfs.btrfs', '-L', label, '-n', clustersize, '/dev/' + device + partition])
-            else:
-                subprocess.run(['mkfs.btrfs', '-L', label, '-n', clustersize, '-l', badblocks_file,
-                                '/dev/' + device + partition])
+    # -f lets mkfs.btrfs overwrite an existing filesystem instead of refusing to.
+    args = ['mkfs.btrfs', '-f']
+    if label != '':
+        args += ['-L', label]
+    if clustersize != '-1':
+        args += ['-n', clustersize]
+    if badblocks_file != '':
+        args += ['-l', badblocks_file]
 
+    run_mkfs(args + ['/dev/' + usb_info.get_partition_name(device, partition)], get_mkfs_timeout(device, partition))
 
-def create_filesystem(device, partition, filesystem, clustersize='-1', label='', badblocks_file=''):
+
+def create_filesystem(device, partition, filesystem, clustersize='-1', label='', badblocks_file='', volume_id='',
+                      reserved_percentage='-1', features=None, fat_count='-1', hidden_sectors='-1', geometry='',
+                      uuid='', strict_label=False):
+    # volume_id, fat_count, hidden_sectors and geometry are only used by FAT32 and FAT16,
+    # and reserved_percentage, features and uuid by ext4.
+    # The label is normalized with normalize_label (so strict_label is its strict argument).
+    label = normalize_label(label, filesystem, strict_label)[0]
     if filesystem.lower() == 'fat32':
-        create_fat32_filesystem(device, partition, label, badblocks_file, clustersize)
+        create_fat32_filesystem(device, partition, label, badblocks_file, clustersize, volume_id, fat_count,
+                                hidden_sectors, geometry)
     elif filesystem.lower() == 'fat16':
-        create_fat16_filesystem(device, partition, label, badblocks_file, clustersize)
+        create_fat16_filesystem(device, partition, label, badblocks_file, clustersize, volume_id, fat_count,
+                                hidden_sectors, geometry)
     elif filesystem.lower() == 'ntfs':
         create_ntfs_filesystem(device, partition, label, clustersize)
     elif filesystem.lower() == 'exfat':
         create_exfat_filesystem(device, partition, label)
     elif filesystem.lower() == 'ext4':
-        create_ext4_filesystem(device, partition, label, badblocks_file, clustersize)
+        create_ext4_filesystem(device, partition, label, badblocks_file, clustersize, reserved_percentage, features,
+                               uuid)
     elif filesystem.lower() == 'btrfs':
         create_btrfs_filesystem(device, partition, label, badblocks_file, clustersize)
     elif filesystem.lower() == 'udf':
         create_udf_filesystem(device, partition, label, clustersize)
 
 
+def create_filesystems(device, partitions):
+    # Creates a filesystem on each of several partitions (for example: a FAT32 boot partition
+    # and an ext4 data partition). partitions is a list of dictionaries with the partition number
+    # and filesystem, and optionally any other argument of create_filesystem:
+    # [{'partition': '1', 'filesystem': 'fat32', 'label': 'BOOT'}, {'partition': '2', 'filesystem': 'ext4'}]
+    # Errors don't stop the remaining partitions from being formatted. They're returned as
+    # a list of (partition, exception) tuples, which is empty if all of them succeeded.
+    errors = []
+    for options in partitions:
+        options = dict(options)
+        partition = options.pop('partition')
+        filesystem = options.pop('filesystem')
+        try:
+            create_filesystem(device, partition, filesystem, **options)
+        except (OSError, subprocess.SubprocessError) as error:
+            errors.append((partition, error))
+    return errors
+
+
 def check_badblocks(device, num_passes, badblocks_file, clustersize='1024'):
     subprocess.run(['badblocks', '-w', '-p', num_passes, '-b', clustersize, '-o', badblocks_file, '/dev/' + device])
+
+
+def get_filesystem_checker(filesystem):
+    # Returns the command that checks the filesystem without modifying it (the partition
+    # is added after it), or [] if there's no checker for the filesystem (UDF).
+    if filesystem.lower() == 'fat32' or filesystem.lower() == 'fat16':
+        return ['fsck.fat', '-n']
+    elif filesystem.lower() == 'exfat':
+        return ['fsck.exfat', '-n']
+    elif filesystem.lower() == 'ntfs':
+        return ['ntfsfix', '--no-action']
+    elif filesystem.lower() == 'ext4':
+        # -f forces the check, as the filesystem is marked clean after mkfs.
+        return ['e2fsck', '-f', '-n']
+    elif filesystem.lower() == 'btrfs':
+        return ['btrfs', 'check', '--readonly']
+    else:
+        return []
+
+
+def check_filesystem(device, partition, filesystem):
+    # Checks the newly created filesystem without modifying it.
+    # Returns False if the check fails, which usually means that mkfs
+    # didn't run correctly or that the flash memory is failing.
+    # Returns None if the checker (see get_filesystem_checker) isn't installed,
+    # so the filesystem couldn't be checked.
+    checker = get_filesystem_checker(filesystem)
+    if len(checker) == 0:
+        # There's nothing to check.
+        return True
+    if shutil.which(checker[0]) is None:
+        return None
+
+    result = subprocess.run(checker + ['/dev/' + usb_info.get_partition_name(device, partition)])
+
+    if result.returncode == 0:
+        return True
+    else:
+        return False
+
+
+class InvalidOptionError(OSError):
+    pass
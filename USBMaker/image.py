@@ -0,0 +1,72 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import subprocess
+import shutil
+import os
+import errno
+
+
+def create_image(path, size, overwrite=False):
+    # Creates the image file and sets its size in bytes.
+    # The file is sparse, so it only takes up space on disk as it is written to.
+    # An existing file is only truncated if overwrite is True (for example, after the user
+    # confirmed replacing it). Otherwise, FileExistsError is raised.
+    with open(path, mode='wb' if overwrite else 'xb') as image_file:
+        image_file.truncate(size)
+
+
+def attach_image(path, sector_size=512):
+    # Sets up a loop device for the image file and returns its name (for example: loop0),
+    # so it can be partitioned, formatted and mounted like a usb drive.
+    # --partscan makes the kernel create the partition devices (loop0p1, ...).
+    # sector_size is the logical sector size of the loop device, which should be 4096
+    # when building an image for a drive with 4096 byte sectors (4Kn).
+    if sector_size != 512 and sector_size != 4096:
+        raise InvalidSectorSizeError
+
+    loop_device = subprocess.check_output(['losetup', '--find', '--show', '--partscan',
+                                           '--sector-size', str(sector_size), path]).decode().rstrip()
+
+    # losetup returns the full path (for example: /dev/loop0), so we just
+    # need to remove the /dev/ part.
+    return loop_device[5:]
+
+
+def detach_image(device):
+    subprocess.run(['losetup', '--detach', '/dev/' + device])
+
+
+def build_image(path, size, build, sector_size=512, overwrite=False):
+    # Creates the image file and attaches it to a loop device, then calls build with the name of
+    # the loop device (for example, a function that partitions and formats it like a usb drive).
+    # The loop device is always detached at the end, even if build fails. Returns what build returns.
+    # overwrite is passed to create_image.
+    # Requires: losetup
+    # losetup is checked first, so an existing file isn't truncated if the image can't be built.
+    if shutil.which('losetup') is None:
+        raise FileNotFoundError(errno.ENOENT, os.strerror(errno.ENOENT), 'losetup')
+    create_image(path, size, overwrite)
+    device = attach_image(path, sector_size)
+    try:
+        return build(device)
+    finally:
+        detach_image(device)
+
+
+class InvalidSectorSizeError(OSError):
+    pass
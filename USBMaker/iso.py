@@ -16,10 +16,19 @@
 #   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
 
 import os
+import errno
 import distutils.dir_util
+import distutils.errors
 import subprocess
 import shutil
 import platform
+import fnmatch
+import re
+import time
+import checksum
+import uid_info
+import usb_info
+import version
 
 # os.symlink raises a PermissionError when creating symlinks
 # on filesystems that don't support them (FAT32, for example).
@@ -41,6 +50,9 @@ def _symlink(source, link_name, target_is_directory=False, dir_fd=None):
 # that uses os.symlink use the _symlink function instead:
 # os.symlink = _symlink
 
+# The name of the file list written by write_file_list, in the root of the usb drive.
+FILE_LIST_NAME = 'USBMAKER_FILES.txt'
+
 
 def get_bios_bootloader_name(iso_mountpoint):
     if os.path.exists(iso_mountpoint + '/boot/isolinux') or os.path.exists(iso_mountpoint + '/boot/syslinux') or \
@@ -67,10 +79,386 @@ def get_uefi_bootloader_name(iso_mountpoint):
         return 'unknown'
 
 
-def copy_iso_contents(iso_mountpoint, device_mountpoint):
+def get_required_size(iso_path, method='iso'):
+    # Returns the minimum size (in bytes) of a usb drive that can hold the iso file.
+    # With the 'dd' method the image is written as it is. Otherwise its contents are copied
+    # to a partition that starts at 1MiB, and some space (around 5%) is needed for the
+    # filesystem's own structures.
+    if method == 'dd':
+        return os.path.getsize(iso_path)
+
+    if os.path.isdir(iso_path):
+        # The iso file was already extracted to a directory.
+        contents_size = 0
+        for root, dirs, files in os.walk(iso_path):
+            for file in files:
+                if not os.path.islink(os.path.join(root, file)):
+                    contents_size += os.path.getsize(os.path.join(root, file))
+    else:
+        contents_size = os.path.getsize(iso_path)
+
+    return 1048576 + contents_size * 105 // 100
+
+
+def copy_iso_contents(iso_mountpoint, device_mountpoint, include=None, exclude=None, sync=True, preserve_times=True,
+                      fsync_files=False, best_effort=False, device=''):
+    # include and exclude are lists of glob patterns (for example: "boot/*" or "*.pdf")
+    # matched against the paths relative to the root of the iso file.
+    # If sync is False, the copied files aren't flushed to the usb drive here. This is faster,
+    # but the drive must not be removed until the system finishes writing them (unmounting
+    # it waits for that).
+    # If preserve_times is True, the copied files keep the modification times they have in the iso file,
+    # instead of the time they were copied. FAT only stores them with a 2 second resolution, so they
+    # may be rounded.
+    # If fsync_files is True, each file is flushed to the usb drive right after it's copied, instead
+    # of all of them at the end. This is slower, but if the copy is interrupted (for example, because
+    # the drive was removed), the files copied until then are complete.
+    # If best_effort is True, files that can't be copied (for example, because of read errors on
+    # a damaged disc) are skipped instead of stopping the copy. Returns the list of skipped files.
+    # device is the usb drive (for example, "sdb"), which is used to tell if a failed copy was caused
+    # by the drive being disconnected.
     os.symlink = _symlink
-    distutils.dir_util.copy_tree(iso_mountpoint, device_mountpoint, preserve_symlinks=1)
+    skipped_files = []
+    try:
+        if include is None and exclude is None and not fsync_files and not best_effort:
+            distutils.dir_util.copy_tree(iso_mountpoint, device_mountpoint, preserve_symlinks=1,
+                                         preserve_times=int(preserve_times))
+        else:
+            skipped_files = copy_filtered_tree(iso_mountpoint, device_mountpoint, include, exclude, preserve_times,
+                                               fsync_files, best_effort)
+    except SourceReadError:
+        raise
+    except (OSError, distutils.errors.DistutilsFileError) as error:
+        # copy_tree replaces the OSError that made the copy fail with a DistutilsFileError, which is turned back
+        # into an OSError (with the errno and the file name of the original one), so it's handled like the others.
+        if isinstance(error, distutils.errors.DistutilsFileError):
+            if isinstance(error.__context__, OSError):
+                os_error = OSError(error.__context__.errno, str(error), error.__context__.filename)
+            else:
+                os_error = OSError(str(error))
+        else:
+            os_error = error
+        # A disconnected drive can't be checked below (its filesystem is gone too).
+        if os_error.errno in [errno.ENODEV, errno.ENXIO] or (device != '' and not usb_info.is_connected(device)):
+            raise OSError(errno.ENODEV, os.strerror(errno.ENODEV), device) from error
+        # The kernel remounts a filesystem as read-only when writing to it fails,
+        # which usually means that the usb drive is failing.
+        if os.statvfs(device_mountpoint).f_flag & os.ST_RDONLY:
+            raise TargetReadOnlyError
+        # Filesystems with a fixed number of inodes (like ext4) can run out of them before running
+        # out of space when there are many small files, which also fails with "No space left on device".
+        # Filesystems without inodes (like FAT) report 0 of them.
+        filesystem_stats = os.statvfs(device_mountpoint)
+        if filesystem_stats.f_files > 0 and filesystem_stats.f_favail == 0 and filesystem_stats.f_bavail > 0:
+            raise OutOfInodesError
+        if os_error is error:
+            raise
+        raise os_error from error
+
+    if sync:
+        os.sync()
+
+    return skipped_files
+
+
+def path_matches(path, patterns):
+    # A path matches if it or one of its parent directories matches one of the patterns,
+    # so a pattern matching a directory covers everything inside of it.
+    path_parts = path.split('/')
+    for i in range(1, len(path_parts) + 1):
+        for pattern in patterns:
+            if fnmatch.fnmatchcase('/'.join(path_parts[:i]), pattern.strip('/')):
+                return True
+    return False
+
+
+def copy_filtered_tree(source, destination, include=None, exclude=None, preserve_times=True, fsync_files=False,
+                       best_effort=False):
+    # Copies the files from source that match include (or all files, if include is None)
+    # and don't match exclude. Like with copy_tree, directories are created even if they're empty
+    # (if they match include), and the parent directories of each file are created as needed.
+    # The directories keep their modes (and their modification times, if preserve_times is True).
+    # Returns the list of files skipped because they couldn't be copied (only if best_effort is True).
+    skipped_files = []
+    directories = []
+    if include is None:
+        include = ['*']
+    if exclude is None:
+        exclude = []
+
+    for root, dirs, files in os.walk(source):
+        relative_root = os.path.relpath(root, source)
+        if relative_root == '.':
+            relative_root = ''
+
+        # Excluded directories aren't walked into.
+        dirs[:] = [directory for directory in dirs if not path_matches(os.path.join(relative_root, directory),
+                                                                          exclude)]
+
+        for directory in dirs:
+            relative_path = os.path.join(relative_root, directory)
+            # Symlinks to directories are copied below.
+            if not os.path.islink(os.path.join(root, directory)):
+                directories.append(relative_path)
+                if path_matches(relative_path, include):
+                    os.makedirs(os.path.join(destination, relative_path), exist_ok=True)
+
+        # Symlinks to directories are listed in dirs, but are copied like files.
+        for name in files + [directory for directory in dirs if os.path.islink(os.path.join(root, directory))]:
+            relative_path = os.path.join(relative_root, name)
+            if path_matches(relative_path, exclude) or not path_matches(relative_path, include):
+                continue
+
+            os.makedirs(os.path.join(destination, relative_root), exist_ok=True)
+            try:
+                copy_file(os.path.join(root, name), os.path.join(destination, relative_path), preserve_times,
+                          fsync_files)
+            except SourceReadError:
+                # Only errors reading the source are skipped. Errors writing to the destination (it's
+                # read-only, or out of space or inodes) would happen again for every file after it.
+                if not best_effort:
+                    raise
+                # The part of the file copied before the error is removed.
+                if os.path.lexists(os.path.join(destination, relative_path)):
+                    os.remove(os.path.join(destination, relative_path))
+                skipped_files.append(relative_path)
+
+    # Copying the files into the directories changes their modification times, so they're set at the end.
+    for relative_path in directories:
+        if os.path.isdir(os.path.join(destination, relative_path)):
+            if preserve_times:
+                shutil.copystat(os.path.join(source, relative_path), os.path.join(destination, relative_path))
+            else:
+                shutil.copymode(os.path.join(source, relative_path), os.path.join(destination, relative_path))
+
+    return skipped_files
+
+
+def copy_file(source, destination, preserve_times=True, fsync_file=False):
+    # Errors reading the source raise a SourceReadError, so they can be told apart
+    # from errors writing to the destination.
+    if os.path.islink(source):
+        os.symlink(os.readlink(source), destination)
+        return
+
+    try:
+        source_file = open(source, 'rb')
+    except OSError as error:
+        raise SourceReadError(error.errno, error.strerror, source) from error
+    with source_file, open(destination, 'wb') as destination_file:
+        while True:
+            try:
+                data = source_file.read(1048576)
+            except OSError as error:
+                raise SourceReadError(error.errno, error.strerror, source) from error
+            if not data:
+                break
+            destination_file.write(data)
+
+        if fsync_file:
+            destination_file.flush()
+            os.fsync(destination_file.fileno())
+
+    if preserve_times:
+        shutil.copystat(source, destination)
+    else:
+        shutil.copymode(source, destination)
+
+
+def update_contents(source, device_mountpoint, delete=False, compare_hashes=False,
+                    algorithm=checksum.DEFAULT_ALGORITHM):
+    # Updates a usb drive that already has a copy of source (an iso mountpoint or a directory), only
+    # copying the files that are new or changed (different size or modification time). If delete is
+    # True, files that are no longer in source are removed from the drive. Only the files recorded in the
+    # file list (see write_file_list) are removed, so the ones added after copying (like the bootloaders
+    # and the info file) are kept. The file list is updated at the end.
+    # If compare_hashes is True, files of the same size are compared by their hashes instead of their
+    # modification times. This is slower (every file is read), but doesn't depend on the times being
+    # preserved (for example, if the files were copied with preserve_times=False).
+    # Returns the number of files added, updated, deleted and unchanged, in a dictionary.
+    counts = {'added': 0, 'updated': 0, 'deleted': 0, 'unchanged': 0}
+    os.symlink = _symlink
+
+    for root, dirs, files in os.walk(source):
+        relative_root = os.path.relpath(root, source)
+        target_root = os.path.join(device_mountpoint, relative_root)
+        # A file (or a symlink) on the drive that is a directory in source is replaced by it.
+        if os.path.lexists(target_root) and (os.path.islink(target_root) or not os.path.isdir(target_root)):
+            os.remove(target_root)
+            counts['deleted'] += 1
+        os.makedirs(target_root, exist_ok=True)
+
+        # Symlinks to directories are listed in dirs (but not walked into), and are copied like files.
+        for name in files + [directory for directory in dirs if os.path.islink(os.path.join(root, directory))]:
+            source_path = os.path.join(root, name)
+            target_path = os.path.join(target_root, name)
+            if not os.path.lexists(target_path):
+                counts['added'] += 1
+            elif os.path.isdir(target_path) and not os.path.islink(target_path):
+                # A directory on the drive that is a file (or a symlink) in source.
+                shutil.rmtree(target_path)
+                counts['updated'] += 1
+            elif os.path.islink(source_path) or os.path.islink(target_path):
+                if os.path.islink(source_path) and os.path.islink(target_path) and \
+                        os.readlink(source_path) == os.readlink(target_path):
+                    counts['unchanged'] += 1
+                    continue
+                os.remove(target_path)
+                counts['updated'] += 1
+            elif compare_hashes and os.path.getsize(source_path) == os.path.getsize(target_path) and \
+                    checksum.get_file_hash(source_path, algorithm) == checksum.get_file_hash(target_path, algorithm):
+                counts['unchanged'] += 1
+                continue
+            # FAT only stores modification times with a 2 second resolution.
+            elif not compare_hashes and os.path.getsize(source_path) == os.path.getsize(target_path) and \
+                    abs(os.path.getmtime(source_path) - os.path.getmtime(target_path)) <= 2:
+                counts['unchanged'] += 1
+                continue
+            else:
+                counts['updated'] += 1
+            copy_file(source_path, target_path)
+
+    recorded_paths = read_file_list(device_mountpoint)
+    if delete:
+        # The paths are sorted in reverse, so the contents of a directory are deleted before it.
+        for path in sorted(recorded_paths, reverse=True):
+            target_path = os.path.join(device_mountpoint, path.rstrip('/'))
+            if os.path.lexists(os.path.join(source, path.rstrip('/'))) or not os.path.lexists(target_path):
+                continue
+            if path.endswith('/'):
+                # Directories are only deleted once they're empty, since they may have files that weren't copied.
+                if os.path.isdir(target_path) and not os.path.islink(target_path) and \
+                        len(os.listdir(target_path)) == 0:
+                    os.rmdir(target_path)
+            elif os.path.islink(target_path) or not os.path.isdir(target_path):
+                os.remove(target_path)
+                counts['deleted'] += 1
+        recorded_paths = set()
+
+    # The paths that weren't deleted stay in the list.
+    write_file_list(device_mountpoint, get_path_list(source) |
+                    {path for path in recorded_paths if os.path.lexists(os.path.join(device_mountpoint, path))})
+
     os.sync()
+    return counts
+
+
+def get_differences(source, device_mountpoint, algorithm=checksum.DEFAULT_ALGORITHM):
+    # Compares the files in source (an iso mountpoint or a directory) with the ones on a usb drive,
+    # for example before updating it. Returns a dictionary with the lists of paths that are only in
+    # source ('added'), only on the drive ('removed'), or in both with a different size or contents
+    # ('modified'). The contents are compared by their hashes, computed with algorithm.
+    source_files = get_file_list(source)
+    device_files = get_file_list(device_mountpoint)
+
+    differences = {'added': [], 'removed': [], 'modified': []}
+    for path in sorted(source_files):
+        if path not in device_files:
+            differences['added'].append(path)
+        elif os.path.getsize(os.path.join(source, path)) != os.path.getsize(os.path.join(device_mountpoint, path)) \
+                or checksum.get_file_hash(os.path.join(source, path), algorithm) != \
+                checksum.get_file_hash(os.path.join(device_mountpoint, path), algorithm):
+            differences['modified'].append(path)
+    for path in sorted(device_files):
+        if path not in source_files:
+            differences['removed'].append(path)
+    return differences
+
+
+def get_file_list(directory):
+    # Returns the paths (relative to directory) of all the regular files in it.
+    file_list = set()
+    for root, dirs, files in os.walk(directory):
+        for name in files:
+            if not os.path.islink(os.path.join(root, name)):
+                file_list.add(os.path.relpath(os.path.join(root, name), directory))
+    return file_list
+
+
+def get_path_list(directory):
+    # Returns the paths (relative to directory) of all the files, symlinks and directories in it.
+    # The paths of directories end with '/'.
+    path_list = set()
+    for root, dirs, files in os.walk(directory):
+        for name in dirs:
+            if os.path.islink(os.path.join(root, name)):
+                path_list.add(os.path.relpath(os.path.join(root, name), directory))
+            else:
+                path_list.add(os.path.relpath(os.path.join(root, name), directory) + '/')
+        for name in files:
+            path_list.add(os.path.relpath(os.path.join(root, name), directory))
+    return path_list
+
+
+def write_file_list(device_mountpoint, paths=None, filename=FILE_LIST_NAME):
+    # Records the paths of the files copied to the usb drive (in the format of get_path_list), so
+    # update_contents only deletes files that were copied from an iso file. It's written after copying,
+    # before anything else is added to the drive. paths defaults to everything on the drive.
+    if paths is None:
+        paths = get_path_list(device_mountpoint)
+    paths = set(paths) - {filename}
+    with open(device_mountpoint + '/' + filename, mode='w', encoding='utf_8', newline='\n') as list_file:
+        for path in sorted(paths):
+            list_file.write(path + '\n')
+
+
+def read_file_list(device_mountpoint, filename=FILE_LIST_NAME):
+    # Returns the paths recorded by write_file_list, or an empty set if there's no file list.
+    if not os.path.isfile(device_mountpoint + '/' + filename):
+        return set()
+    with open(device_mountpoint + '/' + filename, mode='r', encoding='utf_8') as list_file:
+        return {line.rstrip('\n') for line in list_file if line.rstrip('\n') != ''}
+
+
+def write_info_file(device_mountpoint, iso_path, filename='USBMAKER_INFO.txt', extra_info=None,
+                    algorithm=checksum.DEFAULT_ALGORITHM):
+    # Writes a file to the root of the usb drive recording where its contents came from,
+    # so a drive can be identified later. extra_info is a dictionary with additional
+    # lines to write, in the same "key: value" format.
+    info = {'Source': os.path.basename(iso_path),
+            algorithm.upper(): checksum.get_file_hash(iso_path, algorithm),
+            'Created': time.strftime('%Y-%m-%d %H:%M:%S %z'),
+            'USBMaker version': version.VERSION}
+    if extra_info is not None:
+        info.update(extra_info)
+
+    with open(device_mountpoint + '/' + filename, mode='w', encoding='utf_8', newline='\n') as info_file:
+        for key in info:
+            info_file.write(key + ': ' + info[key] + '\n')
+
+
+def extract_archive(archive, device_mountpoint):
+    # Extracts a tar archive (compressed or not, for example: .tar.gz or .tar.zst) to the usb drive,
+    # for drives that are only used to carry data. tar detects the compression by itself.
+    # Owners aren't restored, since they're meaningless on other machines (and on FAT).
+    # Raises ExtractionError if tar failed, for example because of a corrupted archive, or files
+    # (like symlinks) that the filesystem doesn't support.
+    result = subprocess.run(['tar', '--extract', '--file=' + archive, '--directory=' + device_mountpoint,
+                             '--no-same-owner'])
+    if result.returncode != 0:
+        raise ExtractionError
+
+
+def set_ownership(device_mountpoint, uid, gid='', umask=''):
+    # Gives the copied files to a user (for example, the one who started USBMaker with sudo),
+    # so they can be edited later without root. uid and gid are strings, like in uid_info,
+    # and gid defaults to the user's primary group. umask (in octal, for example: '022')
+    # removes permissions from all the files and directories.
+    # This only works on filesystems that store owners (like ext4 and Btrfs). On FAT, exFAT
+    # and NTFS, the owner is set when mounting instead, with the uid=, gid= and umask= options.
+    if gid == '':
+        gid = uid_info.get_gid_from_uid(uid)
+    else:
+        # Checks that the user exists.
+        uid_info.get_gid_from_uid(uid)
+
+    for root, dirs, files in os.walk(device_mountpoint):
+        for name in dirs + files:
+            path = os.path.join(root, name)
+            os.lchown(path, int(uid), int(gid))
+            if umask != '' and not os.path.islink(path):
+                os.chmod(path, os.stat(path).st_mode & ~int(umask, 8) & 0o7777)
+    os.lchown(device_mountpoint, int(uid), int(gid))
 
 
 def create_bootable_usb(device, device_mountpoint, bootloader, target, partition_table, syslinux, syslinux_modules,
@@ -314,3 +702,67 @@ def install_systemd_boot(device_mountpoint):
 
     if os.path.isfile('/usr/lib/systemd/boot/efi/systemd-bootia32.efi'):
         shutil.copy('/usr/lib/systemd/boot/efi/systemd-bootia32.efi', device_mountpoint + '/boot/efi/bootia32.efi')
+
+
+def install_custom_config(device_mountpoint, config_path, bootloader):
+    # Replaces the boot menu config file of the iso file with config_path, after the bootloader
+    # was installed (for example, for unattended installs). bootloader is 'syslinux' or 'grub2'.
+    # Returns the list of kernels and initrds referenced by the new config file that don't exist
+    # on the usb drive, which may mean it won't boot.
+    if not os.path.isfile(config_path):
+        raise FileNotFoundError(config_path)
+
+    if bootloader.lower() == 'syslinux':
+        # The config file that SYSLINUX uses is replaced (see isolinux_to_syslinux).
+        config_dir = device_mountpoint
+        for directory in [device_mountpoint + '/boot/syslinux', device_mountpoint + '/syslinux']:
+            if os.path.isfile(directory + '/syslinux.cfg'):
+                config_dir = directory
+                break
+        shutil.copy(config_path, config_dir + '/syslinux.cfg')
+        # KERNEL/LINUX and INITRD lines, and initrd= in APPEND lines.
+        file_pattern = '^\\s*(?:kernel|linux|initrd)\\s+(\\S+)|initrd=([^\\s,]+)'
+    elif bootloader.lower() == 'grub2':
+        config_dir = device_mountpoint + '/boot/grub'
+        os.makedirs(config_dir, exist_ok=True)
+        shutil.copy(config_path, config_dir + '/grub.cfg')
+        file_pattern = '^\\s*(?:linux|linuxefi|initrd|initrdefi)(?:16)?\\s+(\\S+)'
+    else:
+        raise ValueError(bootloader)
+
+    missing_files = []
+    with open(config_path, mode='r', encoding='utf_8', errors='replace') as config_file:
+        for line in config_file:
+            match = re.search(file_pattern, line, re.IGNORECASE)
+            if match is None:
+                continue
+            path = [group for group in match.groups() if group is not None][0]
+
+            # Paths using GRUB variables or devices (like $root or (hd0,1)) can't be checked.
+            if '$' in path or path.startswith('('):
+                continue
+            # Relative paths start at the directory of the config file.
+            if path.startswith('/'):
+                full_path = device_mountpoint + path
+            else:
+                full_path = config_dir + '/' + path
+            if not os.path.exists(full_path):
+                missing_files.append(path)
+
+    return missing_files
+
+
+class TargetReadOnlyError(OSError):
+    pass
+
+
+class OutOfInodesError(OSError):
+    pass
+
+
+class SourceReadError(OSError):
+    pass
+
+
+class ExtractionError(OSError):
+    pass
@@ -0,0 +1,171 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+# A minimal read-only parser for the volume descriptors of iso files (ISO 9660),
+# so their metadata can be read without mounting them (which requires root).
+
+SECTOR_SIZE = 2048
+
+
+def read_sector(iso_file, sector):
+    iso_file.seek(sector * SECTOR_SIZE)
+    return iso_file.read(SECTOR_SIZE)
+
+
+def get_volume_info(iso_path):
+    # Returns a dictionary with the volume label, the size of the volume (in bytes),
+    # whether it's bootable (has an El Torito boot record) and whether it has an EFI boot image.
+    # Raises InvalidIsoError if the file isn't an iso file.
+    info = {'label': '', 'size': 0, 'bootable': False, 'efi': False}
+    found_primary_descriptor = False
+    boot_catalog = None
+
+    with open(iso_path, 'rb') as iso_file:
+        # The volume descriptors start at sector 16, and end with a terminator (type 255).
+        sector = 16
+        while True:
+            descriptor = read_sector(iso_file, sector)
+            if len(descriptor) < SECTOR_SIZE or descriptor[1:6] != b'CD001':
+                break
+
+            if descriptor[0] == 0 and descriptor[7:39].rstrip(b'\0 ') == b'EL TORITO SPECIFICATION':
+                # Boot record, which contains the sector of the boot catalog.
+                info['bootable'] = True
+                boot_catalog = int.from_bytes(descriptor[71:75], 'little')
+            elif descriptor[0] == 1:
+                # Primary volume descriptor.
+                found_primary_descriptor = True
+                info['label'] = descriptor[40:72].decode('ascii', 'replace').rstrip()
+                # The size is stored in number of logical blocks (usually 2048 bytes).
+                block_size = int.from_bytes(descriptor[128:130], 'little')
+                info['size'] = int.from_bytes(descriptor[80:84], 'little') * block_size
+            elif descriptor[0] == 255:
+                break
+            sector += 1
+
+        if boot_catalog is not None:
+            info['efi'] = has_efi_boot_entry(read_sector(iso_file, boot_catalog))
+
+    if not found_primary_descriptor:
+        raise InvalidIsoError
+    return info
+
+
+def get_root_directory_record(iso_file):
+    # Returns the directory record of the root directory and whether the names are in UCS-2
+    # (Joliet). Joliet is preferred because it keeps long and lowercase names, while plain
+    # ISO 9660 names are limited to uppercase 8.3 names.
+    root_record = None
+    sector = 16
+    while True:
+        descriptor = read_sector(iso_file, sector)
+        if len(descriptor) < SECTOR_SIZE or descriptor[1:6] != b'CD001' or descriptor[0] == 255:
+            break
+        if descriptor[0] == 1 and root_record is None:
+            root_record = descriptor[156:190]
+        elif descriptor[0] == 2 and descriptor[88:91] in [b'%/@', b'%/C', b'%/E']:
+            # Supplementary volume descriptor with the Joliet escape sequences.
+            return descriptor[156:190], True
+        sector += 1
+
+    if root_record is None:
+        raise InvalidIsoError
+    return root_record, False
+
+
+def read_directory(iso_file, record, joliet):
+    # Returns the records of the entries in the directory, as (name, size, is_directory, record) tuples.
+    entries = []
+    extent = int.from_bytes(record[2:6], 'little')
+    size = int.from_bytes(record[10:14], 'little')
+    iso_file.seek(extent * SECTOR_SIZE)
+    data = iso_file.read(size)
+
+    offset = 0
+    while offset < len(data):
+        length = data[offset]
+        if length == 0:
+            # Records don't cross sector boundaries, so the rest of the sector is padding.
+            offset = (offset // SECTOR_SIZE + 1) * SECTOR_SIZE
+            continue
+
+        entry = data[offset:offset + length]
+        offset += length
+        name = entry[33:33 + entry[32]]
+        # The first two entries are the directory itself and its parent.
+        if name == b'\0' or name == b'\1':
+            continue
+
+        if joliet:
+            name = name.decode('utf_16_be', 'replace')
+        else:
+            name = name.decode('ascii', 'replace')
+        # Remove the version number (";1"), and the dot of file names without an extension.
+        name = name.split(';')[0]
+        if name.endswith('.'):
+            name = name[:-1]
+
+        entries.append((name, int.from_bytes(entry[10:14], 'little'), entry[25] & 2 != 0, entry))
+    return entries
+
+
+def list_contents(iso_path):
+    # Returns the list of files and directories in the iso file, read without mounting it,
+    # as dictionaries with the path, the size (in bytes) and the type ('file' or 'directory').
+    # Raises InvalidIsoError if a directory contains itself or one of its parents (which only
+    # happens in damaged or malicious iso files, and would otherwise be listed forever).
+    contents = []
+    with open(iso_path, 'rb') as iso_file:
+        root_record, joliet = get_root_directory_record(iso_file)
+        directories = [('', root_record)]
+        visited_extents = {int.from_bytes(root_record[2:6], 'little')}
+        while len(directories) > 0:
+            path, record = directories.pop(0)
+            for name, size, is_directory, entry in read_directory(iso_file, record, joliet):
+                if is_directory:
+                    extent = int.from_bytes(entry[2:6], 'little')
+                    if extent in visited_extents:
+                        raise InvalidIsoError
+                    visited_extents.add(extent)
+                    contents.append({'path': path + '/' + name, 'size': 0, 'type': 'directory'})
+                    directories.append((path + '/' + name, entry))
+                else:
+                    contents.append({'path': path + '/' + name, 'size': size, 'type': 'file'})
+    return contents
+
+
+def has_efi_boot_entry(boot_catalog):
+    # The boot catalog is a list of 32 byte entries: a validation entry and the default
+    # entry, followed by sections. The validation entry and each section header have
+    # a platform ID, which is 0xEF for EFI.
+    if len(boot_catalog) < 32 or boot_catalog[0] != 1:
+        return False
+    if boot_catalog[1] == 0xEF:
+        return True
+
+    for offset in range(64, len(boot_catalog) - 31, 32):
+        # Section headers start with 0x90, or 0x91 for the last one.
+        if boot_catalog[offset] == 0x90 or boot_catalog[offset] == 0x91:
+            if boot_catalog[offset + 1] == 0xEF:
+                return True
+            if boot_catalog[offset] == 0x91:
+                break
+    return False
+
+
+class InvalidIsoError(Exception):
+    pass
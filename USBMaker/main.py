@@ -20,24 +20,27 @@ import sys
 import os
 import re
 import subprocess
-import shutil
 from PyQt5 import QtWidgets, QtCore, QtGui
 from gui import Ui_MainWindow
 import uid_info
 import about
+import advanced
 import usb_info
-import partitioning
 import formatting
-import dd
+import partitioning
 import mount
 import iso
+import iso9660
+import worker
+import dependencies
 
 
 class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
     # Signals have to be declared here.
-    signal_format = QtCore.pyqtSignal(str, str, str, str, int, int, str)
-    signal_dd = QtCore.pyqtSignal(str, str, int, str)
-    signal_iso = QtCore.pyqtSignal(str, str, str, str, str, list, str, int, int, str, list, list, str)
+    signal_format = QtCore.pyqtSignal(str, str, str, str, int, int, str, int, dict)
+    signal_dd = QtCore.pyqtSignal(str, str, int, str, dict)
+    signal_iso = QtCore.pyqtSignal(str, str, str, str, str, list, str, int, int, str, list, list, str, int, dict)
+    signal_image = QtCore.pyqtSignal(str, int, str, list)
 
     def __init__(self):
         super(MainWindow, self).__init__()
@@ -48,17 +51,29 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
             self.setWindowIcon(QtGui.QIcon.fromTheme('usbmaker'))
 
         self.about_window = about.About()
+        self.advanced_options = advanced.AdvancedOptions()
 
         # Here we set up the gui elements that aren't modified
         # elsewhere in the code.
         self.pushButton_close.clicked.connect(self.close)
         self.pushButton_about.clicked.connect(self.show_about_window)
 
+        # The advanced options button isn't in the .ui file, so it's added next to the about button here.
+        self.pushButton_advanced = QtWidgets.QPushButton(self.centralWidget)
+        self.pushButton_advanced.setText('Advanced...')
+        self.horizontalLayout.insertWidget(1, self.pushButton_advanced)
+        self.pushButton_advanced.clicked.connect(self.show_advanced_options)
+
         self.comboBox_partscheme.insertItem(0, 'MBR partition scheme for BIOS or UEFI')
         self.comboBox_partscheme.insertItem(1, 'MBR partition scheme for UEFI')
         self.comboBox_partscheme.insertItem(2, 'GPT partition scheme for BIOS or UEFI')
         self.comboBox_partscheme.insertItem(3, 'GPT partition scheme for UEFI')
 
+        # GPT is selected by default if this system booted with UEFI (see get_default_partition_table).
+        # The partition scheme can still be changed.
+        if partitioning.get_default_partition_table() == 'gpt':
+            self.comboBox_partscheme.setCurrentIndex(2)
+
         self.comboBox_filesystem.insertItem(0, 'FAT32')
         self.comboBox_filesystem.insertItem(1, 'FAT16')
         self.comboBox_filesystem.insertItem(2, 'NTFS')
@@ -75,6 +90,24 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         self.comboBox_checkbadblocks.insertItem(2, '3 Passes')
         self.comboBox_checkbadblocks.insertItem(3, '4 Passes')
 
+        # The space (in MiB) left unallocated at the end of the device, for example to create another
+        # partition later. It isn't in the .ui file, so it's added to the format options here.
+        self.label_reservedspace = QtWidgets.QLabel(self.frame)
+        self.label_reservedspace.setText('Unallocated space at the end (MiB)')
+        self.spinBox_reservedspace = QtWidgets.QSpinBox(self.frame)
+        self.spinBox_reservedspace.setMaximum(2147483647)
+        font = QtGui.QFont()
+        font.setPointSize(9)
+        self.label_reservedspace.setFont(font)
+        self.spinBox_reservedspace.setFont(font)
+        self.horizontalLayout_reservedspace = QtWidgets.QHBoxLayout()
+        self.horizontalLayout_reservedspace.addWidget(self.label_reservedspace)
+        self.horizontalLayout_reservedspace.addWidget(self.spinBox_reservedspace)
+        self.verticalLayout_3.insertLayout(1, self.horizontalLayout_reservedspace)
+
+        # The status has several lines when there are notes at the end of an operation.
+        self.label_status.setWordWrap(True)
+
         # Check for dependencies and their locations
         self.dependencies = {}
         self.update_dependencies()
@@ -110,11 +143,23 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         # self.device_id_list is initialized here.
         self.device_id_list = []
 
+        # The path and the size (in MiB) of the disk image file, if one is selected as the target.
+        self.image_path = ''
+        self.image_size = 0
+
         # Here self.filename is initialized and the file dialog button
         # is set to activate the get_file_name function when clicked.
         self.filename = ''
         self.pushButton_filedialog.clicked.connect(self.get_file_name)
 
+        # The contents of an iso file that was already extracted to a directory can be copied directly
+        # (with the ISO Image method). This button isn't in the .ui file, so it's added here.
+        self.pushButton_folderdialog = QtWidgets.QPushButton(self.frame)
+        self.pushButton_folderdialog.setText('Folder...')
+        self.pushButton_folderdialog.setToolTip('Select a directory with the extracted contents of an iso file')
+        self.horizontalLayout_3.addWidget(self.pushButton_folderdialog)
+        self.pushButton_folderdialog.clicked.connect(self.get_folder_name)
+
         # Changes to these parts of the gui trigger the update_gui function
         # to update the gui according to the selected options.
         self.comboBox_filesystem.currentIndexChanged.connect(self.update_gui)
@@ -127,6 +172,13 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         # update_gui is called to finish the initialization of the gui.
         self.update_gui()
 
+        # With "-" as an argument, the image is read from stdin (for example, piped from a download).
+        # It can only be written with the DD Image method, since it can't be mounted.
+        if '-' in sys.argv[1:]:
+            self.filename = '-'
+            self.comboBox_bootmethod.setCurrentIndex(1)
+            self.label_status.setText('The image will be read from stdin.')
+
         # The available usb devices are detected here.
         self.refresh_device_list()
         # The refresh button is connected to the refresh_device_list function.
@@ -142,113 +194,13 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         self.signal_format.connect(self.worker.format)
         self.signal_dd.connect(self.worker.make_bootable_dd)
         self.signal_iso.connect(self.worker.make_bootable_iso)
+        self.signal_image.connect(self.worker.build_image)
 
         # The start button is connected to the start function.
         self.pushButton_start.clicked.connect(self.start)
 
     def update_dependencies(self):
-        # Dependencies:
-        # - parted
-        # - grub2 (grub-install)
-        # - syslinux (extlinux)
-        # - grub4dos (bootlace(64).com)
-        # - systemd-boot
-        # - dd
-        # - mkfs.fat
-        # - mkfs.exfat
-        # - mkfs.ntfs
-        # - mkfs.udf
-        # - mkfs.ext4
-        # - mkfs.btrfs
-        # - badblocks
-        # - cdrtools (isoinfo)
-
-        # Reset the dictionary.
-        self.dependencies = {}
-
-        # parted
-        if shutil.which('parted') is None:
-            self.dependencies['parted'] = False
-        else:
-            self.dependencies['parted'] = True
-
-        # grub2
-        if shutil.which('grub-install') is None:
-            self.dependencies['grub2'] = False
-        else:
-            self.dependencies['grub2'] = True
-
-        # syslinux
-        if shutil.which('extlinux') is None:
-            self.dependencies['syslinux'] = False
-        else:
-            self.dependencies['syslinux'] = True
-
-        # grub4dos
-        if shutil.which('bootlace.com') is None and shutil.which('bootlace64.com') is None:
-            self.dependencies['grub4dos'] = False
-        else:
-            self.dependencies['grub4dos'] = True
-
-        # systemd-boot
-        if shutil.which('bootctl') is None:
-            self.dependencies['systemd-boot'] = False
-        else:
-            self.dependencies['systemd-boot'] = True
-
-        # dd
-        if shutil.which('dd') is None:
-            self.dependencies['dd'] = False
-        else:
-            self.dependencies['dd'] = True
-
-        # mkfs.fat
-        if shutil.which('mkfs.fat') is None:
-            self.dependencies['mkfs.fat'] = False
-        else:
-            self.dependencies['mkfs.fat'] = True
-
-        # mkfs.exfat
-        if shutil.which('mkfs.exfat') is None:
-            self.dependencies['mkfs.exfat'] = False
-        else:
-            self.dependencies['mkfs.exfat'] = True
-
-        # mkfs.ntfs
-        if shutil.which('mkfs.ntfs') is None:
-            self.dependencies['mkfs.ntfs'] = False
-        else:
-            self.dependencies['mkfs.ntfs'] = True
-
-        # mkfs.udf
-        if shutil.which('mkfs.udf') is None:
-            self.dependencies['mkfs.udf'] = False
-        else:
-            self.dependencies['mkfs.udf'] = True
-
-        # mkfs.ext4
-        if shutil.which('mkfs.ext4') is None:
-            self.dependencies['mkfs.ext4'] = False
-        else:
-            self.dependencies['mkfs.ext4'] = True
-
-        # mkfs.btrfs
-        if shutil.which('mkfs.btrfs') is None:
-            self.dependencies['mkfs.btrfs'] = False
-        else:
-            self.dependencies['mkfs.btrfs'] = True
-
-        # badblocks
-        if shutil.which('badblocks') is None:
-            self.dependencies['badblocks'] = False
-        else:
-            self.dependencies['badblocks'] = True
-
-        # cdrtools
-        if shutil.which('isoinfo') is None:
-            self.dependencies['cdrtools'] = False
-        else:
-            self.dependencies['cdrtools'] = True
+        self.dependencies = dependencies.get_dependencies()
 
     def find_dependencies(self):
         # Find syslinux
@@ -366,11 +318,13 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
             self.comboBox_filesystem.setEnabled(False)
             self.comboBox_clustersize.setEnabled(False)
             self.lineEdit_label.setEnabled(False)
+            self.spinBox_reservedspace.setEnabled(False)
         else:
             self.comboBox_partscheme.setEnabled(True)
             self.comboBox_filesystem.setEnabled(True)
             self.comboBox_clustersize.setEnabled(True)
             self.lineEdit_label.setEnabled(True)
+            self.spinBox_reservedspace.setEnabled(True)
 
             # The current index for the cluster size comboBox is stored before
             # its update and restored afterwards.
@@ -433,6 +387,10 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
             self.comboBox_bootmethod.setEnabled(True)
             self.pushButton_filedialog.setEnabled(True)
 
+        # Directories can't be written with the DD Image method.
+        self.pushButton_folderdialog.setEnabled(self.checkBox_bootmethod.isChecked() and
+                                                self.comboBox_bootmethod.currentText() == 'ISO Image')
+
         if not self.checkBox_checkbadblocks.isChecked():
             self.comboBox_checkbadblocks.setEnabled(False)
         else:
@@ -450,6 +408,7 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         self.pushButton_start.setEnabled(False)
         self.pushButton_refresh.setEnabled(False)
         self.pushButton_filedialog.setEnabled(False)
+        self.pushButton_folderdialog.setEnabled(False)
         self.pushButton_close.setEnabled(False)
         self.comboBox_device.setEnabled(False)
         self.comboBox_partscheme.setEnabled(False)
@@ -458,6 +417,7 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         self.comboBox_checkbadblocks.setEnabled(False)
         self.comboBox_bootmethod.setEnabled(False)
         self.lineEdit_label.setEnabled(False)
+        self.spinBox_reservedspace.setEnabled(False)
         self.checkBox_checkbadblocks.setEnabled(False)
         self.checkBox_bootmethod.setEnabled(False)
 
@@ -473,6 +433,7 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         self.comboBox_checkbadblocks.setEnabled(True)
         self.comboBox_bootmethod.setEnabled(True)
         self.lineEdit_label.setEnabled(True)
+        self.spinBox_reservedspace.setEnabled(True)
         self.checkBox_checkbadblocks.setEnabled(True)
         self.checkBox_bootmethod.setEnabled(True)
 
@@ -501,6 +462,14 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         self.about_window.move(x, y)
         self.about_window.show()
 
+    def show_advanced_options(self):
+        center_point_x = int(self.x() + self.width() / 2)
+        center_point_y = int(self.y() + self.height() / 2)
+        x = int(center_point_x - self.advanced_options.width() / 2)
+        y = int(center_point_y - self.advanced_options.height() / 2)
+        self.advanced_options.move(x, y)
+        self.advanced_options.show()
+
     def show_messagebox(self, messagebox):
         center_point_x = int(self.x() + self.width() / 2)
         center_point_y = int(self.y() + self.height() / 2)
@@ -537,6 +506,9 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         for device in self.device_id_list:
             self.comboBox_device.addItem('(' + str(round(usb_info.get_size(
                 usb_info.get_block_device_name(device))/1073741824, 1)) + 'GiB) ' + device)
+        # A disk image file can be created instead of writing to a usb drive
+        # (to flash it later, or to boot it in a virtual machine).
+        self.comboBox_device.addItem('Disk image file')
 
     def get_file_name(self):
         # getOpenFileName returns a tuple with the file path and the filter,
@@ -566,6 +538,21 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
                         default_label = line[11:]
 
                 self.lineEdit_label.setText(default_label)
+            else:
+                # Without isoinfo, the label is read directly from the iso file.
+                try:
+                    self.lineEdit_label.setText(iso9660.get_volume_info(self.filename)['label'])
+                except (iso9660.InvalidIsoError, OSError):
+                    self.lineEdit_label.setText('')
+
+    def get_folder_name(self):
+        # Like get_file_name, but for a directory with the extracted contents of an iso file.
+        # If the user selects the cancel button, self.filename remains unchanged
+        directory = QtWidgets.QFileDialog.getExistingDirectory(self, 'Select the extracted iso file', self.homedir)
+        if directory != '':
+            self.filename = directory
+            # The directory name is used as the label, like the volume id of an iso file.
+            self.lineEdit_label.setText(os.path.basename(directory.rstrip('/')))
 
     def get_table(self):
         if self.comboBox_partscheme.currentIndex() == 0 or self.comboBox_partscheme.currentIndex() == 1:
@@ -608,9 +595,47 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
     def get_label(self):
         return self.lineEdit_label.text()
 
+    def get_reserved_space(self):
+        # In MiB, since Qt signals can't carry bigger numbers than 32-bit ints.
+        return self.spinBox_reservedspace.value()
+
     def get_device_id(self):
         return self.device_id_list[self.comboBox_device.currentIndex()]
 
+    def get_device(self):
+        # Returns the name of the selected block device, or '' if the target is a disk image file.
+        if self.comboBox_device.currentIndex() == len(self.device_id_list):
+            return ''
+        return usb_info.get_block_device_name(self.get_device_id())
+
+    def get_device_size(self, device):
+        if device == '':
+            return self.image_size * 1048576
+        return usb_info.get_size(device)
+
+    def get_image_target(self, method):
+        # Asks for the path and the size of the disk image file. The default size is the one needed
+        # for the selected file (or 1GiB when formatting or reading from stdin). Returns False if the user cancelled.
+        # The file dialog asks the user to confirm replacing the file if it already exists.
+        image_path = QtWidgets.QFileDialog.getSaveFileName(directory=self.homedir,
+                                                           filter='Disk Images (*.img);;All Files (*)',
+                                                           initialFilter='Disk Images (*.img)')[0]
+        if image_path == '':
+            return False
+
+        if method == 'format' or self.filename == '-':
+            default_size = 1024
+        else:
+            default_size = -(-iso.get_required_size(self.filename, method) // 1048576) + self.get_reserved_space()
+        image_size, accepted = QtWidgets.QInputDialog.getInt(self, 'USBMaker', 'Size of the image file (MiB):',
+                                                             default_size, 2, 2147483647)
+        if not accepted:
+            return False
+
+        self.image_path = image_path
+        self.image_size = image_size
+        return True
+
     def get_filesystem(self):
         return self.comboBox_filesystem.currentText().lower()
 
@@ -620,263 +645,309 @@ class MainWindow(QtWidgets.QMainWindow, Ui_MainWindow):
         else:
             return 'uefi'
 
+    def get_source_error(self, method):
+        # Checks that the selected file isn't empty or truncated (for example, by an interrupted download)
+        # before anything is written to the device. Returns the error message, or '' if there's none.
+        if self.filename == '-':
+            # Nothing can be checked before reading stdin.
+            if method == 'iso':
+                return 'The image can only be read from stdin with the DD Image method.'
+            return ''
+        if os.path.isdir(self.filename):
+            if method == 'dd':
+                return 'A directory can only be copied with the ISO Image method.'
+            return ''
+        file_size = os.path.getsize(self.filename)
+        if file_size == 0:
+            return 'The selected file is empty.'
+
+        try:
+            volume_info = iso9660.get_volume_info(self.filename)
+        except iso9660.InvalidIsoError:
+            # Raw images (for the dd method) don't need to be iso files.
+            if method == 'iso':
+                return 'The selected file is not a valid iso file.'
+            return ''
+
+        if volume_info['size'] > file_size:
+            return 'The selected file is truncated (it should be ' + str(volume_info['size']) + ' bytes, but it\'s ' + \
+                   str(file_size) + ' bytes).'
+        return ''
+
+    def get_capacity_warning(self, required_size, device_size):
+        # The device is big enough, but its size is unusual for the image (for example, a DVD sized image
+        # on a stick with barely enough space, or a very big device, which may be an external hard drive
+        # instead of a usb stick). Returns the warning message, or '' if there's none.
+        if device_size < required_size * 3 // 2:
+            reason = 'The device is only slightly bigger than the image, so there will be little free space left.'
+        elif device_size > 274877906944:
+            reason = 'The device is bigger than 256GiB, so it may not be the usb drive you want to use.'
+        else:
+            return ''
+
+        return reason + ' (image size: ' + str(round(required_size/1073741824, 1)) + 'GiB, device size: ' + \
+            str(round(device_size/1073741824, 1)) + 'GiB)'
+
+    def get_preflight_issues(self, device, method, missing_deps=None, filesystem='', reserved_space=0, label='',
+                             source_error=None):
+        # Runs all the checks that apply to the method ('format', 'dd' or 'iso') before anything
+        # is done to the device, so that the user is told about every problem at once.
+        # filesystem is needed for the 'format' and 'iso' methods, to check the label (and for
+        # the 'iso' method, in which the usb drive is mounted, that it can be mounted).
+        # reserved_space is the space (in bytes) left unallocated at the end of the device.
+        # Returns a list of [blocking, message] issues. Blocking issues stop the operation,
+        # and the others are warnings that the user has to confirm.
+        # device is '' if the target is a disk image file, which isn't created yet.
+        # source_error is the result of get_source_error, if it was already checked.
+        issues = []
+        device_size = self.get_device_size(device)
+
+        holders = usb_info.get_holders(device) if device != '' else []
+        if len(holders) > 0:
+            # Writing to a device used by an active LVM volume group or an open
+            # LUKS mapping could corrupt the data in use by the system.
+            issues.append([True, 'The device is in use by ' + ', '.join(holders) + ' (for example, an LVM volume ' +
+                           'or an encrypted volume). Deactivate it before using this device.'])
+
+        processes = usb_info.get_processes_using(device) if device != '' else []
+        if len(processes) > 0:
+            # The device may be too busy to be written to (for example, by a file manager that
+            # has it open), in which case partitioning or formatting it would fail.
+            issues.append([False, 'The device is open by ' + ', '.join(processes) + '.'])
+
+        if device == '' and not self.dependencies['losetup']:
+            # The disk image file is attached to a loop device by losetup.
+            missing_deps = (missing_deps or []) + ['losetup']
+
+        if missing_deps is not None and len(missing_deps) > 0:
+            issues.append([True, 'Could not find the software required to perform this action. The dependencies ' +
+                           'that need to be installed are:\n' + '\n'.join(missing_deps)])
+
+        if method == 'iso' and filesystem == 'ntfs' and not mount.can_mount(filesystem):
+            issues.append([True, 'Could not find a driver to mount NTFS. Install ntfs-3g, or use a kernel ' +
+                           'with the ntfs3 driver.'])
+        elif method == 'iso' and not mount.can_mount(filesystem):
+            issues.append([True, 'The running kernel doesn\'t support the ' + filesystem + ' filesystem, so the ' +
+                           'usb drive couldn\'t be mounted to copy the files.'])
+
+        if method == 'format' or method == 'iso':
+            # The user is asked whether to use the label as it will be changed (the normalized
+            # label is used afterwards, so the filesystem is created with strict labels).
+            label_message = formatting.normalize_label(label, filesystem)[1]
+            if label_message != '':
+                issues.append([False, label_message])
+
+        if (method == 'format' or method == 'iso') and filesystem == 'ext4':
+            features = formatting.get_ext4_features(self.advanced_options.get_options()['ext4_features'])
+            unknown_features = formatting.get_unknown_ext4_features(features)
+            if len(unknown_features) > 0:
+                # They're passed to mke2fs anyway, which fails if it doesn't support them.
+                issues.append([False, 'Unknown ext4 features: ' + ', '.join(unknown_features) + '. Creating the ' +
+                               'filesystem will fail if they aren\'t supported by mke2fs.'])
+
+        # The partition starts at 1MiB and must be at least 1MiB long, like in partitioning.get_partition_end.
+        if reserved_space > 0 and (device_size - reserved_space) // 1048576 < 2:
+            issues.append([True, 'The unallocated space at the end is too big for this device.'])
+            return issues
+
+        if method == 'dd' or method == 'iso':
+            if source_error is None:
+                source_error = self.get_source_error(method)
+            if source_error != '':
+                # The other checks need a valid image.
+                issues.append([True, source_error])
+                return issues
+
+            if self.filename == '-':
+                # The size of the image isn't known when it's read from stdin.
+                return issues
+
+            required_size = iso.get_required_size(self.filename, method)
+            capacity_warning = self.get_capacity_warning(required_size, device_size)
+            if device_size - reserved_space < required_size:
+                issues.append([True, 'The device is too small for this image.'])
+            elif capacity_warning != '':
+                issues.append([False, capacity_warning])
+
+        return issues
+
+    def run_preflight(self, device, method, missing_deps=None, filesystem='', reserved_space=0, label='',
+                      source_error=None):
+        # Shows the issues found by get_preflight_issues. Returns True if there are no
+        # blocking issues and the user confirmed the warnings (if there are any).
+        issues = self.get_preflight_issues(device, method, missing_deps, filesystem, reserved_space, label,
+                                           source_error)
+        errors = [issue[1] for issue in issues if issue[0]]
+        warnings = [issue[1] for issue in issues if not issue[0]]
+
+        if len(errors) > 0:
+            QtWidgets.QMessageBox.warning(self, 'USBMaker', '\n\n'.join(errors + warnings))
+            return False
+        if len(warnings) > 0:
+            return QtWidgets.QMessageBox.question(self, 'USBMaker', '\n\n'.join(warnings) + '\n\nContinue anyway?',
+                                                  QtWidgets.QMessageBox.Yes | QtWidgets.QMessageBox.No,
+                                                  QtWidgets.QMessageBox.No) == QtWidgets.QMessageBox.Yes
+        return True
+
     def start_format(self):
         # Collect information.
         label = self.get_label()
-        device_id = self.get_device_id()
         partition_table = self.get_table()
         filesystem = self.get_filesystem()
-        device = usb_info.get_block_device_name(device_id)
+        device = self.get_device()
         badblocks_passes = self.get_badblocks_passes()
-        badblocks_file = '/tmp/usbmaker' + str(os.getpid()) + '-badblocks.txt'
+        badblocks_file = mount.get_temp_path('badblocks.txt')
         clustersize = self.get_cluster_size()
+        reserved_space = self.get_reserved_space()
 
         if not self.dependencies['badblocks']:
             badblocks_passes = 0
 
-        # missing_deps is a string containing the list of missing dependencies.
-        missing_deps = ''
-        mkfs_found = False
-
-        if filesystem == 'fat32' or filesystem == 'fat16':
-            if self.dependencies['mkfs.fat']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.fat'
-        elif filesystem == 'exfat':
-            if self.dependencies['mkfs.exfat']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.exfat'
-        elif filesystem == 'ntfs':
-            if self.dependencies['mkfs.ntfs']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.ntfs'
-        elif filesystem == 'udf':
-            if self.dependencies['mkfs.udf']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.udf'
-        elif filesystem == 'ext4':
-            if self.dependencies['mkfs.ext4']:
-                mkfs_found = True
+        if device == '' and not self.get_image_target('format'):
+            return
+
+        missing_deps = dependencies.get_missing_dependencies(self.dependencies, filesystem) + \
+            dependencies.get_missing_option_dependencies(self.dependencies, self.advanced_options.get_options())
+        if self.run_preflight(device, 'format', missing_deps, filesystem, reserved_space * 1048576, label):
+            label = formatting.normalize_label(label, filesystem)[0]
+            args = [filesystem, partition_table, label, clustersize, badblocks_passes, badblocks_file, reserved_space,
+                    self.advanced_options.get_options()]
+            if device == '':
+                # Send a signal to the worker object to start the build_image() function.
+                self.signal_image.emit(self.image_path, self.image_size, 'format', args)
             else:
-                missing_deps += '\nmkfs.ext4'
-        elif filesystem == 'btrfs':
-            if self.dependencies['mkfs.btrfs']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.btrfs'
-        else:
-            missing_deps += '\nUnknown filesystem: "' + filesystem + '"'
-
-        if not self.dependencies['parted']:
-            missing_deps += '\nparted'
-
-        if not mkfs_found or not self.dependencies['parted']:
-            QtWidgets.QMessageBox.warning(self, 'USBMaker', 'Could not find the software required to perform this ' +
-                                          'action. The dependencies that need to be installed are:\n' + missing_deps)
-        else:
-            # Send a signal to the worker object to start the format() function.
-            self.signal_format.emit(device, filesystem, partition_table, label, clustersize, badblocks_passes,
-                                    badblocks_file)
+                # Send a signal to the worker object to start the format() function.
+                self.signal_format.emit(device, *args)
 
     def start_dd(self):
         # Collect information.
-        device_id = self.get_device_id()
-        device = usb_info.get_block_device_name(device_id)
+        device = self.get_device()
         badblocks_passes = self.get_badblocks_passes()
-        badblocks_file = '/tmp/usbmaker' + str(os.getpid()) + '-badblocks.txt'
+        badblocks_file = mount.get_temp_path('badblocks.txt')
 
         if not self.dependencies['badblocks']:
             badblocks_passes = 0
 
         if not self.dependencies['dd']:
-            QtWidgets.QMessageBox.warning(self, 'USBMaker', 'Could not find the software required to perform this ' +
-                                          'action. The dependencies that need to be installed are:\n' + '\ndd')
-        else:
-            # Send a signal to the worker object to start the make_bootable_dd() function.
-            self.signal_dd.emit(device, self.filename, badblocks_passes, badblocks_file)
+            missing_deps = ['dd']
+        else:
+            missing_deps = []
+
+        if device == '' and not self.get_image_target('dd'):
+            return
+
+        if self.run_preflight(device, 'dd', missing_deps):
+            options = self.advanced_options.get_options()
+            if device != '':
+                # The device is found again by its ID if it reconnects after being written to.
+                options['device_id'] = self.get_device_id()
+            args = [self.filename, badblocks_passes, badblocks_file, options]
+            if device == '':
+                # Send a signal to the worker object to start the build_image() function.
+                self.signal_image.emit(self.image_path, self.image_size, 'dd', args)
+            else:
+                # Send a signal to the worker object to start the make_bootable_dd() function.
+                self.signal_dd.emit(device, *args)
 
     def start_iso(self):
         # Collect information.
         label = self.get_label()
-        device_id = self.get_device_id()
         partition_table = self.get_table()
         filesystem = self.get_filesystem()
-        device = usb_info.get_block_device_name(device_id)
+        device = self.get_device()
         badblocks_passes = self.get_badblocks_passes()
-        badblocks_file = '/tmp/usbmaker' + str(os.getpid()) + '-badblocks.txt'
+        badblocks_file = mount.get_temp_path('badblocks.txt')
         clustersize = self.get_cluster_size()
         target = self.get_target()
-
-        iso_mountpoint = '/tmp/usbmaker' + str(os.getpid()) + '-iso'
-        mount.mount_iso(self.filename, iso_mountpoint)
-
-        bootloader = [iso.get_uefi_bootloader_name(iso_mountpoint), iso.get_bios_bootloader_name(iso_mountpoint)]
-
-        # Check if a UEFI bootloader is present.
-        if os.path.isfile(iso_mountpoint + '/boot/efi/bootx64.efi') or \
-           os.path.isfile(iso_mountpoint + '/boot/efi/bootia32.efi'):
-            uefi_bootloader_installed = True
-        else:
-            uefi_bootloader_installed = False
-
-        mount.unmount(iso_mountpoint)
-
-        # Ask user whether to replace the bootloader or use the included one.
-        if uefi_bootloader_installed:
-            if QtWidgets.QMessageBox.question(self.main_window, 'Replace UEFI bootloader?',
-                                              'This ISO image already contains a UEFI bootloader.\n' +
-                                              'Do you want to replace the UEFI bootloader?',
-                                              QtWidgets.QMessageBox.Yes | QtWidgets.QMessageBox.No,
-                                              QtWidgets.QMessageBox.No) == QtWidgets.QMessageBox.Yes:
-                replace_uefi_bootloader = True
-            else:
-                replace_uefi_bootloader = False
-        else:
-            replace_uefi_bootloader = True
-
-        # Change the target variable to preserve the UEFI bootloader.
-        if not replace_uefi_bootloader:
-            if target == 'uefi':
-                target = 'none'
-            else:
-                target = 'bios'
+        reserved_space = self.get_reserved_space()
+        options = self.advanced_options.get_options()
+
+        if options['update']:
+            # Only the files are updated, so the partition table, the label and the bootloader aren't used.
+            if device == '':
+                self.label_status.setText('Error: only a usb drive can be updated, not a disk image file.')
+            elif self.run_preflight(device, 'iso', [], filesystem):
+                self.signal_iso.emit(device, self.filename, filesystem, partition_table, target, ['', ''], label,
+                                     clustersize, 0, badblocks_file, self.syslinux, self.syslinux_modules, self.grldr,
+                                     0, options)
+            return
+
+        if device == '' and not self.get_image_target('iso'):
+            return
 
         if not self.dependencies['badblocks']:
             badblocks_passes = 0
 
-        # missing_deps is a string containing the list of missing dependencies.
-        missing_deps = ''
-        mkfs_found = False
-        bootloader_found = False
-
-        if filesystem == 'fat32' or filesystem == 'fat16':
-            if self.dependencies['mkfs.fat']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.fat'
-        elif filesystem == 'exfat':
-            if self.dependencies['mkfs.exfat']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.exfat'
-        elif filesystem == 'ntfs':
-            if self.dependencies['mkfs.ntfs']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.ntfs'
-        elif filesystem == 'udf':
-            if self.dependencies['mkfs.udf']:
-                mkfs_found = True
+        # The dependencies depend on the bootloaders in the iso file, so it's read before the
+        # preflight checks (if it's valid), and every issue is shown in the same dialog.
+        source_error = self.get_source_error('iso')
+        ask_replace_uefi = False
+        if source_error == '':
+            if os.path.isdir(self.filename):
+                # The iso file was already extracted to a directory, so it doesn't need to be mounted.
+                iso_mountpoint = self.filename
             else:
-                missing_deps += '\nmkfs.udf'
-        elif filesystem == 'ext4':
-            if self.dependencies['mkfs.ext4']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.ext4'
-        elif filesystem == 'btrfs':
-            if self.dependencies['mkfs.btrfs']:
-                mkfs_found = True
-            else:
-                missing_deps += '\nmkfs.btrfs'
-        else:
-            missing_deps += '\nUnknown filesystem: "' + filesystem + '"'
+                iso_mountpoint = mount.get_temp_path('iso')
+                mount.mount_iso(self.filename, iso_mountpoint)
 
-        if target == 'none':
-            bootloader_found = True
+            bootloader = [iso.get_uefi_bootloader_name(iso_mountpoint), iso.get_bios_bootloader_name(iso_mountpoint)]
 
-        elif target == 'uefi':
-            if bootloader[0] == 'syslinux':
-                if self.dependencies['syslinux']:
-                    bootloader_found = True
-                else:
-                    missing_deps += '\nsyslinux'
-            elif bootloader[0] == 'grub2':
-                if self.dependencies['grub2']:
-                    bootloader_found = True
-                else:
-                    missing_deps += '\ngrub2'
-            elif bootloader[0] == 'systemd-boot':
-                if self.dependencies['systemd-boot']:
-                    bootloader_found = True
-                else:
-                    missing_deps += '\nsystemd-boot'
+            # Check if a UEFI bootloader is present.
+            if os.path.isfile(iso_mountpoint + '/boot/efi/bootx64.efi') or \
+               os.path.isfile(iso_mountpoint + '/boot/efi/bootia32.efi'):
+                uefi_bootloader_installed = True
             else:
-                missing_deps += '\nUnknown bootloader (UEFI)'
+                uefi_bootloader_installed = False
 
-        elif target == 'bios':
-            if bootloader[1] == 'syslinux':
-                if self.dependencies['syslinux']:
-                    bootloader_found = True
-                else:
-                    missing_deps += '\nsyslinux'
-            elif bootloader[1] == 'grub4dos':
-                if self.dependencies['grub4dos']:
-                    bootloader_found = True
-                else:
-                    missing_deps += '\ngrub4dos'
-            else:
-                missing_deps += '\nUnknown bootloader (BIOS)'
+            if not os.path.isdir(self.filename):
+                mount.unmount(iso_mountpoint)
 
-        elif target == 'both':
-            if bootloader[0] == 'syslinux' and bootloader[1] == 'syslinux':
-                if self.dependencies['syslinux']:
-                    bootloader_found = True
-                else:
-                    missing_deps += '\nsyslinux'
-            else:
-                uefi_bootloader_found = False
-                bios_bootloader_found = False
-
-                if bootloader[0] == 'syslinux':
-                    if self.dependencies['syslinux']:
-                        uefi_bootloader_found = True
-                    else:
-                        missing_deps += '\nsyslinux'
-                elif bootloader[0] == 'grub2':
-                    if self.dependencies['grub2']:
-                        uefi_bootloader_found = True
-                    else:
-                        missing_deps += '\ngrub2'
-                elif bootloader[0] == 'systemd-boot':
-                    if self.dependencies['systemd-boot']:
-                        uefi_bootloader_found = True
-                    else:
-                        missing_deps += '\nsystemd-boot'
-                else:
-                    missing_deps += '\nUnknown bootloader (UEFI)'
-
-                if bootloader[1] == 'syslinux':
-                    if self.dependencies['syslinux']:
-                        bios_bootloader_found = True
-                    else:
-                        missing_deps += '\nsyslinux'
-                elif bootloader[1] == 'grub4dos':
-                    if self.dependencies['grub4dos']:
-                        bios_bootloader_found = True
-                    else:
-                        missing_deps += '\ngrub4dos'
+            missing_deps = dependencies.get_missing_dependencies(self.dependencies, filesystem, target, bootloader)
+
+            # If the iso file already contains a UEFI bootloader, the target is changed to preserve it,
+            # and the user is asked whether to replace it only after the preflight checks pass.
+            # If the dependencies needed to replace it are missing, the included one is kept without asking.
+            if uefi_bootloader_installed:
+                uefi_target = target
+                if target == 'uefi':
+                    target = 'none'
                 else:
-                    missing_deps += '\nUnknown bootloader (BIOS)'
+                    target = 'bios'
 
-                if uefi_bootloader_found and bios_bootloader_found:
-                    bootloader_found = True
+                preserved_missing_deps = dependencies.get_missing_dependencies(self.dependencies, filesystem, target,
+                                                                               bootloader)
+                ask_replace_uefi = set(missing_deps) <= set(preserved_missing_deps)
+                missing_deps = preserved_missing_deps
+        else:
+            # The bootloaders are unknown, so only the filesystem's dependencies are checked.
+            bootloader = ['', '']
+            missing_deps = dependencies.get_missing_dependencies(self.dependencies, filesystem)
 
-        if not self.dependencies['parted']:
-            missing_deps += '\nparted'
+        missing_deps += dependencies.get_missing_option_dependencies(self.dependencies, options)
+        if not self.run_preflight(device, 'iso', missing_deps, filesystem, reserved_space * 1048576, label,
+                                  source_error):
+            return
 
-        if not mkfs_found or not bootloader_found or not self.dependencies['parted']:
-            QtWidgets.QMessageBox.warning(self, 'USBMaker', 'Could not find the software required to perform this ' +
-                                          'action. The dependencies that need to be installed are:\n' + missing_deps)
+        # Ask user whether to replace the bootloader or use the included one.
+        if ask_replace_uefi:
+            if QtWidgets.QMessageBox.question(self, 'Replace UEFI bootloader?',
+                                              'This ISO image already contains a UEFI bootloader.\n' +
+                                              'Do you want to replace the UEFI bootloader?',
+                                              QtWidgets.QMessageBox.Yes | QtWidgets.QMessageBox.No,
+                                              QtWidgets.QMessageBox.No) == QtWidgets.QMessageBox.Yes:
+                target = uefi_target
+
+        label = formatting.normalize_label(label, filesystem)[0]
+
+        args = [self.filename, filesystem, partition_table, target, bootloader, label, clustersize,
+                badblocks_passes, badblocks_file, self.syslinux, self.syslinux_modules, self.grldr, reserved_space,
+                options]
+        if device == '':
+            # Send a signal to the worker object to start the build_image() function.
+            self.signal_image.emit(self.image_path, self.image_size, 'iso', args)
         else:
             # Send a signal to the worker object to start the make_bootable_iso() function.
-            self.signal_iso.emit(device, self.filename, filesystem, partition_table, target, bootloader, label,
-                                 clustersize, badblocks_passes, badblocks_file, self.syslinux, self.syslinux_modules,
-                                 self.grldr)
+            self.signal_iso.emit(device, *args)
 
     def start(self):
         # Check if there's a device selected.
@@ -919,151 +990,27 @@ class WorkerObject(QtCore.QObject):
         self.signal_set_status.connect(self.main_window.set_status)
         self.signal_show_badblocks_messagebox.connect(self.main_window.show_badblocks_messagebox)
 
-    @QtCore.pyqtSlot(str, str, str, str, int, int, str)
-    def format(self, device, filesystem, partition_table, label, clustersize, badblocks_passes, badblocks_file):
-        # Requires: parted, mkfs.*
-        self.signal_set_enabled.emit(False)
-        self.signal_set_progress.emit(0)
-
-        # Unmount partitions before continuing.
-        mount.unmount_all_partitions(device)
-
-        self.signal_set_status.emit('Creating the partition table...')
-
-        # Partition the usb drive.
-        partitioning.create_partition_table(device, partition_table)
-
-        if badblocks_passes > 0:
-            self.signal_set_status.emit('Checking for bad blocks...')
-            if clustersize == -1:
-                formatting.check_badblocks(device, str(badblocks_passes), badblocks_file)
-            else:
-                formatting.check_badblocks(device, str(badblocks_passes), badblocks_file, str(clustersize))
-
-            # Show message box informing the user of the badblocks check.
-            self.signal_show_badblocks_messagebox.emit(badblocks_file)
-
-        self.signal_set_progress.emit(25)
-        self.signal_set_status.emit('Creating the partition...')
-
-        partitioning.create_partition_wrapper(device, filesystem)
-
-        if partition_table == 'gpt':
-            partitioning.change_partition_name(device, label)
-
-        self.signal_set_progress.emit(50)
-        self.signal_set_status.emit('Creating the filesystem...')
-
-        # Create the filesystem.
-        if badblocks_passes > 0:
-            formatting.create_filesystem(device, '1', filesystem, str(clustersize), label, badblocks_file)
-        else:
-            formatting.create_filesystem(device, '1', filesystem, str(clustersize), label)
-
-        self.signal_set_progress.emit(100)
-        self.signal_set_status.emit('Completed.')
-
-        self.signal_set_enabled.emit(True)
-
-    @QtCore.pyqtSlot(str, str, int, str)
-    def make_bootable_dd(self, device, filename, badblocks_passes, badblocks_file):
-        # Requires: dd
-        self.signal_set_enabled.emit(False)
-        self.signal_set_progress.emit(0)
-
-        # Unmount partitions before continuing.
-        mount.unmount_all_partitions(device)
-
-        if badblocks_passes > 0:
-            self.signal_set_status.emit('Checking for bad blocks...')
-            formatting.check_badblocks(device, str(badblocks_passes), badblocks_file)
-
-            # Show message box informing the user of the badblocks check.
-            self.signal_show_badblocks_messagebox.emit(badblocks_file)
-
-        # Write image to usb
-        self.signal_set_status.emit('Writing image...')
-        dd.dd(filename, device)
-
-        self.signal_set_progress.emit(100)
-        self.signal_set_status.emit('Completed.')
-
-        self.signal_set_enabled.emit(True)
-
-    @QtCore.pyqtSlot(str, str, str, str, str, list, str, int, int, str, list, list, str)
-    def make_bootable_iso(self, device, filename, filesystem, partition_table, target, bootloader, label, clustersize,
-                          badblocks_passes, badblocks_file, syslinux, syslinux_modules, grldr):
-        # Requires: parted, mkfs.*, bootloader(grub2, syslinux, grub4dos, systemd-boot)
-        self.signal_set_enabled.emit(False)
-        self.signal_set_progress.emit(0)
-
-        # Unmount partitions before continuing.
-        mount.unmount_all_partitions(device)
-
-        self.signal_set_status.emit('Creating the partition table...')
-
-        # Partition the usb drive.
-        partitioning.create_partition_table(device, partition_table)
-
-        self.signal_set_status.emit('Creating the partition...')
-        self.signal_set_progress.emit(5)
-
-        partitioning.create_partition_wrapper(device, filesystem)
-
-        if partition_table == 'gpt':
-            partitioning.change_partition_name(device, label)
-
-        if badblocks_passes > 0:
-            self.signal_set_status.emit('Checking for bad blocks...')
-            if clustersize == -1:
-                formatting.check_badblocks(device, str(badblocks_passes), badblocks_file)
-            else:
-                formatting.check_badblocks(device, str(badblocks_passes), badblocks_file, str(clustersize))
-
-            # Show message box informing the user of the badblocks check.
-            self.signal_show_badblocks_messagebox.emit(badblocks_file)
-
-        self.signal_set_status.emit('Creating the filesystem...')
-        self.signal_set_progress.emit(10)
-
-        # Create the filesystem.
-        if badblocks_passes > 0:
-            formatting.create_filesystem(device, '1', filesystem, str(clustersize), label, badblocks_file)
-        else:
-            formatting.create_filesystem(device, '1', filesystem, str(clustersize), label)
-
-        self.signal_set_status.emit('Copying files...')
-        self.signal_set_progress.emit(25)
-
-        # Mount the usb and the iso file.
-        usb_mountpoint = '/tmp/usbmaker' + str(os.getpid()) + '-usb'
-        iso_mountpoint = '/tmp/usbmaker' + str(os.getpid()) + '-iso'
-        mount.mount(device + '1', usb_mountpoint)
-        mount.mount_iso(filename, iso_mountpoint)
-
-        # Copy the iso contents to the usb drive.
-        iso.copy_iso_contents(iso_mountpoint, usb_mountpoint)
-
-        # Unmount the iso file.
-        mount.unmount(iso_mountpoint)
-
-        self.signal_set_status.emit('Installing the bootloader...')
-        self.signal_set_progress.emit(80)
+        # The operations are run by worker.Worker, which updates the gui through the signals.
+        self.worker = worker.Worker(self.signal_set_enabled.emit, self.signal_set_progress.emit,
+                                    self.signal_set_status.emit, self.signal_show_badblocks_messagebox.emit)
 
-        # Make the usb bootable.
-        iso.create_bootable_usb(device, usb_mountpoint, bootloader, target, partition_table,
-                                syslinux, syslinux_modules, grldr)
+    # Each of the slots runs the corresponding function of the worker, with the same arguments.
 
-        # Unmount the usb drive.
-        mount.unmount(usb_mountpoint)
+    @QtCore.pyqtSlot(str, str, str, str, int, int, str, int, dict)
+    def format(self, *args):
+        self.worker.format(*args)
 
-        if target == 'both':
-            partitioning.mark_bootable(device, partition_table)
+    @QtCore.pyqtSlot(str, str, int, str, dict)
+    def make_bootable_dd(self, *args):
+        self.worker.make_bootable_dd(*args)
 
-        self.signal_set_status.emit('Completed.')
-        self.signal_set_progress.emit(100)
+    @QtCore.pyqtSlot(str, str, str, str, str, list, str, int, int, str, list, list, str, int, dict)
+    def make_bootable_iso(self, *args):
+        self.worker.make_bootable_iso(*args)
 
-        self.signal_set_enabled.emit(True)
+    @QtCore.pyqtSlot(str, int, str, list)
+    def build_image(self, *args):
+        self.worker.build_image(*args)
 
 app = QtWidgets.QApplication(sys.argv)
 window = MainWindow()
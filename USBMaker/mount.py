@@ -17,12 +17,101 @@
 
 import os
 import subprocess
+import shutil
+import tempfile
 import usb_info
 
 
-def mount(partition, mountpoint):
+def get_temp_path(name, parent='', prefix='usbmaker'):
+    # Returns the path to use for a temporary file or mount point, which is in the
+    # format <parent>/<prefix><pid>-<name> (for example: /tmp/usbmaker1234-usb).
+    # parent defaults to the system's temporary directory.
+    if parent == '':
+        parent = tempfile.gettempdir()
+
+    if not os.path.isdir(parent) or not os.access(parent, os.W_OK):
+        raise TempDirError(parent)
+
+    return os.path.join(parent, prefix + str(os.getpid()) + '-' + name)
+
+
+# The default mount options for each filesystem, used when copying files to it.
+# utf8 makes file names with non-ASCII characters be copied correctly,
+# and shortname=mixed keeps the case of short names on FAT.
+DEFAULT_MOUNT_OPTIONS = {'fat32': 'utf8,shortname=mixed', 'fat16': 'utf8,shortname=mixed'}
+
+
+def is_kernel_filesystem(filesystem):
+    # Returns True if the running kernel supports the filesystem, loading its module if needed.
+    for attempt in range(2):
+        with open('/proc/filesystems', mode='r') as filesystems_file:
+            for line in filesystems_file:
+                if line.strip() != '' and line.split()[-1] == filesystem:
+                    return True
+
+        if attempt == 0:
+            try:
+                subprocess.run(['modprobe', '-q', filesystem], stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+            except FileNotFoundError:
+                return False
+    return False
+
+
+def can_mount(filesystem):
+    # Returns True if the filesystem (as named in USBMaker, for example: 'fat32') can be mounted.
+    # Without this, a filesystem whose module isn't available would be created, then fail to mount.
+    # exFAT can also be mounted with FUSE (exfat-fuse) on kernels older than 5.7.
+    if filesystem.lower() == 'fat32' or filesystem.lower() == 'fat16':
+        return is_kernel_filesystem('vfat')
+    elif filesystem.lower() == 'exfat':
+        return is_kernel_filesystem('exfat') or shutil.which('mount.exfat-fuse') is not None
+    elif filesystem.lower() == 'ntfs':
+        try:
+            get_ntfs_driver()
+        except NtfsDriverNotFoundError:
+            return False
+        return True
+    else:
+        return is_kernel_filesystem(filesystem.lower())
+
+
+def get_ntfs_driver():
+    # NTFS can be mounted with the kernel's ntfs3 driver (Linux 5.15+), or with ntfs-3g (FUSE).
+    # ntfs3 is preferred, since it's faster. The kernel's old ntfs driver is read-only,
+    # so it can't be used.
+    if is_kernel_filesystem('ntfs3'):
+        return 'ntfs3'
+    elif shutil.which('ntfs-3g') is not None:
+        return 'ntfs-3g'
+    else:
+        raise NtfsDriverNotFoundError
+
+
+def get_default_mount_options(filesystem):
+    # The kernel's exFAT driver needs iocharset=utf8 for the same reason, but exfat-fuse (used when
+    # the kernel doesn't have it) always uses UTF-8 and fails to mount with it.
+    if filesystem.lower() == 'exfat':
+        return 'iocharset=utf8' if is_kernel_filesystem('exfat') else ''
+    return DEFAULT_MOUNT_OPTIONS.get(filesystem.lower(), '')
+
+
+def mount(partition, mountpoint, filesystem='', options='', ntfs_driver=''):
+    # filesystem selects the default options (see get_default_mount_options), and the driver for NTFS.
+    # options are added after the default ones, so they take precedence over them.
+    # ntfs_driver is 'ntfs3' or 'ntfs-3g', or '' to use whichever is available.
+    args = ['mount']
+    if filesystem.lower() == 'ntfs':
+        if ntfs_driver == '':
+            ntfs_driver = get_ntfs_driver()
+        args += ['-t', ntfs_driver]
+
     os.makedirs(mountpoint)
-    subprocess.run(['mount', '/dev/' + partition, mountpoint])
+
+    mount_options = [option for option in [get_default_mount_options(filesystem), options] if option != '']
+    if len(mount_options) > 0:
+        args += ['-o', ','.join(mount_options)]
+
+    subprocess.run(args + ['/dev/' + partition, mountpoint])
 
 
 def mount_iso(iso, mountpoint):
@@ -44,3 +133,42 @@ def unmount_all_partitions(device):
     partitions = usb_info.get_partitions(device)
     for partition in partitions:
         unmount_partition(partition)
+
+
+def get_mounted_partitions(device):
+    # Returns the partitions of the device that are mounted, read from /proc/mounts.
+    mounted_partitions = []
+    with open('/proc/mounts', mode='r') as mounts_file:
+        for line in mounts_file:
+            source = line.split()[0]
+            for partition in usb_info.get_partitions(device):
+                if source == '/dev/' + partition and partition not in mounted_partitions:
+                    mounted_partitions.append(partition)
+    return mounted_partitions
+
+
+def eject(device):
+    # Unmounts all of the device's partitions, flushes them, and ejects the device so it
+    # can be removed safely. Returns the list of partitions that were unmounted.
+    mounted_partitions = get_mounted_partitions(device)
+    for partition in mounted_partitions:
+        unmount_partition(partition)
+    os.sync()
+
+    try:
+        subprocess.run(['eject', '/dev/' + device])
+    except FileNotFoundError:
+        # udisksctl also powers off the device.
+        try:
+            subprocess.run(['udisksctl', 'power-off', '--block-device', '/dev/' + device])
+        except FileNotFoundError:
+            pass
+    return mounted_partitions
+
+
+class TempDirError(OSError):
+    pass
+
+
+class NtfsDriverNotFoundError(OSError):
+    pass
@@ -16,43 +16,122 @@
 #   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
 
 import subprocess
+import re
+import os
+import time
+import uuid
 import usb_info
 
 
-def create_partition_table(device, table):
+def discard_device(device):
+    # Discards (TRIMs) the whole device, which can improve the write performance and
+    # lifespan of SSDs and some flash drives. This erases everything on the device.
+    # Returns False if the device doesn't support discard, so the caller can warn the user.
+    if not usb_info.supports_discard(device):
+        return False
+
+    subprocess.run(['blkdiscard', '/dev/' + device])
+    return True
+
+
+def wipe_device(device, use_wipefs=True, timeout=60):
+    # Quickly removes everything that could make the old partitions or filesystems be detected again:
+    # the signatures of the filesystems (with wipefs, if it's installed), and the first and the last MiB of
+    # the device, which contain the MBR and the primary and backup GPT. Without this, a partition created
+    # at the same place as an old one may still be detected (by blkid, for example) as the old filesystem.
+    # wipefs hangs or fails on some devices, so it can be skipped (use_wipefs=False), and it's stopped after
+    # timeout seconds. Then, only the signatures overwritten by mkfs are removed, and the others (for example,
+    # of a bigger filesystem that used to be on the device) may still confuse the system.
+    if use_wipefs:
+        try:
+            for partition in usb_info.get_partitions(device):
+                subprocess.run(['wipefs', '--all', '--quiet', '/dev/' + partition], timeout=timeout)
+            subprocess.run(['wipefs', '--all', '--quiet', '/dev/' + device], timeout=timeout)
+        except (FileNotFoundError, subprocess.TimeoutExpired):
+            pass
+
+    device_size = usb_info.get_size(device)
+    wipe_size = min(1048576, device_size)
+    with open('/dev/' + device, 'r+b') as device_file:
+        device_file.write(bytes(wipe_size))
+        device_file.seek(device_size - wipe_size)
+        device_file.write(bytes(wipe_size))
+        device_file.flush()
+        os.fsync(device_file.fileno())
+
+
+def is_valid_gpt_entries(gpt_entries):
+    # gpt_entries is the number of partition entries in a GPT table. The UEFI specification
+    # requires at least 128, and the entries (128 bytes each, 4 per 512 byte sector) have to
+    # fit before the first partition, which starts at 1MiB.
+    return 128 <= gpt_entries <= 8128 and gpt_entries % 4 == 0
+
+
+def get_default_partition_table(sys_path='/sys'):
+    # If this system booted with UEFI, GPT is used by default, since the drive is most likely
+    # going to be used on a similar machine. This is only a guess based on the current machine.
+    # sys_path is where sysfs is mounted.
+    if os.path.isdir(sys_path + '/firmware/efi'):
+        return 'gpt'
+    return 'msdos'
+
+
+def create_partition_table(device, table, gpt_entries=128):
+    # gpt_entries is only used by GPT (see is_valid_gpt_entries).
     if table.lower() == 'msdos' or table.lower() == 'mbr':
         subprocess.run(['parted', '-s', '/dev/' + device, 'mktable', 'msdos'])
     elif table.lower() == 'gpt':
-        subprocess.run(['parted', '-s', '/dev/' + device, 'mktable', 'gpt'])
+        if not is_valid_gpt_entries(gpt_entries):
+            raise InvalidPartitionTableError
 
+        if gpt_entries == 128:
+            subprocess.run(['parted', '-s', '/dev/' + device, 'mktable', 'gpt'])
+        else:
+            # parted always creates 128 entries.
+            subprocess.run(['sfdisk', '/dev/' + device],
+                           input=('label: gpt\ntable-length: ' + str(gpt_entries) + '\n').encode())
 
-def create_partition(device, fs_type='ext2'):
-    subprocess.run(['parted', '-s', '/dev/' + device, 'mkpart', 'primary', fs_type, '1MiB', '100%'])
+
+def create_partition(device, fs_type='ext2', reserved_space=0):
+    if reserved_space == 0:
+        subprocess.run(['parted', '-s', '/dev/' + device, 'mkpart', 'primary', fs_type, '1MiB', '100%'])
+    else:
+        create_custom_sized_partition(device, get_partition_end(device, reserved_space), fs_type)
 
 
 def create_custom_sized_partition(device, size, fs_type='ext2'):
     subprocess.run(['parted', '-s', '/dev/' + device, 'mkpart', 'primary', fs_type, '1MiB', size])
 
 
-def create_partition_wrapper(device, fs_type):
+def get_partition_end(device, reserved_space):
+    # Returns the end of a partition that leaves reserved_space bytes
+    # unallocated at the end of the device, rounded down to a MiB boundary.
+    # Partitions start at 1MiB, so the reserved space must leave room for at least 1MiB after that.
+    end = (usb_info.get_size(device) - reserved_space) // 1048576
+    if reserved_space < 0 or end < 2:
+        raise InsufficientSpaceError
+    return str(end) + 'MiB'
+
+
+def create_partition_wrapper(device, fs_type, reserved_space=0):
     if fs_type.lower() == 'fat32':
-        create_partition(device, 'fat32')
+        create_partition(device, 'fat32', reserved_space)
     elif fs_type.lower() == 'fat16':
-        if usb_info.get_size(device) > 4294967296:
+        if usb_info.get_size(device) - reserved_space > 4294967296:
             create_custom_sized_partition(device, '4096MiB', 'fat16')
         else:
-            create_partition(device, 'fat16')
+            create_partition(device, 'fat16', reserved_space)
     elif fs_type.lower() == 'ntfs':
-        create_partition(device, 'ntfs')
+        create_partition(device, 'ntfs', reserved_space)
     elif fs_type.lower() == 'exfat':
         # exFAT and NTFS share the same fs-type
-        create_partition(device, 'ntfs')
+        create_partition(device, 'ntfs', reserved_space)
     elif fs_type.lower() == 'ext4':
-        create_partition(device, 'ext4')
+        create_partition(device, 'ext4', reserved_space)
     elif fs_type.lower() == 'btrfs':
-        create_partition(device, 'btrfs')
+        create_partition(device, 'btrfs', reserved_space)
     else:
-        create_partition(device)
+        create_partition(device, reserved_space=reserved_space)
 
 
 def mark_bootable(device, partition_table):
@@ -62,10 +141,175 @@ def mark_bootable(device, partition_table):
         subprocess.run(['parted', '-s', '/dev/' + device, 'set', '1', 'legacy_boot', 'on'])
 
 
+def get_bootable_partitions(device):
+    # Returns the list of partition numbers with the boot flag set (MBR) or the
+    # legacy_boot flag set (GPT), read from parted's machine readable output, in which
+    # each partition is a line like "1:1049kB:16.0GB:16.0GB:fat32::boot, lba;".
+    parted_output = subprocess.run(['parted', '-m', '-s', '/dev/' + device, 'print'],
+                                   stdout=subprocess.PIPE).stdout.decode()
+
+    bootable_partitions = []
+    for line in parted_output.splitlines():
+        if re.match('[0-9]+:', line):
+            flags = line.rstrip(';').split(':')[-1].split(', ')
+            if 'boot' in flags or 'legacy_boot' in flags:
+                bootable_partitions.append(line.split(':')[0])
+    return bootable_partitions
+
+
+def check_alignment(device, partition, alignment='optimal'):
+    # Returns True if the partition is aligned to the device's optimal I/O size
+    # (alignment='optimal'), or at least to its physical sector size (alignment='minimal').
+    # A misaligned partition still works, but writes to it are slower and wear
+    # the flash memory more.
+    result = subprocess.run(['parted', '-s', '/dev/' + device, 'align-check', alignment, partition],
+                            stdout=subprocess.DEVNULL)
+    return result.returncode == 0
+
+
 def change_partition_name(device, name):
     subprocess.run(['parted', '-s', '/dev/' + device, 'name', '1', name])
 
 
-def partprobe():
-    # Informs the kernel of changes in the partition table.
-    subprocess.run(['partprobe'])
+def is_valid_disk_identifier(table, identifier):
+    # The disk identifier is the 32-bit disk signature (8 hex digits, optionally
+    # prefixed with "0x") for MBR, or the disk GUID for GPT.
+    if table.lower() == 'msdos' or table.lower() == 'mbr':
+        return re.match('(0x)?[0-9a-fA-F]{8}$', identifier) is not None
+    elif table.lower() == 'gpt':
+        return re.match('[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$',
+                        identifier) is not None
+    return False
+
+
+def set_disk_identifier(device, table, identifier):
+    # Sets the disk identifier (see is_valid_disk_identifier). By default it's random.
+    if not is_valid_disk_identifier(table, identifier):
+        raise InvalidDiskIdentifierError
+    if (table.lower() == 'msdos' or table.lower() == 'mbr') and identifier[:2] != '0x':
+        identifier = '0x' + identifier
+
+    subprocess.run(['sfdisk', '--disk-id', '/dev/' + device, identifier])
+
+
+def set_partition_guid(device, partition, guid):
+    # Sets the unique GUID of a GPT partition, which is random by default. Together with
+    # set_disk_identifier, this makes two images built from the same inputs identical.
+    if not re.match('[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$', guid):
+        raise InvalidDiskIdentifierError
+
+    subprocess.run(['sfdisk', '--part-uuid', '/dev/' + device, partition, guid])
+
+
+def get_deterministic_guid(name):
+    # Returns a GUID derived from name (for example: "myimage-partition1"), which is
+    # always the same for the same name, to use with set_disk_identifier or set_partition_guid.
+    return str(uuid.uuid5(uuid.NAMESPACE_URL, 'usbmaker:' + name))
+
+
+def get_partition_attributes(text):
+    # Splits a comma-separated list of GPT attribute bit numbers (as written by the user,
+    # for example: "62,63") into the list used by set_partition_attributes.
+    attributes = []
+    for bit in text.split(','):
+        if bit.strip() == '':
+            continue
+        if not re.match('[0-9]+$', bit.strip()):
+            raise InvalidPartitionAttributeError
+        attributes.append(int(bit))
+    return attributes
+
+
+def get_attribute_names(attributes):
+    # Returns the attribute bits (see set_partition_attributes) as sfdisk's list of attributes
+    # (for example: "RequiredPartition,GUID:62,63"). Raises InvalidPartitionAttributeError for
+    # reserved bits, so the attributes can be checked before the device is partitioned.
+    attribute_names = []
+    type_specific_bits = []
+    for bit in attributes:
+        if bit == 0:
+            attribute_names.append('RequiredPartition')
+        elif bit == 1:
+            attribute_names.append('NoBlockIOProtocol')
+        elif bit == 2:
+            attribute_names.append('LegacyBIOSBootable')
+        elif 48 <= bit <= 63:
+            type_specific_bits.append(str(bit))
+        else:
+            raise InvalidPartitionAttributeError
+
+    if len(type_specific_bits) > 0:
+        attribute_names.append('GUID:' + ','.join(type_specific_bits))
+    return ','.join(attribute_names)
+
+
+def set_partition_attributes(device, partition, attributes):
+    # Sets the GPT attribute bits of a partition, replacing the ones already set.
+    # attributes is a list of bit numbers. Bits 0 to 2 are defined for all partitions
+    # (0: required, 1: no block IO protocol, 2: legacy BIOS bootable) and bits 48 to 63
+    # depend on the partition type (for Microsoft basic data partitions, 60: read-only,
+    # 62: hidden and 63: no automount). The other bits are reserved.
+    subprocess.run(['sfdisk', '--part-attrs', '/dev/' + device, partition, get_attribute_names(attributes)])
+
+
+def wait_for_partition(device, partition, timeout=10):
+    # After the partition table is changed, the kernel re-reads it and udev recreates
+    # the partition devices, so they can be missing for a moment.
+    # Returns False if the partition doesn't appear within timeout seconds.
+    partition_path = '/dev/' + usb_info.get_partition_name(device, partition)
+    delay = 0.1
+    start_time = time.monotonic()
+    while not os.path.exists(partition_path):
+        if time.monotonic() - start_time > timeout:
+            return False
+        time.sleep(delay)
+        delay = min(delay * 2, 1)
+    return True
+
+
+def rescan_device(device):
+    # Asks the kernel to rescan the (SCSI) device, so that enclosures that cache
+    # aggressively don't show stale data after the device was written to.
+    # Returns False if the device can't be rescanned, which isn't an error.
+    if not os.path.isfile(usb_info.SYS_BLOCK_PATH + '/' + device + '/device/rescan'):
+        return False
+
+    try:
+        with open(usb_info.SYS_BLOCK_PATH + '/' + device + '/device/rescan', mode='w') as rescan_file:
+            rescan_file.write('1')
+    except OSError:
+        return False
+
+    return True
+
+
+def partprobe(device):
+    # Informs the kernel of changes in the partition table, then waits for udev
+    # to finish creating the partition devices.
+    # Neither is required (parted already informs the kernel and wait_for_partition
+    # waits for the devices), so a missing partprobe or udevadm is ignored.
+    try:
+        subprocess.run(['partprobe', '/dev/' + device])
+    except FileNotFoundError:
+        pass
+
+    try:
+        subprocess.run(['udevadm', 'settle'])
+    except FileNotFoundError:
+        pass
+
+
+class InsufficientSpaceError(OSError):
+    pass
+
+
+class InvalidDiskIdentifierError(OSError):
+    pass
+
+
+class InvalidPartitionAttributeError(OSError):
+    pass
+
+
+class InvalidPartitionTableError(OSError):
+    pass
@@ -15,6 +15,7 @@
 #   You should have received a copy of the GNU General Public License
 #   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
 
+import os
 import subprocess
 
 
@@ -44,5 +45,22 @@ def get_home_from_uid(uid):
     raise UserNotFoundError
 
 
+def get_gid_from_uid(uid):
+    # Returns the user's primary group.
+    passwd = parse_passwd()
+    for user in passwd:
+        if user[2] == uid:
+            return user[3]
+    raise UserNotFoundError
+
+
+def get_invoking_uid():
+    # Returns the uid of the user who started USBMaker with pkexec or sudo, or '' if it's unknown.
+    for variable in ['PKEXEC_UID', 'SUDO_UID']:
+        if os.getenv(variable) is not None:
+            return os.getenv(variable)
+    return ''
+
+
 class UserNotFoundError(Exception):
     pass
@@ -17,11 +17,19 @@
 
 import os
 import re
+import time
+
+# Where udev creates the persistent links to the devices and partitions.
+DEV_DISK_PATH = '/dev/disk'
+# Where the kernel shows the block devices in sysfs, and the processes in procfs.
+# Tests replace them with directories that look like them.
+SYS_BLOCK_PATH = '/sys/block'
+PROC_PATH = '/proc'
 
 
 def get_id_list():
     # The list of all storage devices is obtained from /dev/disk/by-id/
-    devices = os.listdir('/dev/disk/by-id/')
+    devices = os.listdir(DEV_DISK_PATH + '/by-id/')
 
     # All partitions and non-usb storage devices are removed from the list.
     not_usb_list = []
@@ -44,36 +52,160 @@ def get_id_list():
     return devices
 
 
+def wait_for_device(timeout=60, serial=''):
+    # Waits until a usb drive is connected, and returns its ID (from get_id_list).
+    # Drives that were already connected are ignored. If serial is given, only a drive
+    # whose ID contains it is accepted (IDs end with the serial number, for example:
+    # usb-SanDisk_Cruzer_Blade_4C530001234567891234-0:0).
+    # Returns '' if no drive is connected within timeout seconds.
+    known_ids = get_id_list()
+    start_time = time.monotonic()
+    while time.monotonic() - start_time < timeout:
+        for device_id in get_id_list():
+            if device_id not in known_ids and serial in device_id:
+                return device_id
+        time.sleep(0.5)
+    return ''
+
+
 def get_block_device_name(device_id):
     # os.readlink returns a relative path (for example: ../../sda), so
     # we just need to remove the ../../ part.
-    return os.readlink('/dev/disk/by-id/' + device_id)[6:]
+    return os.readlink(DEV_DISK_PATH + '/by-id/' + device_id)[6:]
+
+
+def is_connected(device):
+    # The device's directory in /sys/block/ is removed when it's disconnected.
+    return os.path.isdir(SYS_BLOCK_PATH + '/' + device)
 
 
 def get_size(device):
     # /sys/block/*/size is read to get the number of sectors in the usb.
-    with open('/sys/block/' + device + '/size', mode='r') as size_file:
+    # The kernel always counts these in 512 byte sectors, even on devices with
+    # 4096 byte logical sectors (4Kn), so the logical block size isn't used here.
+    with open(SYS_BLOCK_PATH + '/' + device + '/size', mode='r') as size_file:
         sectors = int(size_file.read().rstrip())
 
-    # /sys/block/*/queue/logical_block_size is read to get the size of sectors in the usb.
-    with open('/sys/block/' + device + '/queue/logical_block_size', mode='r') as block_size_file:
-        block_size = int(block_size_file.read().rstrip())
-
-    # The sector size is multiplied by the number of sectors to get the number of bytes.
-    return sectors * block_size
+    # The number of sectors is multiplied by 512 to get the number of bytes.
+    return sectors * 512
 
 
 def get_block_size(device):
-    with open('/sys/block/' + device + '/queue/hw_sector_size', mode='r') as sector_size_file:
+    with open(SYS_BLOCK_PATH + '/' + device + '/queue/hw_sector_size', mode='r') as sector_size_file:
         sector_size = int(sector_size_file.read().rstrip())
 
     return sector_size
 
 
+def supports_discard(device):
+    # Devices that don't support discard (TRIM) report a maximum discard size of 0.
+    if not os.path.isfile(SYS_BLOCK_PATH + '/' + device + '/queue/discard_max_bytes'):
+        return False
+
+    with open(SYS_BLOCK_PATH + '/' + device + '/queue/discard_max_bytes', mode='r') as discard_file:
+        discard_max_bytes = int(discard_file.read().rstrip())
+
+    return discard_max_bytes > 0
+
+
+def get_write_block_size(device):
+    # Returns the block size to use when writing an image to the device.
+    # The default is 4MiB, adjusted to a multiple of the optimal I/O size
+    # (or, if the device doesn't report one, the minimum I/O size).
+    block_size = 4194304
+
+    for io_size_file_name in ['optimal_io_size', 'minimum_io_size']:
+        if os.path.isfile(SYS_BLOCK_PATH + '/' + device + '/queue/' + io_size_file_name):
+            with open(SYS_BLOCK_PATH + '/' + device + '/queue/' + io_size_file_name, mode='r') as io_size_file:
+                io_size = int(io_size_file.read().rstrip())
+
+            # Devices that don't have a preference report 0.
+            if io_size > 0:
+                return max(io_size, block_size - block_size % io_size)
+
+    return block_size
+
+
+def get_partition_name(device, partition):
+    # When the device name ends with a number (for example: nvme0n1, mmcblk0 or loop0),
+    # the partition number is separated from it by a 'p' (nvme0n1p1, mmcblk0p1, loop0p1).
+    # Otherwise, it's just appended to it (sdb1).
+    if re.search('[0-9]$', device):
+        return device + 'p' + partition
+    else:
+        return device + partition
+
+
+def get_partition_size(device, partition):
+    # Like get_size, but for a partition (for example: /sys/block/sdb/sdb1/size).
+    partition_name = get_partition_name(device, partition)
+    with open(SYS_BLOCK_PATH + '/' + device + '/' + partition_name + '/size', mode='r') as size_file:
+        sectors = int(size_file.read().rstrip())
+
+    return sectors * 512
+
+
+def get_persistent_paths(device, partition):
+    # Returns the paths in /dev/disk/by-label/, /dev/disk/by-uuid/ and /dev/disk/by-partuuid/
+    # (created by udev) that point to the partition, which can be used to find it in scripts.
+    persistent_paths = []
+    partition_path = '/dev/' + get_partition_name(device, partition)
+    for directory in [DEV_DISK_PATH + '/by-label', DEV_DISK_PATH + '/by-uuid', DEV_DISK_PATH + '/by-partuuid']:
+        if os.path.isdir(directory):
+            for link in sorted(os.listdir(directory)):
+                if os.path.realpath(directory + '/' + link) == partition_path:
+                    persistent_paths.append(directory + '/' + link)
+    return persistent_paths
+
+
 def get_partitions(device):
+    # Each partition has a directory inside the device's directory in /sys/block/
+    # (for example: /sys/block/sdb/sdb1), which contains a "partition" file.
     part_list = []
-    for file in os.listdir('/dev/'):
-        if re.search(device, file):
+    for file in os.listdir(SYS_BLOCK_PATH + '/' + device):
+        if os.path.isfile(SYS_BLOCK_PATH + '/' + device + '/' + file + '/partition'):
             part_list.append(file)
-    part_list.remove(device)
     return part_list
+
+
+def get_space_usage(device):
+    # Returns the size of the device, the space used by its partitions and the space left
+    # unallocated (for example, the reserved space at the end), all in bytes.
+    # The unallocated space includes the partition table and the gap before the first partition.
+    used_space = 0
+    for partition in get_partitions(device):
+        with open(SYS_BLOCK_PATH + '/' + device + '/' + partition + '/size', mode='r') as size_file:
+            used_space += int(size_file.read().rstrip()) * 512
+
+    return [get_size(device), used_space, get_size(device) - used_space]
+
+
+def get_processes_using(device):
+    # Returns the processes that have the device or one of its partitions open, as
+    # "name (pid)" strings (for example: "gvfsd-metadata (1234)"), found by looking
+    # at the open files of every process in /proc/.
+    device_paths = ['/dev/' + device] + ['/dev/' + partition for partition in get_partitions(device)]
+    processes = []
+    for pid in os.listdir(PROC_PATH):
+        if not pid.isdigit():
+            continue
+
+        try:
+            for fd in os.listdir(PROC_PATH + '/' + pid + '/fd'):
+                if os.readlink(PROC_PATH + '/' + pid + '/fd/' + fd) in device_paths:
+                    with open(PROC_PATH + '/' + pid + '/comm', mode='r') as comm_file:
+                        processes.append(comm_file.read().rstrip() + ' (' + pid + ')')
+                    break
+        except OSError:
+            # The process exited meanwhile, or it belongs to another user.
+            continue
+    return processes
+
+
+def get_holders(device):
+    # Returns the list of devices (for example: dm-0) that are using the device or
+    # one of its partitions, such as LVM volumes or open LUKS mappings.
+    holder_list = os.listdir(SYS_BLOCK_PATH + '/' + device + '/holders')
+    for partition in get_partitions(device):
+        holder_list += os.listdir(SYS_BLOCK_PATH + '/' + device + '/' + partition + '/holders')
+    return holder_list
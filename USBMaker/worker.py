@@ -0,0 +1,881 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import os
+import re
+import errno
+import subprocess
+import usb_info
+import partitioning
+import formatting
+import dd
+import mount
+import iso
+import image
+import checksum
+import uid_info
+
+# The messages shown for the errors of USBMaker's modules that don't carry one.
+ERROR_MESSAGES = {
+    dd.ImageTooLargeError: 'the image is larger than the device.',
+    dd.InvalidOffsetError: 'the offset to write the image at is invalid.',
+    formatting.InvalidOptionError: 'an option of the filesystem is invalid.',
+    iso.ExtractionError: 'the archive couldn\'t be extracted (it may be corrupted, or have files that the '
+                         'filesystem doesn\'t support, like symlinks on FAT32).',
+    mount.NtfsDriverNotFoundError: 'no NTFS driver was found (install ntfs-3g, or use a kernel with ntfs3).',
+    partitioning.InsufficientSpaceError: 'the device is too small for the partition.',
+    partitioning.InvalidDiskIdentifierError: 'the disk identifier is invalid.',
+    partitioning.InvalidPartitionAttributeError: 'the partition attributes are invalid.',
+    partitioning.InvalidPartitionTableError: 'the partition table options are invalid.',
+    image.InvalidSectorSizeError: 'the sector size of the disk image is invalid.'
+}
+
+# The options of the operations (set in the advanced options dialog), with their defaults:
+# check_image_state: compare the size, modification time and inode of the image before and after it's written,
+# to detect if it was changed or replaced meanwhile (for example, on a network share).
+# verify: after writing an image with the DD Image method, read it back from the device and compare
+# all of it ('full') or some parts of it ('sampled', which is faster). With the ISO Image method, both compare
+# all the copied files with the ones in the iso file. '' doesn't verify anything.
+# work_dir: the directory where the image is read back to when verifying all of it ('' is the system's
+# temporary directory).
+# update: with the ISO Image method, only update the files of a usb drive created from an older version of the
+# iso file (see Worker.run_update), instead of recreating it.
+# delete_removed: when updating, delete the files that aren't in the iso file anymore.
+# check_filesystem: check the new filesystem (see formatting.check_filesystem) after creating it.
+# rescan: after writing an image with the DD Image method, make the kernel rescan the device (see
+# partitioning.rescan_device), so that it doesn't show the old data cached by the enclosure.
+# discard: discard (TRIM) the whole device before partitioning it (see partitioning.discard_device).
+# disk_identifier: the disk identifier (see partitioning.is_valid_disk_identifier) set after creating the
+# partition table. '' keeps the random one.
+# deterministic_name: if not '', the disk identifier and the GUID of the partition are derived from it (see
+# partitioning.get_deterministic_guid) instead of being random, so the same name always creates the same partition
+# table (the disk_identifier option takes precedence for the disk identifier).
+# gpt_attributes: a comma-separated list of the GPT attribute bits to set on the partition (see
+# partitioning.set_partition_attributes), for example "62,63" to hide it from Windows.
+# gpt_entries: the number of partition entries of a GPT partition table (see partitioning.is_valid_gpt_entries).
+# '' is the default (128).
+# partition_hashes: when formatting or creating a drive from an iso file is completed, show the hash of the contents
+# of each partition (see checksum.get_partition_hashes), to record the state of the drive.
+# verify_checksum: before writing an image (DD Image) or copying the files of an iso file (ISO Image), compare its
+# hash with the one in sums_file (see checksum.verify_iso), or in the sums file next to it if sums_file is ''.
+# check_capacity: before writing to the device, check that it can store as much data as it reports (see
+# dd.check_capacity), which counterfeit drives can't. This takes a while, and it erases the device.
+# write_info_file: with the ISO Image method, write a file recording the iso file the drive was created from (see
+# iso.write_info_file) to the root of the usb drive, named info_file_name ('' is USBMAKER_INFO.txt).
+# archive: when formatting, a tar archive (compressed or not) to extract to the new filesystem (see
+# iso.extract_archive), for drives that are only used to carry data. '' doesn't extract anything.
+# set_ownership: on filesystems that store owners (ext4 and Btrfs), give the files to the user who started USBMaker
+# with pkexec or sudo (see iso.set_ownership), so they can be edited without root.
+# custom_config: with the ISO Image method, a boot menu config file (syslinux.cfg or grub.cfg) that replaces the one of
+# the iso file after the bootloader is installed (see iso.install_custom_config). '' keeps the iso file's one.
+# wait_for_reconnect: if the device disconnects after writing an image with the DD Image method (some devices
+# reconnect after their partition table changes), wait up to RECONNECT_TIMEOUT seconds for the device with the ID
+# device_id (set by the gui, see usb_info.get_id_list) to be connected again, and continue with it.
+# eject: eject the device (see mount.eject) when the operation is completed, so it can be unplugged.
+# ext4_features: a comma-separated list of ext4 features to enable, or to disable if prefixed with "^" (see
+# formatting.create_ext4_filesystem).
+DEFAULT_OPTIONS = {
+    'check_image_state': False,
+    'verify': '',
+    'work_dir': '',
+    'update': False,
+    'delete_removed': False,
+    'check_filesystem': False,
+    'ext4_features': '',
+    'discard': False,
+    'rescan': False,
+    'disk_identifier': '',
+    'deterministic_name': '',
+    'gpt_attributes': '',
+    'gpt_entries': '',
+    'partition_hashes': False,
+    'verify_checksum': False,
+    'sums_file': '',
+    'check_capacity': False,
+    'write_info_file': False,
+    'info_file_name': '',
+    'archive': '',
+    'set_ownership': False,
+    'custom_config': '',
+    'wait_for_reconnect': False,
+    'device_id': '',
+    'eject': False
+}
+
+# How long to wait for a device to reconnect (see the wait_for_reconnect option), in seconds.
+RECONNECT_TIMEOUT = 60
+
+
+def get_completed_status(notes, status='Completed.'):
+    # The status shown at the end of an operation, followed by the notes about it (one per line).
+    return '\n'.join([status] + notes)
+
+
+def get_gpt_entries(options):
+    # The gpt_entries option as a number (it's checked by Worker.check_partitioning_options).
+    if options['gpt_entries'] == '':
+        return 128
+    return int(options['gpt_entries'])
+
+
+def get_options(options):
+    # Returns the options with the default value of the ones that aren't given.
+    all_options = dict(DEFAULT_OPTIONS)
+    if options is not None:
+        all_options.update(options)
+    return all_options
+
+
+def get_error_message(error):
+    # Describes an OSError (which the errors of USBMaker's modules are too) or a subprocess.SubprocessError
+    # to the user.
+    if type(error) in ERROR_MESSAGES:
+        return ERROR_MESSAGES[type(error)]
+    elif isinstance(error, mount.TempDirError):
+        return 'could not create a temporary directory in ' + error.args[0] + '.'
+    elif isinstance(error, dd.InsufficientTempSpaceError):
+        return 'there isn\'t enough free space in ' + error.args[0] + ' to verify the image.'
+    elif isinstance(error, OSError) and error.errno == errno.EIO:
+        return 'reading or writing the device failed (input/output error), which usually means that it is failing.'
+    elif isinstance(error, subprocess.TimeoutExpired):
+        return error.cmd[0] + ' didn\'t finish after ' + str(error.timeout) + ' seconds.'
+    elif isinstance(error, subprocess.CalledProcessError):
+        return error.cmd[0] + ' failed (exit status ' + str(error.returncode) + ').'
+    else:
+        return str(error)
+
+
+class Worker:
+    # Runs the operations started from the gui (in the worker thread). It doesn't use Qt, so the gui is
+    # updated through the functions it's given (WorkerObject's signals), which also allows testing it.
+    def __init__(self, set_enabled, set_progress, set_status, show_badblocks_messagebox):
+        self.set_enabled = set_enabled
+        self.set_progress = set_progress
+        self.set_status = set_status
+        self.show_badblocks_messagebox = show_badblocks_messagebox
+
+    def check_device(self, device):
+        # If the device was disconnected, the user is told so and False is returned,
+        # so the operation can be stopped instead of failing with confusing errors.
+        if usb_info.is_connected(device):
+            return True
+        else:
+            self.set_status('Error: the device was disconnected.')
+            self.set_enabled(True)
+            return False
+
+    def handle_error(self, device, error):
+        # Stops the operation after it failed with an OSError (which the errors of USBMaker's modules
+        # are too) or a subprocess.SubprocessError (a command that failed or timed out).
+        # The usb drive and the iso file may still be mounted.
+        for name in ['usb', 'iso']:
+            try:
+                mountpoint = mount.get_temp_path(name)
+                if os.path.ismount(mountpoint):
+                    mount.unmount(mountpoint)
+            except OSError:
+                pass
+
+        # Once a usb drive is unplugged, every access to it fails (with ENODEV, ENXIO or EIO), so that's
+        # reported as such instead of as a confusing error (which could make the user think the image is
+        # corrupted). EIO while the drive is still connected means that reading or writing it failed.
+        if not usb_info.is_connected(device):
+            self.set_status('Error: the device was disconnected.')
+        else:
+            self.set_status('Error: ' + get_error_message(error))
+        self.set_enabled(True)
+
+    def verify_checksum(self, filename, sums_file, notes):
+        # Compares the hash of the image or iso file with the one in the sums file (or the one found next to it,
+        # if sums_file is ''). Returns False (after telling the user) if they don't match or the hash isn't found.
+        # An image read from stdin or an extracted iso file can't be verified, which is added to notes.
+        if filename == '-' or os.path.isdir(filename):
+            notes.append('The checksum wasn\'t verified, since there is no image file.')
+            return True
+
+        if sums_file == '':
+            sums_file = checksum.find_sums_file(filename)
+        if sums_file == '':
+            self.set_status('Error: no ' + checksum.DEFAULT_ALGORITHM.upper() + 'SUMS file listing the image was '
+                            'found next to it.')
+            self.set_enabled(True)
+            return False
+
+        self.set_status('Verifying the checksum of the image...')
+        try:
+            verified = checksum.verify_iso(filename, sums_file)
+        except (checksum.HashNotFoundError, UnicodeDecodeError):
+            self.set_status('Error: the image isn\'t listed in ' + os.path.basename(sums_file) + '.')
+            self.set_enabled(True)
+            return False
+        if not verified:
+            self.set_status('Error: the checksum of the image doesn\'t match the one in ' +
+                            os.path.basename(sums_file) + ', so it\'s corrupted or was modified.')
+            self.set_enabled(True)
+            return False
+        return True
+
+    def complete(self, device, options, status):
+        # Shows the status of a completed operation, after ejecting the device (with the eject option).
+        # Disk image files are attached to loop devices, which can't be ejected.
+        if options['eject'] and not device.startswith('loop'):
+            self.set_status('Ejecting the device...')
+            mount.eject(device)
+        self.set_status(status)
+
+    def check_capacity(self, device):
+        # Returns False (after telling the user) if the device can't store as much data as it reports.
+        self.set_status('Checking the capacity of the device...')
+        real_capacity = dd.check_capacity(device)
+        if real_capacity < usb_info.get_size(device):
+            self.set_status('Error: the device can only store about ' + str(real_capacity // 1048576) + 'MiB of the ' +
+                            str(usb_info.get_size(device) // 1048576) + 'MiB it reports, which means that it\'s '
+                            'counterfeit.')
+            self.set_enabled(True)
+            return False
+        return True
+
+    def install_custom_config(self, usb_mountpoint, config_path, bootloader, notes):
+        # Installs the custom boot menu config file for SYSLINUX or GRUB2 (whichever the iso file uses). The files
+        # it references that aren't on the usb drive are added to notes, since the drive may not boot.
+        if 'syslinux' in [name.lower() for name in bootloader]:
+            config_bootloader = 'syslinux'
+        elif 'grub2' in [name.lower() for name in bootloader]:
+            config_bootloader = 'grub2'
+        else:
+            notes.append('The boot menu config file wasn\'t installed, since the iso file doesn\'t use SYSLINUX or '
+                         'GRUB2.')
+            return
+
+        missing_files = iso.install_custom_config(usb_mountpoint, config_path, config_bootloader)
+        if len(missing_files) > 0:
+            notes.append('The boot menu config file references files that aren\'t on the usb drive: ' +
+                         ', '.join(missing_files))
+
+    def set_ownership(self, usb_mountpoint, filesystem, notes):
+        # Gives the files of the usb drive to the user who started USBMaker (see uid_info.get_invoking_uid).
+        # If that isn't possible, the files stay owned by root, and the reason is added to notes.
+        uid = uid_info.get_invoking_uid()
+        if filesystem != 'ext4' and filesystem != 'btrfs':
+            notes.append('The owner of the files wasn\'t set, since the filesystem doesn\'t store owners.')
+        elif uid == '':
+            notes.append('The owner of the files wasn\'t set, since USBMaker wasn\'t started with pkexec or sudo.')
+        else:
+            self.set_status('Setting the owner of the files...')
+            try:
+                iso.set_ownership(usb_mountpoint, uid)
+            except uid_info.UserNotFoundError:
+                notes.append('The owner of the files wasn\'t set, since the user with the uid ' + uid +
+                             ' wasn\'t found.')
+
+    def check_partitioning_options(self, partition_table, options):
+        # Checks the options used when partitioning the device, before anything is done to it.
+        # Returns False (after telling the user) if one of them is invalid.
+        if options['disk_identifier'] != '' and not partitioning.is_valid_disk_identifier(partition_table,
+                                                                                          options['disk_identifier']):
+            if partition_table == 'gpt':
+                self.set_status('Error: the disk identifier of a GPT partition table must be a GUID.')
+            else:
+                self.set_status('Error: the disk identifier of an MBR partition table must be 8 hexadecimal digits.')
+            self.set_enabled(True)
+            return False
+
+        if options['gpt_entries'] != '' and (not re.match('[0-9]+$', options['gpt_entries']) or
+                                             not partitioning.is_valid_gpt_entries(int(options['gpt_entries']))):
+            self.set_status('Error: the number of GPT partition entries must be a multiple of 4 from 128 to 8128.')
+            self.set_enabled(True)
+            return False
+
+        if options['gpt_attributes'] != '':
+            if partition_table != 'gpt':
+                self.set_status('Error: only the partitions of a GPT partition table have attributes.')
+                self.set_enabled(True)
+                return False
+            try:
+                partitioning.get_attribute_names(partitioning.get_partition_attributes(options['gpt_attributes']))
+            except partitioning.InvalidPartitionAttributeError:
+                self.set_status('Error: the partition attributes must be a comma-separated list of the bits 0, 1, 2 '
+                                'and 48 to 63.')
+                self.set_enabled(True)
+                return False
+        return True
+
+    def set_disk_identifier(self, device, partition_table, options):
+        # Sets the disk identifier given in the options, or the one derived from the deterministic name
+        # (the disk signature of an MBR partition table is the first 8 digits of the GUID).
+        if options['disk_identifier'] != '':
+            partitioning.set_disk_identifier(device, partition_table, options['disk_identifier'])
+        elif options['deterministic_name'] != '':
+            guid = partitioning.get_deterministic_guid(options['deterministic_name'])
+            if partition_table == 'gpt':
+                partitioning.set_disk_identifier(device, partition_table, guid)
+            else:
+                partitioning.set_disk_identifier(device, partition_table, guid[:8])
+
+    def create_filesystems(self, device, partitions):
+        # Creates the filesystems with formatting.create_filesystems. If any of them failed, the user
+        # is told the error of each partition (one per line), and False is returned.
+        errors = formatting.create_filesystems(device, partitions)
+        if len(errors) == 0:
+            return True
+        if not usb_info.is_connected(device):
+            self.set_status('Error: the device was disconnected.')
+        else:
+            self.set_status('\n'.join(['Error: creating the filesystem of partition ' + partition + ' failed: ' +
+                                        get_error_message(error) for partition, error in errors]))
+        self.set_enabled(True)
+        return False
+
+    # Each of the slots runs the corresponding run_* function, with the same arguments.
+
+    def format(self, device, *args):
+        try:
+            self.run_format(device, *args)
+        except (OSError, subprocess.SubprocessError) as error:
+            self.handle_error(device, error)
+
+    def make_bootable_dd(self, device, *args):
+        try:
+            self.run_dd(device, *args)
+        except (OSError, subprocess.SubprocessError) as error:
+            self.handle_error(device, error)
+
+    def make_bootable_iso(self, device, *args):
+        try:
+            self.run_iso(device, *args)
+        except (OSError, subprocess.SubprocessError) as error:
+            self.handle_error(device, error)
+
+    def run_format(self, device, filesystem, partition_table, label, clustersize, badblocks_passes, badblocks_file,
+                   reserved_space, options=None):
+        # Requires: parted, mkfs.*
+        # reserved_space is the space (in MiB) left unallocated at the end of the device.
+        # options are described in DEFAULT_OPTIONS.
+        options = get_options(options)
+        notes = []
+        self.set_enabled(False)
+        self.set_progress(0)
+
+        if not self.check_partitioning_options(partition_table, options):
+            return
+
+        # Unmount partitions before continuing.
+        mount.unmount_all_partitions(device)
+
+        if options['check_capacity'] and not self.check_capacity(device):
+            return
+
+        if options['discard']:
+            self.set_status('Discarding the data of the device...')
+            if not partitioning.discard_device(device):
+                notes.append('The device doesn\'t support discard (TRIM), so it wasn\'t discarded.')
+
+        self.set_status('Creating the partition table...')
+
+        # Partition the usb drive.
+        partitioning.wipe_device(device)
+        partitioning.create_partition_table(device, partition_table, get_gpt_entries(options))
+        self.set_disk_identifier(device, partition_table, options)
+
+        if not self.check_device(device):
+            return
+
+        if badblocks_passes > 0:
+            self.set_status('Checking for bad blocks...')
+            if clustersize == -1:
+                formatting.check_badblocks(device, str(badblocks_passes), badblocks_file)
+            else:
+                formatting.check_badblocks(device, str(badblocks_passes), badblocks_file, str(clustersize))
+
+            # Show message box informing the user of the badblocks check.
+            self.show_badblocks_messagebox(badblocks_file)
+
+        self.set_progress(25)
+        self.set_status('Creating the partition...')
+
+        partitioning.create_partition_wrapper(device, filesystem, reserved_space * 1048576)
+
+        if not partitioning.check_alignment(device, '1'):
+            # It still works, so the user is only told about it.
+            notes.append('The partition isn\'t optimally aligned, so writing to it may be slower.')
+
+        if partition_table == 'gpt':
+            partitioning.change_partition_name(device, label)
+            if options['deterministic_name'] != '':
+                partitioning.set_partition_guid(device, '1', partitioning.get_deterministic_guid(
+                    options['deterministic_name'] + '-partition1'))
+            if options['gpt_attributes'] != '':
+                partitioning.set_partition_attributes(device, '1',
+                                                      partitioning.get_partition_attributes(options['gpt_attributes']))
+
+        partitioning.partprobe(device)
+
+        if not self.check_device(device):
+            return
+
+        if not partitioning.wait_for_partition(device, '1'):
+            self.set_status('Error: the partition was not found after creating it.')
+            self.set_enabled(True)
+            return
+
+        self.set_progress(50)
+        self.set_status('Creating the filesystem...')
+
+        # Create the filesystem.
+        partitions = [{'partition': '1', 'filesystem': filesystem, 'clustersize': str(clustersize), 'label': label,
+                       'features': formatting.get_ext4_features(options['ext4_features']), 'strict_label': True}]
+        if badblocks_passes > 0:
+            partitions[0]['badblocks_file'] = badblocks_file
+        if not self.create_filesystems(device, partitions):
+            return
+
+        if not self.check_device(device):
+            return
+
+        if options['check_filesystem'] and not self.check_filesystem(device, filesystem, notes):
+            return
+
+        if options['archive'] != '' or options['set_ownership']:
+            usb_mountpoint = mount.get_temp_path('usb')
+            mount.mount(usb_info.get_partition_name(device, '1'), usb_mountpoint, filesystem)
+            if options['archive'] != '':
+                self.set_status('Extracting the archive...')
+                self.set_progress(75)
+                iso.extract_archive(options['archive'], usb_mountpoint)
+            if options['set_ownership']:
+                self.set_ownership(usb_mountpoint, filesystem, notes)
+            mount.unmount(usb_mountpoint)
+
+            if not self.check_device(device):
+                return
+
+        self.add_partition_notes(device, notes)
+        if options['partition_hashes']:
+            self.set_status('Computing the hashes of the partitions...')
+            for partition, partition_hash in checksum.get_partition_hashes(device).items():
+                notes.append(checksum.DEFAULT_ALGORITHM.upper() + ' of ' + partition + ': ' + partition_hash)
+        self.set_progress(100)
+        self.complete(device, options, get_completed_status(notes))
+
+        self.set_enabled(True)
+
+    def run_dd(self, device, filename, badblocks_passes, badblocks_file, options=None):
+        # Requires: dd
+        options = get_options(options)
+        notes = []
+        self.set_enabled(False)
+        self.set_progress(0)
+
+        if options['verify_checksum'] and not self.verify_checksum(filename, options['sums_file'], notes):
+            return
+
+        # Unmount partitions before continuing.
+        mount.unmount_all_partitions(device)
+
+        if options['check_capacity'] and not self.check_capacity(device):
+            return
+
+        if badblocks_passes > 0:
+            self.set_status('Checking for bad blocks...')
+            formatting.check_badblocks(device, str(badblocks_passes), badblocks_file)
+
+            # Show message box informing the user of the badblocks check.
+            self.show_badblocks_messagebox(badblocks_file)
+
+        # Write image to usb
+        self.set_status('Writing image...')
+        check_image_state = options['check_image_state'] and filename != '-'
+        if check_image_state:
+            image_state = dd.get_image_state(filename)
+        if filename != '-':
+            image_size = os.path.getsize(filename)
+        else:
+            # The size of the image isn't known when it's read from stdin,
+            # so the progress bar is filled up to the size of the device.
+            image_size = usb_info.get_size(device)
+
+        def set_progress(written):
+            # 100% is only shown once dd finished.
+            self.set_progress(min(written * 100 // image_size, 99))
+
+        bytes_written = dd.dd(filename, device, progress=set_progress)
+
+        if options['wait_for_reconnect'] and options['device_id'] != '' and not usb_info.is_connected(device):
+            self.set_status('Waiting for the device to be connected again...')
+            device_id = usb_info.wait_for_device(RECONNECT_TIMEOUT, serial=options['device_id'])
+            if device_id != '':
+                # The device may have another name now.
+                device = usb_info.get_block_device_name(device_id)
+
+        if not self.check_device(device):
+            return
+
+        if options['rescan']:
+            # This is done before verifying the image, which would read the cached data otherwise.
+            if partitioning.rescan_device(device):
+                partitioning.partprobe(device)
+            else:
+                notes.append('The device couldn\'t be rescanned, so the new partitions may only appear after '
+                             'reconnecting it.')
+
+        self.set_progress(100)
+
+        # The size of the image isn't known when it's read from stdin.
+        if check_image_state and dd.get_image_state(filename) != image_state:
+            self.set_status('Error: the image was changed while it was being written.')
+        elif filename != '-' and bytes_written < os.path.getsize(filename):
+            # This happens if the device is too small or if a write failed.
+            self.set_status('Error: only ' + str(bytes_written) + ' of ' +
+                            str(os.path.getsize(filename)) + ' bytes were written.')
+        elif options['verify'] == 'full' and filename != '-':
+            self.set_status('Verifying the written image...')
+            if dd.dd_check(filename, device, work_dir=options['work_dir']):
+                self.complete(device, options, get_completed_status(notes, 'Completed (the written image was '
+                                                                           'verified).'))
+            else:
+                self.set_status('Error: the data on the device doesn\'t match the image.')
+        elif options['verify'] == 'sampled' and filename != '-':
+            self.set_status('Verifying parts of the written image...')
+            result = dd.dd_check_sampled(filename, device)
+            if len(result['mismatched']) > 0:
+                self.set_status('Error: ' + str(len(result['mismatched'])) + ' of the ' + str(len(result['checked'])) +
+                                ' parts of the image that were checked don\'t match the data on the device.')
+            else:
+                self.complete(device, options, get_completed_status(notes, 'Completed (' +
+                                                                    str(len(result['checked'])) +
+                                                                    ' parts of the written image were verified).'))
+        else:
+            self.complete(device, options, get_completed_status(notes))
+
+        self.set_enabled(True)
+
+    def run_iso(self, device, filename, filesystem, partition_table, target, bootloader, label, clustersize,
+                badblocks_passes, badblocks_file, syslinux, syslinux_modules, grldr, reserved_space, options=None):
+        # Requires: parted, mkfs.*, bootloader(grub2, syslinux, grub4dos, systemd-boot)
+        # reserved_space is the space (in MiB) left unallocated at the end of the device.
+        options = get_options(options)
+        if options['update']:
+            self.run_update(device, filename, filesystem, options)
+            return
+
+        notes = []
+        self.set_enabled(False)
+        self.set_progress(0)
+
+        if not self.check_partitioning_options(partition_table, options):
+            return
+
+        if options['verify_checksum'] and not self.verify_checksum(filename, options['sums_file'], notes):
+            return
+
+        if options['custom_config'] != '' and not os.path.isfile(options['custom_config']):
+            self.set_status('Error: the boot menu config file ' + options['custom_config'] + ' doesn\'t exist.')
+            self.set_enabled(True)
+            return
+
+        # Unmount partitions before continuing.
+        mount.unmount_all_partitions(device)
+
+        if options['check_capacity'] and not self.check_capacity(device):
+            return
+
+        if options['discard']:
+            self.set_status('Discarding the data of the device...')
+            if not partitioning.discard_device(device):
+                notes.append('The device doesn\'t support discard (TRIM), so it wasn\'t discarded.')
+
+        self.set_status('Creating the partition table...')
+
+        # Partition the usb drive.
+        partitioning.wipe_device(device)
+        partitioning.create_partition_table(device, partition_table, get_gpt_entries(options))
+        self.set_disk_identifier(device, partition_table, options)
+
+        if not self.check_device(device):
+            return
+
+        self.set_status('Creating the partition...')
+        self.set_progress(5)
+
+        partitioning.create_partition_wrapper(device, filesystem, reserved_space * 1048576)
+
+        if not partitioning.check_alignment(device, '1'):
+            # It still works, so the user is only told about it.
+            notes.append('The partition isn\'t optimally aligned, so writing to it may be slower.')
+
+        if partition_table == 'gpt':
+            partitioning.change_partition_name(device, label)
+            if options['deterministic_name'] != '':
+                partitioning.set_partition_guid(device, '1', partitioning.get_deterministic_guid(
+                    options['deterministic_name'] + '-partition1'))
+            if options['gpt_attributes'] != '':
+                partitioning.set_partition_attributes(device, '1',
+                                                      partitioning.get_partition_attributes(options['gpt_attributes']))
+
+        partitioning.partprobe(device)
+
+        if not self.check_device(device):
+            return
+
+        if badblocks_passes > 0:
+            self.set_status('Checking for bad blocks...')
+            if clustersize == -1:
+                formatting.check_badblocks(device, str(badblocks_passes), badblocks_file)
+            else:
+                formatting.check_badblocks(device, str(badblocks_passes), badblocks_file, str(clustersize))
+
+            # Show message box informing the user of the badblocks check.
+            self.show_badblocks_messagebox(badblocks_file)
+
+        if not partitioning.wait_for_partition(device, '1'):
+            self.set_status('Error: the partition was not found after creating it.')
+            self.set_enabled(True)
+            return
+
+        self.set_status('Creating the filesystem...')
+        self.set_progress(10)
+
+        # Create the filesystem.
+        features = formatting.get_ext4_features(options['ext4_features'])
+        if badblocks_passes > 0:
+            formatting.create_filesystem(device, '1', filesystem, str(clustersize), label, badblocks_file,
+                                         features=features, strict_label=True)
+        else:
+            formatting.create_filesystem(device, '1', filesystem, str(clustersize), label, features=features,
+                                         strict_label=True)
+
+        if not self.check_device(device):
+            return
+
+        if options['check_filesystem'] and not self.check_filesystem(device, filesystem, notes):
+            return
+
+        self.set_status('Copying files...')
+        self.set_progress(25)
+
+        # Mount the usb and the iso file.
+        # filename can also be a directory containing the extracted contents of an iso file,
+        # which is copied directly.
+        usb_mountpoint = mount.get_temp_path('usb')
+        mount.mount(usb_info.get_partition_name(device, '1'), usb_mountpoint, filesystem)
+        if os.path.isdir(filename):
+            iso_mountpoint = filename
+        else:
+            iso_mountpoint = mount.get_temp_path('iso')
+            mount.mount_iso(filename, iso_mountpoint)
+        check_image_state = options['check_image_state'] and not os.path.isdir(filename)
+        if check_image_state:
+            image_state = dd.get_image_state(filename)
+
+        # Copy the iso contents to the usb drive.
+        try:
+            iso.copy_iso_contents(iso_mountpoint, usb_mountpoint, device=device)
+        except iso.TargetReadOnlyError:
+            if not os.path.isdir(filename):
+                mount.unmount(iso_mountpoint)
+            mount.unmount(usb_mountpoint)
+            self.set_status('Error: the usb drive became read-only while copying, which usually means '
+                            'that it is failing.')
+            self.set_enabled(True)
+            return
+        except iso.OutOfInodesError:
+            if not os.path.isdir(filename):
+                mount.unmount(iso_mountpoint)
+            mount.unmount(usb_mountpoint)
+            self.set_status('Error: the filesystem ran out of inodes (there are too many files). '
+                            'Use another filesystem, like FAT32.')
+            self.set_enabled(True)
+            return
+
+        if options['verify'] != '':
+            verify_error = self.verify_files(device, filesystem, iso_mountpoint, usb_mountpoint)
+            if verify_error != '':
+                if not os.path.isdir(filename):
+                    mount.unmount(iso_mountpoint)
+                mount.unmount(usb_mountpoint)
+                self.set_status(verify_error)
+                self.set_enabled(True)
+                return
+
+        # Unmount the iso file.
+        if not os.path.isdir(filename):
+            mount.unmount(iso_mountpoint)
+
+        if not self.check_device(device):
+            mount.unmount(usb_mountpoint)
+            return
+
+        # The copied files are recorded before the bootloader is installed, so updating the drive later
+        # doesn't delete the bootloader's files.
+        iso.write_file_list(usb_mountpoint)
+
+        if check_image_state and dd.get_image_state(filename) != image_state:
+            mount.unmount(usb_mountpoint)
+            self.set_status('Error: the iso file was changed while it was being copied.')
+            self.set_enabled(True)
+            return
+
+        self.set_status('Installing the bootloader...')
+        self.set_progress(80)
+
+        # Make the usb bootable.
+        iso.create_bootable_usb(device, usb_mountpoint, bootloader, target, partition_table,
+                                syslinux, syslinux_modules, grldr)
+
+        if options['custom_config'] != '':
+            self.install_custom_config(usb_mountpoint, options['custom_config'], bootloader, notes)
+
+        if options['write_info_file']:
+            if os.path.isdir(filename):
+                notes.append('The info file wasn\'t written, since there is no iso file to compute the hash of.')
+            else:
+                iso.write_info_file(usb_mountpoint, filename, options['info_file_name'] or 'USBMAKER_INFO.txt')
+
+        if options['set_ownership']:
+            self.set_ownership(usb_mountpoint, filesystem, notes)
+
+        # Unmount the usb drive.
+        mount.unmount(usb_mountpoint)
+
+        if not self.check_device(device):
+            return
+
+        # The partition has to be marked as bootable to boot on BIOS systems.
+        if target == 'both' or target == 'bios':
+            partitioning.mark_bootable(device, partition_table)
+
+            if len(partitioning.get_bootable_partitions(device)) == 0:
+                notes.append('The partition could not be marked as bootable.')
+        self.add_partition_notes(device, notes)
+        if options['partition_hashes']:
+            self.set_status('Computing the hashes of the partitions...')
+            for partition, partition_hash in checksum.get_partition_hashes(device).items():
+                notes.append(checksum.DEFAULT_ALGORITHM.upper() + ' of ' + partition + ': ' + partition_hash)
+        self.complete(device, options, get_completed_status(notes))
+        self.set_progress(100)
+
+        self.set_enabled(True)
+
+    def run_update(self, device, filename, filesystem, options):
+        # Updates the files of a usb drive created with the ISO Image method from an older version of the iso file
+        # (or directory), with iso.update_contents. The partition table, the filesystem and the bootloader are kept.
+        # filesystem is the filesystem of the drive's partition, which is mounted with its options.
+        self.set_enabled(False)
+        self.set_progress(0)
+
+        # Unmount partitions before continuing.
+        mount.unmount_all_partitions(device)
+
+        self.set_status('Updating the files...')
+
+        usb_mountpoint = mount.get_temp_path('usb')
+        mount.mount(usb_info.get_partition_name(device, '1'), usb_mountpoint, filesystem)
+        if os.path.isdir(filename):
+            iso_mountpoint = filename
+        else:
+            iso_mountpoint = mount.get_temp_path('iso')
+            mount.mount_iso(filename, iso_mountpoint)
+
+        counts = iso.update_contents(iso_mountpoint, usb_mountpoint, options['delete_removed'])
+
+        verify_error = ''
+        if options['verify'] != '':
+            verify_error = self.verify_files(device, filesystem, iso_mountpoint, usb_mountpoint)
+
+        if not os.path.isdir(filename):
+            mount.unmount(iso_mountpoint)
+        mount.unmount(usb_mountpoint)
+
+        if not self.check_device(device):
+            return
+
+        if verify_error != '':
+            self.set_status(verify_error)
+            self.set_enabled(True)
+            return
+
+        self.set_progress(100)
+        self.set_status('Completed (files added: ' + str(counts['added']) + ', updated: ' + str(counts['updated']) +
+                        ', deleted: ' + str(counts['deleted']) + ', unchanged: ' + str(counts['unchanged']) + ').')
+        self.set_enabled(True)
+
+    def add_partition_notes(self, device, notes):
+        # Adds the space used by the new partition and the space left unallocated (see usb_info.get_space_usage),
+        # and the persistent paths of the partition (see usb_info.get_persistent_paths, so that it can be found
+        # in scripts) to notes. udev creates the paths after the partition is formatted.
+        space_usage = usb_info.get_space_usage(device)
+        notes.append('Space used by the partition: ' + str(space_usage[1] // 1048576) + 'MiB, unallocated: ' +
+                     str(space_usage[2] // 1048576) + 'MiB.')
+
+        partitioning.partprobe(device)
+        persistent_paths = usb_info.get_persistent_paths(device, '1')
+        if len(persistent_paths) > 0:
+            notes.append('The partition can be found at: ' + ', '.join(persistent_paths))
+
+    def check_filesystem(self, device, filesystem, notes):
+        # Checks the new filesystem of the first partition. Returns False (after telling the user) if it has errors.
+        # If the filesystem's checker isn't installed, the operation continues, and that's added to notes.
+        self.set_status('Checking the filesystem...')
+        result = formatting.check_filesystem(device, '1', filesystem)
+        if result is None:
+            notes.append('The filesystem wasn\'t checked, since ' + formatting.get_filesystem_checker(filesystem)[0] +
+                         ' isn\'t installed.')
+        elif not result:
+            self.set_status('Error: the new filesystem has errors, which usually means that the usb drive is failing.')
+            self.set_enabled(True)
+            return False
+        return True
+
+    def verify_files(self, device, filesystem, iso_mountpoint, usb_mountpoint):
+        # Compares the files copied to the usb drive with the ones in the iso file (see iso.get_differences).
+        # Returns the error message, or '' if all of them match. Files that are only on the drive
+        # (like the file list) don't matter.
+        self.set_status('Verifying the copied files...')
+        # The drive is mounted again, so the files are read from it instead of from the cache.
+        mount.unmount(usb_mountpoint)
+        mount.mount(usb_info.get_partition_name(device, '1'), usb_mountpoint, filesystem)
+
+        differences = iso.get_differences(iso_mountpoint, usb_mountpoint)
+        wrong_files = differences['added'] + differences['modified']
+        if len(wrong_files) == 0:
+            return ''
+        # Only the first files are listed, since there may be many of them.
+        file_list = ', '.join(wrong_files[:5])
+        if len(wrong_files) > 5:
+            file_list += ', ...'
+        return 'Error: ' + str(len(wrong_files)) + ' of the copied files don\'t match the iso file (' + file_list + ').'
+
+    def build_image(self, image_path, image_size, method, args):
+        # Builds a disk image file of image_size MiB instead of writing to a usb drive. The image is
+        # attached to a loop device, which goes through the same steps as a usb drive with the method
+        # ('format', 'dd' or 'iso'). args are the arguments of that method, after the device.
+        # Requires: losetup
+        if method == 'format':
+            build = self.format
+        elif method == 'dd':
+            build = self.make_bootable_dd
+        else:
+            build = self.make_bootable_iso
+
+        # Errors in the build itself are handled by the slot, so the ones left come from
+        # creating the image file or setting up the loop device.
+        # The file dialog already asked the user whether to replace the file, if it exists.
+        try:
+            image.build_image(image_path, image_size * 1048576, lambda device: build(device, *args), overwrite=True)
+        except (OSError, subprocess.CalledProcessError):
+            self.set_status('Error: could not create the disk image file.')
+            self.set_enabled(True)
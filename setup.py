@@ -1,7 +1,11 @@
+import re
 from setuptools import setup
 
+with open('USBMaker/version.py', mode='r', encoding='utf_8') as version_file:
+    version = re.search("VERSION = '(.+)'", version_file.read()).group(1)
+
 setup(name='USBMaker',
-      version='1.0.0',
+      version=version,
       packages=['USBMaker'],
       install_requires=['PyQt5'],
       url='https://github.com/gmes/USBMaker',
@@ -0,0 +1,23 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import sys
+import os
+
+# The modules import each other by their names (like when USBMaker is run), so their
+# directory is added to the path, the same way USBMaker/__init__.py does it.
+sys.path = [os.path.join(os.path.dirname(os.path.dirname(os.path.abspath(__file__))), 'USBMaker')] + sys.path
@@ -0,0 +1,139 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+# Builds small iso files (ISO 9660, optionally with Joliet names and an El Torito boot record)
+# for the tests, so they don't depend on mkisofs or xorriso.
+
+SECTOR_SIZE = 2048
+
+
+def both_endian(value, length):
+    return value.to_bytes(length, 'little') + value.to_bytes(length, 'big')
+
+
+def directory_record(name, extent, size, is_directory):
+    record = bytes([0, 0]) + both_endian(extent, 4) + both_endian(size, 4) + bytes(7) + \
+        bytes([2 if is_directory else 0, 0, 0]) + both_endian(1, 2) + bytes([len(name)]) + name
+    if len(record) % 2 == 1:
+        record += b'\0'
+    # The first byte is the length of the record.
+    return bytes([len(record)]) + record[1:]
+
+
+def encode_name(name, is_directory, joliet):
+    if joliet:
+        return name.encode('utf_16_be')
+    if is_directory:
+        return name.upper().encode('ascii')
+    if '.' not in name:
+        name += '.'
+    return (name.upper() + ';1').encode('ascii')
+
+
+def build_iso(path, label='', files=None, joliet=False, boot_catalog=None):
+    # files is a dictionary of names to the contents of the file (bytes), or to another
+    # dictionary for a directory. boot_catalog is the contents of the El Torito boot catalog,
+    # if the iso file should be bootable.
+    # Returns a dictionary with the extent (sector) of every directory, by path ('' is the root).
+    if files is None:
+        files = {}
+
+    # System area, the volume descriptors (primary, boot record, Joliet, terminator) and the boot catalog.
+    next_sector = 16 + 2 + int(boot_catalog is not None) * 2 + int(joliet)
+
+    # Every directory uses one sector, which is enough for the tests.
+    directories = [('', files)]
+    extents = {}
+    i = 0
+    while i < len(directories):
+        directory_path, contents = directories[i]
+        extents[directory_path] = next_sector
+        next_sector += 1
+        for name in sorted(contents):
+            if isinstance(contents[name], dict):
+                directories.append((directory_path + '/' + name, contents[name]))
+        i += 1
+
+    file_extents = {}
+    for directory_path, contents in directories:
+        for name in sorted(contents):
+            if not isinstance(contents[name], dict):
+                file_extents[directory_path + '/' + name] = next_sector
+                next_sector += max(1, (len(contents[name]) + SECTOR_SIZE - 1) // SECTOR_SIZE)
+
+    image = bytearray(next_sector * SECTOR_SIZE)
+
+    def write(sector, data):
+        image[sector * SECTOR_SIZE:sector * SECTOR_SIZE + len(data)] = data
+
+    def descriptor(descriptor_type, root_record, escape=b''):
+        data = bytearray(SECTOR_SIZE)
+        data[0] = descriptor_type
+        data[1:7] = b'CD001\1'
+        data[40:72] = label.encode('ascii').ljust(32)
+        data[80:88] = both_endian(next_sector, 4)
+        data[88:88 + len(escape)] = escape
+        data[128:132] = both_endian(SECTOR_SIZE, 2)
+        data[156:190] = root_record
+        return bytes(data)
+
+    def write_directories(names_in_joliet):
+        for directory_path, contents in directories:
+            parent_path = directory_path.rsplit('/', 1)[0] if directory_path != '' else ''
+            data = directory_record(b'\0', extents[directory_path], SECTOR_SIZE, True) + \
+                directory_record(b'\1', extents[parent_path], SECTOR_SIZE, True)
+            for name in sorted(contents):
+                is_directory = isinstance(contents[name], dict)
+                if is_directory:
+                    extent, size = extents[directory_path + '/' + name], SECTOR_SIZE
+                else:
+                    extent, size = file_extents[directory_path + '/' + name], len(contents[name])
+                data += directory_record(encode_name(name, is_directory, names_in_joliet), extent, size,
+                                         is_directory)
+            write(extents[directory_path], data)
+
+    root_record = directory_record(b'\0', extents[''], SECTOR_SIZE, True)
+    sector = 16
+    write(sector, descriptor(1, root_record))
+    sector += 1
+    if boot_catalog is not None:
+        boot_record = bytearray(SECTOR_SIZE)
+        boot_record[0] = 0
+        boot_record[1:7] = b'CD001\1'
+        boot_record[7:30] = b'EL TORITO SPECIFICATION'
+        boot_record[71:75] = (sector + 3 if joliet else sector + 2).to_bytes(4, 'little')
+        write(sector, boot_record)
+        sector += 1
+    if joliet:
+        write(sector, descriptor(2, root_record, b'%/E'))
+        sector += 1
+    write(sector, b'\xffCD001\1')
+    sector += 1
+    if boot_catalog is not None:
+        write(sector, boot_catalog)
+
+    # The plain ISO 9660 and the Joliet names point to the same directories, so only one set of
+    # names is written: the Joliet ones if there is a Joliet descriptor (which is read instead).
+    write_directories(joliet)
+    for directory_path, contents in directories:
+        for name in sorted(contents):
+            if not isinstance(contents[name], dict):
+                write(file_extents[directory_path + '/' + name], contents[name])
+
+    with open(path, 'wb') as iso_file:
+        iso_file.write(image)
+    return extents
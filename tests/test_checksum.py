@@ -0,0 +1,111 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+import os
+import tempfile
+import checksum
+
+SHA256 = 'a' * 64
+
+
+class GetExpectedHashTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.sums_file = os.path.join(self.directory.name, 'SHA256SUMS')
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def write_sums(self, text):
+        with open(self.sums_file, mode='w', encoding='utf_8') as sums:
+            sums.write(text)
+
+    def test_gnu_format(self):
+        self.write_sums(('b' * 64) + '  other.iso\n' + SHA256 + '  image.iso\n')
+        self.assertEqual(checksum.get_expected_hash(self.sums_file, '/home/user/image.iso'), SHA256)
+
+    def test_binary_mode(self):
+        self.write_sums(SHA256.upper() + ' *image.iso\r\n')
+        self.assertEqual(checksum.get_expected_hash(self.sums_file, 'image.iso'), SHA256)
+
+    def test_bsd_format(self):
+        self.write_sums('SHA256 (image.iso) = ' + SHA256 + '\n')
+        self.assertEqual(checksum.get_expected_hash(self.sums_file, 'image.iso'), SHA256)
+
+    def test_bsd_format_other_algorithm(self):
+        self.write_sums('SHA3-256 (image.iso) = ' + SHA256 + '\n')
+        with self.assertRaises(checksum.HashNotFoundError):
+            checksum.get_expected_hash(self.sums_file, 'image.iso')
+
+    def test_hash_length(self):
+        # A SHA-512 hash isn't taken for a SHA-256 one.
+        self.write_sums(('c' * 128) + '  image.iso\n')
+        with self.assertRaises(checksum.HashNotFoundError):
+            checksum.get_expected_hash(self.sums_file, 'image.iso')
+        self.assertEqual(checksum.get_expected_hash(self.sums_file, 'image.iso', 'sha512'), 'c' * 128)
+
+
+class FindSumsFileTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.iso = os.path.join(self.directory.name, 'image.iso')
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def write_sums(self, name, text):
+        with open(os.path.join(self.directory.name, name), mode='w', encoding='utf_8') as sums:
+            sums.write(text)
+
+    def test_found(self):
+        self.write_sums('SHA256SUMS', SHA256 + '  image.iso\n')
+        self.assertEqual(checksum.find_sums_file(self.iso), os.path.join(self.directory.name, 'SHA256SUMS'))
+
+    def test_other_names(self):
+        # A sums file that doesn't list the iso file is skipped.
+        self.write_sums('SHA256SUMS', SHA256 + '  other.iso\n')
+        self.write_sums('image.iso.sha256', SHA256 + ' *image.iso\n')
+        self.assertEqual(checksum.find_sums_file(self.iso), os.path.join(self.directory.name, 'image.iso.sha256'))
+
+    def test_not_found(self):
+        self.assertEqual(checksum.find_sums_file(self.iso), '')
+        self.write_sums('SHA256SUMS', SHA256 + '  other.iso\n')
+        self.assertEqual(checksum.find_sums_file(self.iso), '')
+
+
+class GetFileHashTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.path = os.path.join(self.directory.name, 'file')
+        with open(self.path, 'wb') as file:
+            file.write(b'abc')
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def test_default_algorithm(self):
+        self.assertEqual(checksum.DEFAULT_ALGORITHM, 'sha256')
+        self.assertEqual(checksum.get_file_hash(self.path),
+                         'ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad')
+
+    def test_algorithms(self):
+        self.assertEqual(checksum.get_file_hash(self.path, 'md5'), '900150983cd24fb0d6963f7d28e17f72')
+        self.assertEqual(checksum.get_file_hash(self.path, 'sha1'), 'a9993e364706816aba3e25717850c26c9cd0d89d')
+        self.assertEqual(checksum.get_file_hash(self.path, 'sha512'),
+                         'ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a'
+                         '2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f')
@@ -0,0 +1,190 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+from unittest import mock
+import os
+import shutil
+import tempfile
+import dd
+import image
+
+
+class GetSampleOffsetsTest(unittest.TestCase):
+    def test_small_image(self):
+        self.assertEqual(dd.get_sample_offsets(1000, 8, 1048576), [0])
+
+    def test_offsets(self):
+        self.assertEqual(dd.get_sample_offsets(10 * 1048576, 3, 1048576),
+                         [0, 9 * 1048576 // 4, 18 * 1048576 // 4, 27 * 1048576 // 4, 9 * 1048576])
+
+    def test_no_windows(self):
+        # Only the first and the last window.
+        self.assertEqual(dd.get_sample_offsets(4096, 0, 1024), [0, 3072])
+
+
+GIB = 1073741824
+
+
+class FakeDevice:
+    # A counterfeit drive that reports reported_size bytes, but only stores real_size. Writes past
+    # real_size wrap around to the beginning of the drive (if wraps is True) or are lost.
+    def __init__(self, reported_size, real_size, wraps=True):
+        self.blocks = reported_size // dd.CAPACITY_BLOCK_SIZE
+        self.real_blocks = real_size // dd.CAPACITY_BLOCK_SIZE
+        self.wraps = wraps
+        self.data = {}
+
+    def get_real_index(self, index):
+        if index < self.real_blocks:
+            return index
+        return index % self.real_blocks if self.wraps else None
+
+    def read_block(self, index):
+        return self.data.get(self.get_real_index(index), bytes(dd.CAPACITY_BLOCK_SIZE))
+
+    def write_block(self, index, data):
+        real_index = self.get_real_index(index)
+        if real_index is not None:
+            self.data[real_index] = data
+
+
+class ProbeCapacityTest(unittest.TestCase):
+    def probe(self, reported_size, real_size, wraps=True):
+        device = FakeDevice(reported_size, real_size, wraps)
+        return dd.probe_capacity(device.read_block, device.write_block, device.blocks) * dd.CAPACITY_BLOCK_SIZE
+
+    def test_genuine(self):
+        self.assertEqual(self.probe(int(7.5 * GIB), int(7.5 * GIB)), int(7.5 * GIB))
+
+    def test_wrapping(self):
+        for reported_size, real_size in [(int(59.6 * GIB), 8 * GIB), (int(1862.6 * GIB), 16 * GIB),
+                                         (int(62.5 * GIB), 8 * GIB), (32 * GIB, 8 * GIB)]:
+            with self.subTest(reported_size=reported_size, real_size=real_size):
+                self.assertEqual(self.probe(reported_size, real_size), real_size)
+
+    def test_lost_writes(self):
+        for reported_size, real_size in [(int(59.6 * GIB), 8 * GIB), (int(62.5 * GIB), int(7.3 * GIB)),
+                                         (32 * GIB, 1048576)]:
+            with self.subTest(reported_size=reported_size, real_size=real_size):
+                self.assertEqual(self.probe(reported_size, real_size, False),
+                                 real_size // dd.CAPACITY_BLOCK_SIZE * dd.CAPACITY_BLOCK_SIZE)
+
+    def test_nothing_stored(self):
+        self.assertEqual(self.probe(8 * GIB, 0, False), 0)
+
+
+class GetFileCapacityTest(unittest.TestCase):
+    def test_file(self):
+        # A file keeps everything written to it, like a genuine device.
+        with tempfile.TemporaryDirectory() as directory:
+            path = os.path.join(directory, 'device.img')
+            image.create_image(path, 1048576)
+            self.assertEqual(dd.get_file_capacity(path, 1048576), 1048576)
+            self.assertEqual(os.path.getsize(path), 1048576)
+
+
+class DdCheckTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.image_path = os.path.join(self.directory.name, 'image.iso')
+        with open(self.image_path, 'wb') as image_file:
+            image_file.write(bytes(4096))
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def test_insufficient_temp_space(self):
+        # The free space of work_dir is checked before anything is read from the device.
+        work_dir = os.path.join(self.directory.name, 'work')
+        os.mkdir(work_dir)
+        with mock.patch('shutil.disk_usage', return_value=mock.Mock(free=4095)) as disk_usage, \
+                mock.patch('dd.drop_cache', side_effect=AssertionError):
+            with self.assertRaises(dd.InsufficientTempSpaceError) as context:
+                dd.dd_check(self.image_path, 'sdz', work_dir=work_dir)
+        disk_usage.assert_called_once_with(work_dir)
+        self.assertEqual(context.exception.args[0], work_dir)
+        self.assertIsInstance(context.exception, OSError)
+
+
+@unittest.skipUnless(os.geteuid() == 0 and shutil.which('losetup') is not None,
+                     'loop devices can only be set up by root')
+class DdTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.image_path = os.path.join(self.directory.name, 'image.iso')
+        self.data = os.urandom(3 * 1048576 + 512)
+        with open(self.image_path, 'wb') as image_file:
+            image_file.write(self.data)
+        self.target_path = os.path.join(self.directory.name, 'target.img')
+        image.create_image(self.target_path, 8388608)
+        self.device = image.attach_image(self.target_path)
+
+    def tearDown(self):
+        image.detach_image(self.device)
+        self.directory.cleanup()
+
+    def read_device(self):
+        with open('/dev/' + self.device, 'rb') as device_file:
+            return device_file.read(len(self.data))
+
+    def test_file(self):
+        progress = []
+        self.assertEqual(dd.dd(self.image_path, self.device, progress=progress.append), len(self.data))
+        self.assertEqual(progress[-1], len(self.data))
+        self.assertEqual(self.read_device(), self.data)
+
+    def test_stdin(self):
+        # dd reads the image from the stdin it inherits.
+        stdin = os.dup(0)
+        try:
+            with open(self.image_path, 'rb') as image_file:
+                os.dup2(image_file.fileno(), 0)
+                progress = []
+                bytes_written = dd.dd('-', self.device, progress=progress.append)
+        finally:
+            os.dup2(stdin, 0)
+            os.close(stdin)
+        self.assertEqual(bytes_written, len(self.data))
+        self.assertEqual(progress[-1], len(self.data))
+        self.assertEqual(self.read_device(), self.data)
+
+    def test_sampled_check(self):
+        dd.dd(self.image_path, self.device)
+        result = dd.dd_check_sampled(self.image_path, self.device, 2, 1048576)
+        self.assertEqual(result['checked'], [(offset, 1048576) for offset in
+                                             dd.get_sample_offsets(len(self.data), 2, 1048576)])
+        self.assertEqual(result['mismatched'], [])
+
+    def test_sampled_check_corrupted_window(self):
+        dd.dd(self.image_path, self.device)
+        # One byte written in the last window (which starts at 2MiB + 512).
+        with open('/dev/' + self.device, 'r+b') as device_file:
+            device_file.seek(len(self.data) - 1)
+            device_file.write(bytes([self.data[-1] ^ 0xff]))
+        result = dd.dd_check_sampled(self.image_path, self.device, 2, 1048576)
+        self.assertEqual(len(result['checked']), 4)
+        self.assertEqual(result['mismatched'], [(2 * 1048576 + 512, 1048576)])
+
+    def test_image_too_large(self):
+        with open(self.image_path, 'ab') as image_file:
+            image_file.write(bytes(8388608))
+        with self.assertRaises(dd.ImageTooLargeError):
+            dd.dd(self.image_path, self.device)
+
+    def test_check_capacity(self):
+        self.assertEqual(dd.check_capacity(self.device), 8388608)
@@ -0,0 +1,129 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+from unittest import mock
+import os
+import tempfile
+import dependencies
+
+
+class GetDependenciesTest(unittest.TestCase):
+    def setUp(self):
+        # PATH only has a directory with (empty) executables for the commands of every dependency,
+        # except extlinux and mkfs.fat.
+        self.directory = tempfile.TemporaryDirectory()
+        for commands in dependencies.COMMANDS.values():
+            for command in commands:
+                if command not in ['extlinux', 'mkfs.fat']:
+                    path = os.path.join(self.directory.name, command)
+                    open(path, 'w').close()
+                    os.chmod(path, 0o755)
+        patcher = mock.patch.dict('os.environ', {'PATH': self.directory.name})
+        patcher.start()
+        self.addCleanup(patcher.stop)
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def test_dependencies(self):
+        installed = dependencies.get_dependencies()
+        self.assertEqual([dependency for dependency in installed if not installed[dependency]],
+                         ['syslinux', 'mkfs.fat'])
+
+    def test_missing_dependencies(self):
+        # Both missing commands are reported at once.
+        self.assertEqual(dependencies.get_missing_dependencies(dependencies.get_dependencies(), 'fat32', 'both',
+                                                               ['syslinux', 'syslinux']), ['mkfs.fat', 'syslinux'])
+
+    def test_alternative_command(self):
+        os.remove(os.path.join(self.directory.name, 'bootlace.com'))
+        self.assertTrue(dependencies.get_dependencies()['grub4dos'])
+        os.remove(os.path.join(self.directory.name, 'bootlace64.com'))
+        self.assertFalse(dependencies.get_dependencies()['grub4dos'])
+
+
+class GetMissingDependenciesTest(unittest.TestCase):
+    def setUp(self):
+        self.installed = dict.fromkeys(dependencies.COMMANDS, True)
+
+    def get_missing(self, missing, *args):
+        installed = dict(self.installed)
+        for dependency in missing:
+            installed[dependency] = False
+        return dependencies.get_missing_dependencies(installed, *args)
+
+    def test_filesystems(self):
+        for filesystem, mkfs in [('fat32', 'mkfs.fat'), ('fat16', 'mkfs.fat'), ('exfat', 'mkfs.exfat'),
+                                 ('ntfs', 'mkfs.ntfs'), ('udf', 'mkfs.udf'), ('ext4', 'mkfs.ext4'),
+                                 ('btrfs', 'mkfs.btrfs')]:
+            self.assertEqual(self.get_missing([], filesystem), [])
+            self.assertEqual(self.get_missing([mkfs], filesystem), [mkfs])
+        self.assertEqual(self.get_missing([], 'hfs'), ['Unknown filesystem: "hfs"'])
+
+    def test_parted(self):
+        self.assertEqual(self.get_missing(['parted'], 'fat32'), ['parted'])
+
+    def test_uefi_bootloaders(self):
+        for bootloader in ['syslinux', 'grub2', 'systemd-boot']:
+            self.assertEqual(self.get_missing([bootloader], 'fat32', 'uefi', [bootloader, '']), [bootloader])
+            # The BIOS bootloader isn't needed.
+            self.assertEqual(self.get_missing([bootloader], 'fat32', 'bios', [bootloader, 'grub4dos']), [])
+        self.assertEqual(self.get_missing([], 'fat32', 'uefi', ['', '']), ['Unknown bootloader (UEFI)'])
+
+    def test_bios_bootloaders(self):
+        # The SYSLINUX MBR is installed with dd.
+        self.assertEqual(self.get_missing(['dd'], 'fat32', 'bios', ['', 'syslinux']), ['dd'])
+        self.assertEqual(self.get_missing(['grub4dos'], 'fat32', 'bios', ['', 'grub4dos']), ['grub4dos'])
+        self.assertEqual(self.get_missing([], 'fat32', 'bios', ['grub2', '']), ['Unknown bootloader (BIOS)'])
+
+    def test_both_targets(self):
+        # syslinux is only listed once.
+        self.assertEqual(self.get_missing(['syslinux', 'mkfs.fat'], 'fat32', 'both', ['syslinux', 'syslinux']),
+                         ['mkfs.fat', 'syslinux'])
+        self.assertEqual(self.get_missing([], 'fat32', 'both', ['', '']),
+                         ['Unknown bootloader (UEFI)', 'Unknown bootloader (BIOS)'])
+        # No bootloader is installed for the 'none' target.
+        self.assertEqual(self.get_missing(['syslinux'], 'fat32', 'none', ['syslinux', 'syslinux']), [])
+
+
+class GetMissingOptionDependenciesTest(unittest.TestCase):
+    def test_discard(self):
+        installed = dict.fromkeys(dependencies.COMMANDS, True)
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'discard': True}), [])
+        installed['blkdiscard'] = False
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'discard': True}), ['blkdiscard'])
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'discard': False}), [])
+
+    def test_disk_identifier(self):
+        installed = dict.fromkeys(dependencies.COMMANDS, True)
+        installed['sfdisk'] = False
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'disk_identifier': '1234abcd'}),
+                         ['sfdisk'])
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'disk_identifier': ''}), [])
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'deterministic_name': 'image'}),
+                         ['sfdisk'])
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'gpt_attributes': '62'}),
+                         ['sfdisk'])
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'gpt_entries': '256'}),
+                         ['sfdisk'])
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'gpt_entries': '128'}), [])
+
+    def test_archive(self):
+        installed = dict.fromkeys(dependencies.COMMANDS, True)
+        installed['tar'] = False
+        self.assertEqual(dependencies.get_missing_option_dependencies(installed, {'archive': 'data.tar'}), ['tar'])
@@ -0,0 +1,317 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+from unittest import mock
+import os
+import shutil
+import subprocess
+import tempfile
+import formatting
+import image
+import partitioning
+import usb_info
+
+
+class NormalizeLabelTest(unittest.TestCase):
+    def test_fat(self):
+        # Uppercasing isn't a change.
+        self.assertEqual(formatting.normalize_label('usb drive', 'fat32'), ('USB DRIVE', ''))
+        self.assertEqual(formatting.normalize_label('abcdefghijk', 'FAT16'), ('ABCDEFGHIJK', ''))
+        label, message = formatting.normalize_label('abcdefghijkl', 'fat32')
+        self.assertEqual(label, 'ABCDEFGHIJK')
+        self.assertNotEqual(message, '')
+        self.assertEqual(formatting.normalize_label('a.b*c\td', 'fat32')[0], 'ABCD')
+
+    def test_exfat(self):
+        self.assertEqual(formatting.normalize_label('a' * 15, 'exfat'), ('a' * 15, ''))
+        self.assertEqual(formatting.normalize_label('a' * 16, 'exfat')[0], 'a' * 15)
+        # Characters outside the BMP take two UTF-16 code units, and aren't split.
+        self.assertEqual(formatting.normalize_label('a' * 14 + '\U0001F600', 'exfat')[0], 'a' * 14)
+
+    def test_ext4(self):
+        self.assertEqual(formatting.normalize_label('a' * 16, 'ext4'), ('a' * 16, ''))
+        # 16 bytes of UTF-8, without splitting "é" (2 bytes).
+        self.assertEqual(formatting.normalize_label('a' * 15 + 'é', 'ext4')[0], 'a' * 15)
+
+    def test_ntfs(self):
+        self.assertEqual(formatting.normalize_label('a' * 32, 'ntfs'), ('a' * 32, ''))
+        self.assertEqual(formatting.normalize_label('a' * 33, 'ntfs')[0], 'a' * 32)
+
+    def test_btrfs(self):
+        self.assertEqual(formatting.normalize_label('a' * 255, 'btrfs'), ('a' * 255, ''))
+        self.assertEqual(formatting.normalize_label('a' * 256, 'btrfs')[0], 'a' * 255)
+
+    def test_udf(self):
+        self.assertEqual(formatting.normalize_label('a' * 126, 'udf'), ('a' * 126, ''))
+        self.assertEqual(formatting.normalize_label('a' * 127, 'udf')[0], 'a' * 126)
+        self.assertEqual(formatting.normalize_label('ā' * 64, 'udf')[0], 'ā' * 63)
+
+    def test_strict(self):
+        self.assertEqual(formatting.normalize_label('usb', 'fat32', True), ('USB', ''))
+        with self.assertRaises(formatting.InvalidOptionError):
+            formatting.normalize_label('a' * 12, 'fat32', True)
+
+
+class GetFatGeometryArgsTest(unittest.TestCase):
+    def test_defaults(self):
+        self.assertEqual(formatting.get_fat_geometry_args(), [])
+
+    def test_all_options(self):
+        self.assertEqual(formatting.get_fat_geometry_args('1', '2048', '255/63'),
+                         ['-f', '1', '-h', '2048', '-g', '255/63'])
+
+    def test_invalid_options(self):
+        for args in [('5', '-1', ''), ('-1', '4294967296', ''), ('-1', '-1', '256/63'), ('-1', '-1', '255/64'),
+                     ('-1', '-1', '255'), ('-1', 'x', '')]:
+            with self.assertRaises(formatting.InvalidOptionError):
+                formatting.get_fat_geometry_args(*args)
+
+
+class GetMkfsEnvironmentTest(unittest.TestCase):
+    def test_c_utf8(self):
+        for locales in [b'C\nC.utf8\nPOSIX\n', b'C\nC.UTF-8\nPOSIX\n']:
+            with mock.patch('subprocess.check_output', return_value=locales) as check_output:
+                self.assertEqual(formatting.get_mkfs_environment()['LC_ALL'], 'C.UTF-8')
+            check_output.assert_called_once_with(['locale', '-a'])
+
+    def test_no_c_utf8(self):
+        # The user's LC_ALL would override LC_MESSAGES, so it only sets the character set.
+        with mock.patch('subprocess.check_output', return_value=b'C\nPOSIX\nen_US.utf8\n'), \
+                mock.patch.dict('os.environ', {'LC_ALL': 'en_US.UTF-8'}):
+            environment = formatting.get_mkfs_environment()
+        self.assertNotIn('LC_ALL', environment)
+        self.assertEqual(environment['LC_CTYPE'], 'en_US.UTF-8')
+        self.assertEqual(environment['LC_MESSAGES'], 'C')
+        self.assertEqual(environment['LANGUAGE'], 'C')
+
+    def test_no_locale(self):
+        for error in [FileNotFoundError, subprocess.CalledProcessError(1, ['locale', '-a'])]:
+            with mock.patch('subprocess.check_output', side_effect=error), mock.patch.dict('os.environ', clear=True):
+                self.assertEqual(formatting.get_mkfs_environment(), {'LC_MESSAGES': 'C', 'LANGUAGE': 'C'})
+
+
+class GetMkfsTimeoutTest(unittest.TestCase):
+    def test_timeout(self):
+        # 10 seconds more for each GiB, rounded down.
+        for size, timeout in [[16777216, 600], [1073741824, 610], [64000000000, 1190]]:
+            with mock.patch('usb_info.get_partition_size', return_value=size) as get_partition_size:
+                self.assertEqual(formatting.get_mkfs_timeout('sdb', '1'), timeout)
+            get_partition_size.assert_called_once_with('sdb', '1')
+
+
+class RunMkfsTest(unittest.TestCase):
+    def test_non_interactive(self):
+        # mkfs can't wait for an answer, nor run forever.
+        with mock.patch('subprocess.check_output', return_value=b'C.utf8\n'), mock.patch('subprocess.run') as run:
+            formatting.run_mkfs(['mkfs.ext4', '/dev/sdb1'], 1190)
+        self.assertEqual(run.call_args.kwargs['stdin'], subprocess.DEVNULL)
+        self.assertEqual(run.call_args.kwargs['timeout'], 1190)
+        self.assertEqual(run.call_args.kwargs['env']['LC_ALL'], 'C.UTF-8')
+
+        with mock.patch('subprocess.check_output', return_value=b'C.utf8\n'), mock.patch('subprocess.run') as run:
+            formatting.run_mkfs(['mkfs.ext4', '/dev/sdb1'])
+        self.assertEqual(run.call_args.kwargs['timeout'], formatting.MKFS_TIMEOUT)
+
+    def test_partition_size(self):
+        with mock.patch('usb_info.get_partition_size', return_value=1073741824), \
+                mock.patch('formatting.run_mkfs') as run_mkfs:
+            formatting.create_exfat_filesystem('sdb', '1')
+        run_mkfs.assert_called_once_with(['mkfs.exfat', '/dev/sdb1'], 610)
+
+    def test_timeout(self):
+        with mock.patch('subprocess.run', side_effect=subprocess.TimeoutExpired(['mkfs.ext4'], 600)):
+            with self.assertRaises(subprocess.TimeoutExpired):
+                formatting.run_mkfs(['mkfs.ext4', '/dev/sdb1'])
+
+
+class Ext4FeaturesTest(unittest.TestCase):
+    def setUp(self):
+        # The size of the partition is used for mkfs's timeout.
+        patcher = mock.patch('usb_info.get_partition_size', return_value=16777216)
+        patcher.start()
+        self.addCleanup(patcher.stop)
+
+    def test_get_features(self):
+        self.assertEqual(formatting.get_ext4_features(' ^64bit, ^metadata_csum,'), ['^64bit', '^metadata_csum'])
+        self.assertEqual(formatting.get_ext4_features(''), [])
+
+    def test_unknown_features(self):
+        self.assertEqual(formatting.get_unknown_ext4_features(['^64bit', 'casefold', '^unknown', 'typo']),
+                         ['^unknown', 'typo'])
+
+    def test_args(self):
+        # Unknown features are passed to mke2fs too.
+        with mock.patch('formatting.run_mkfs') as run_mkfs:
+            formatting.create_ext4_filesystem('sdb', '1', features=['^64bit', 'unknown'])
+        self.assertEqual(run_mkfs.call_args.args[0], ['mkfs.ext4', '-F', '-O', '^64bit,unknown', '/dev/sdb1'])
+
+
+class CreateFilesystemsErrorsTest(unittest.TestCase):
+    def test_errors(self):
+        # The partition after a failing one is still formatted, and each error is returned.
+        def create_filesystem(device, partition, filesystem, **options):
+            if partition != '2':
+                raise subprocess.CalledProcessError(1, ['mkfs.' + filesystem])
+
+        with mock.patch('formatting.create_filesystem', side_effect=create_filesystem) as create:
+            errors = formatting.create_filesystems('sdb', [{'partition': '1', 'filesystem': 'fat32'},
+                                                           {'partition': '2', 'filesystem': 'ext4', 'label': 'DATA'},
+                                                           {'partition': '3', 'filesystem': 'ext4'}])
+        self.assertEqual(create.call_count, 3)
+        self.assertEqual(create.call_args_list[1], mock.call('sdb', '2', 'ext4', label='DATA'))
+        self.assertEqual([partition for partition, error in errors], ['1', '3'])
+
+
+class CreateFatFilesystemTest(unittest.TestCase):
+    def setUp(self):
+        # The size of the partition is used for mkfs's timeout.
+        patcher = mock.patch('usb_info.get_partition_size', return_value=16777216)
+        patcher.start()
+        self.addCleanup(patcher.stop)
+
+    def test_volume_id(self):
+        for create, fat_type in [[formatting.create_fat32_filesystem, '-F32'],
+                                 [formatting.create_fat16_filesystem, '-F16']]:
+            with mock.patch('formatting.run_mkfs') as run_mkfs:
+                create('sdb', '1', label='usb', volume_id='1234ABCD')
+            self.assertEqual(run_mkfs.call_args.args[0],
+                             ['mkfs.fat', fat_type, '-n', 'USB', '-i', '1234ABCD', '/dev/sdb1'])
+
+    def test_invalid_volume_id(self):
+        for create in [formatting.create_fat32_filesystem, formatting.create_fat16_filesystem]:
+            for volume_id in ['1234', '1234ABCDE', '1234ABCG']:
+                with mock.patch('formatting.run_mkfs') as run_mkfs:
+                    with self.assertRaises(formatting.InvalidOptionError):
+                        create('sdb', '1', volume_id=volume_id)
+                run_mkfs.assert_not_called()
+
+
+class CreateExt4FilesystemTest(unittest.TestCase):
+    def setUp(self):
+        # The size of the partition is used for mkfs's timeout.
+        patcher = mock.patch('usb_info.get_partition_size', return_value=16777216)
+        patcher.start()
+        self.addCleanup(patcher.stop)
+
+    def test_reserved_percentage(self):
+        # 0 is valid (no space reserved for root).
+        with mock.patch('formatting.run_mkfs') as run_mkfs:
+            formatting.create_ext4_filesystem('sdb', '1', reserved_percentage='0')
+        self.assertEqual(run_mkfs.call_args.args[0], ['mkfs.ext4', '-F', '-m', '0', '/dev/sdb1'])
+
+        for reserved_percentage in ['51', '-5', '1.5', '']:
+            with mock.patch('formatting.run_mkfs') as run_mkfs:
+                with self.assertRaises(formatting.InvalidOptionError):
+                    formatting.create_ext4_filesystem('sdb', '1', reserved_percentage=reserved_percentage)
+            run_mkfs.assert_not_called()
+
+    def test_uuid(self):
+        # A fixed UUID makes the partition appear at the same /dev/disk/by-uuid/ path.
+        uuid = '0b0e8f9a-3c4d-4e5f-8a9b-0c1d2e3f4a5b'
+        with mock.patch('formatting.run_mkfs') as run_mkfs:
+            formatting.create_ext4_filesystem('sdb', '1', label='DATA', uuid=uuid)
+        self.assertEqual(run_mkfs.call_args.args[0], ['mkfs.ext4', '-F', '-L', 'DATA', '-U', uuid, '/dev/sdb1'])
+
+        with mock.patch('formatting.run_mkfs') as run_mkfs:
+            with self.assertRaises(formatting.InvalidOptionError):
+                formatting.create_ext4_filesystem('sdb', '1', uuid='not-a-uuid')
+        run_mkfs.assert_not_called()
+
+
+class CheckFilesystemTest(unittest.TestCase):
+    def test_checkers(self):
+        # Each checker only reports errors, without changing the filesystem.
+        checkers = {'fat32': ['fsck.fat', '-n'], 'fat16': ['fsck.fat', '-n'], 'exfat': ['fsck.exfat', '-n'],
+                    'ntfs': ['ntfsfix', '--no-action'], 'ext4': ['e2fsck', '-f', '-n'],
+                    'btrfs': ['btrfs', 'check', '--readonly']}
+        for filesystem, checker in checkers.items():
+            with mock.patch('shutil.which', return_value='/usr/sbin/' + checker[0]), \
+                    mock.patch('subprocess.run', return_value=mock.Mock(returncode=0)) as run:
+                self.assertTrue(formatting.check_filesystem('sdb', '1', filesystem))
+            self.assertEqual(run.call_args.args[0], checker + ['/dev/sdb1'])
+
+    def test_errors(self):
+        with mock.patch('shutil.which', return_value='/usr/sbin/e2fsck'), \
+                mock.patch('subprocess.run', return_value=mock.Mock(returncode=4)):
+            self.assertFalse(formatting.check_filesystem('sdb', '1', 'ext4'))
+
+    def test_not_installed(self):
+        with mock.patch('shutil.which', return_value=None), mock.patch('subprocess.run') as run:
+            self.assertIsNone(formatting.check_filesystem('sdb', '1', 'ext4'))
+        run.assert_not_called()
+
+    def test_no_checker(self):
+        # There is no checker for udf, so it's never reported as failing.
+        with mock.patch('subprocess.run') as run:
+            self.assertTrue(formatting.check_filesystem('sdb', '1', 'udf'))
+        run.assert_not_called()
+
+
+def write_mbr(path, partitions):
+    # Writes an MBR partition table with the partitions, given as (first sector, number of sectors) tuples.
+    mbr = bytearray(512)
+    for i, (start, sectors) in enumerate(partitions):
+        entry = bytes(4) + bytes([0x83]) + bytes(3) + start.to_bytes(4, 'little') + sectors.to_bytes(4, 'little')
+        mbr[446 + i * 16:462 + i * 16] = entry
+    mbr[510:512] = b'\x55\xaa'
+    with open(path, 'r+b') as image_file:
+        image_file.write(mbr)
+
+
+@unittest.skipUnless(os.geteuid() == 0 and shutil.which('losetup') is not None and
+                     shutil.which('mkfs.ext4') is not None and shutil.which('partx') is not None,
+                     'root, losetup, partx and mkfs.ext4 are needed')
+class CreateFilesystemsTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.image_path = os.path.join(self.directory.name, 'image.img')
+        image.create_image(self.image_path, 16777216)
+        write_mbr(self.image_path, [(2048, 8192), (10240, 16384)])
+        self.device = image.attach_image(self.image_path)
+        if not partitioning.wait_for_partition(self.device, '2', 1):
+            # The kernel may be built without MBR support, so the partitions are added by partx.
+            subprocess.run(['partx', '--add', '/dev/' + self.device])
+        self.assertTrue(partitioning.wait_for_partition(self.device, '2'))
+
+    def tearDown(self):
+        image.detach_image(self.device)
+        self.directory.cleanup()
+
+    def get_label(self, partition):
+        return subprocess.check_output(['e2label', '/dev/' + usb_info.get_partition_name(self.device, partition)]) \
+            .decode().strip()
+
+    def test_partitions(self):
+        errors = formatting.create_filesystems(self.device, [
+            {'partition': '1', 'filesystem': 'ext4', 'label': 'BOOT'},
+            {'partition': '2', 'filesystem': 'ext4', 'label': 'DATA', 'reserved_percentage': '0'}])
+        self.assertEqual(errors, [])
+        self.assertEqual(self.get_label('1'), 'BOOT')
+        self.assertEqual(self.get_label('2'), 'DATA')
+
+    def test_errors(self):
+        # Each error is returned with its partition, without stopping the others.
+        # mke2fs fails with an unknown feature.
+        errors = formatting.create_filesystems(self.device, [
+            {'partition': '1', 'filesystem': 'ext4', 'features': ['unknown_feature']},
+            {'partition': '2', 'filesystem': 'ext4', 'reserved_percentage': '90'},
+            {'partition': '2', 'filesystem': 'ext4', 'label': 'DATA'}])
+        self.assertEqual([partition for partition, error in errors], ['1', '2'])
+        self.assertIsInstance(errors[0][1], subprocess.CalledProcessError)
+        self.assertIsInstance(errors[1][1], formatting.InvalidOptionError)
+        self.assertEqual(self.get_label('2'), 'DATA')
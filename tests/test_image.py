@@ -0,0 +1,151 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+from unittest import mock
+import os
+import shutil
+import subprocess
+import tempfile
+import image
+import formatting
+import iso
+import mount
+import partitioning
+import usb_info
+
+
+def can_use_loop_devices():
+    return os.geteuid() == 0 and shutil.which('losetup') is not None
+
+
+def is_attached(path):
+    # losetup --associated lists the loop devices using the file.
+    return subprocess.check_output(['losetup', '--associated', path]).decode().strip() != ''
+
+
+class CreateImageTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.image_path = os.path.join(self.directory.name, 'image.img')
+        with open(self.image_path, 'wb') as image_file:
+            image_file.write(b'data')
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def test_existing_file(self):
+        with self.assertRaises(FileExistsError):
+            image.create_image(self.image_path, 8388608)
+        self.assertEqual(os.path.getsize(self.image_path), 4)
+
+        image.create_image(self.image_path, 8388608, overwrite=True)
+        self.assertEqual(os.path.getsize(self.image_path), 8388608)
+
+    def test_losetup_not_installed(self):
+        # The file isn't truncated when the image can't be built.
+        with mock.patch.dict('os.environ', {'PATH': self.directory.name}):
+            with self.assertRaises(FileNotFoundError):
+                image.build_image(self.image_path, 8388608, lambda device: None, overwrite=True)
+        self.assertEqual(os.path.getsize(self.image_path), 4)
+
+
+@unittest.skipUnless(can_use_loop_devices(), 'loop devices can only be set up by root')
+class BuildImageTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.image_path = os.path.join(self.directory.name, 'image.img')
+        self.source = os.path.join(self.directory.name, 'source')
+        os.makedirs(os.path.join(self.source, 'boot'))
+        with open(os.path.join(self.source, 'boot', 'kernel'), 'wb') as kernel:
+            kernel.write(os.urandom(65536))
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def check_contents(self, partition):
+        # The image is mounted on its own after it was detached, to check the copied files are in it.
+        mountpoint = os.path.join(self.directory.name, 'check')
+        os.mkdir(mountpoint)
+        if partition:
+            device = image.attach_image(self.image_path)
+            subprocess.run(['mount', '/dev/' + usb_info.get_partition_name(device, '1'), mountpoint], check=True)
+        else:
+            device = ''
+            subprocess.run(['mount', '-o', 'loop,ro', self.image_path, mountpoint], check=True)
+        try:
+            self.assertEqual(iso.get_differences(self.source, mountpoint), {'added': [], 'removed': [], 'modified': []})
+        finally:
+            mount.unmount(mountpoint)
+            if device != '':
+                image.detach_image(device)
+
+    def test_size(self):
+        image.build_image(self.image_path, 8388608, lambda device: None)
+        self.assertEqual(os.path.getsize(self.image_path), 8388608)
+        self.assertFalse(is_attached(self.image_path))
+
+    def test_detached_after_error(self):
+        def build(device):
+            self.assertTrue(usb_info.is_connected(device))
+            raise RuntimeError
+
+        with self.assertRaises(RuntimeError):
+            image.build_image(self.image_path, 8388608, build)
+        self.assertFalse(is_attached(self.image_path))
+
+    @unittest.skipUnless(shutil.which('mkfs.ext4') is not None, 'mkfs.ext4 is needed')
+    def test_filesystem_image(self):
+        # A filesystem on the whole loop device, without a partition table.
+        def build(device):
+            formatting.run_mkfs(['mkfs.ext4', '-F', '-q', '/dev/' + device])
+            mountpoint = os.path.join(self.directory.name, 'usb')
+            mount.mount(device, mountpoint, 'ext4')
+            try:
+                iso.copy_iso_contents(self.source, mountpoint)
+            finally:
+                mount.unmount(mountpoint)
+
+        image.build_image(self.image_path, 16777216, build)
+        self.check_contents(False)
+
+    @unittest.skipUnless(shutil.which('parted') is not None and shutil.which('mkfs.fat') is not None,
+                         'parted and mkfs.fat are needed')
+    def test_bootable_image(self):
+        # The same steps as creating a bootable usb drive with the 'iso' method, without the bootloader.
+        def build(device):
+            partitioning.create_partition_table(device, 'msdos')
+            partitioning.create_partition_wrapper(device, 'fat32')
+            partitioning.partprobe(device)
+            self.assertTrue(partitioning.wait_for_partition(device, '1'))
+            formatting.create_filesystem(device, '1', 'fat32', label='TEST')
+            mountpoint = os.path.join(self.directory.name, 'usb')
+            mount.mount(usb_info.get_partition_name(device, '1'), mountpoint, 'fat32')
+            try:
+                iso.copy_iso_contents(self.source, mountpoint)
+            finally:
+                mount.unmount(mountpoint)
+            partitioning.mark_bootable(device, 'msdos')
+            self.assertEqual(len(partitioning.get_bootable_partitions(device)), 1)
+
+        image.build_image(self.image_path, 67108864, build)
+        with open(self.image_path, 'rb') as image_file:
+            mbr = image_file.read(512)
+        self.assertEqual(mbr[510:512], b'\x55\xaa')
+        # The boot flag of the first partition.
+        self.assertEqual(mbr[446], 0x80)
+        self.check_contents(True)
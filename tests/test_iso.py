@@ -0,0 +1,417 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+import unittest.mock
+import os
+import shutil
+import errno
+import tempfile
+import hashlib
+import tarfile
+import types
+import iso
+import version
+
+
+class PathMatchesTest(unittest.TestCase):
+    def test_file(self):
+        self.assertTrue(iso.path_matches('docs/manual.pdf', ['*.pdf']))
+        self.assertFalse(iso.path_matches('docs/manual.txt', ['*.pdf']))
+
+    def test_parent_directory(self):
+        self.assertTrue(iso.path_matches('boot/grub/grub.cfg', ['boot']))
+        self.assertTrue(iso.path_matches('boot/grub/grub.cfg', ['/boot/grub/']))
+        self.assertFalse(iso.path_matches('efi/boot/bootx64.efi', ['boot']))
+
+    def test_case_sensitive(self):
+        self.assertFalse(iso.path_matches('EFI', ['efi']))
+
+    def test_no_patterns(self):
+        self.assertFalse(iso.path_matches('boot', []))
+
+
+class FailingFile:
+    # A file whose reads or writes fail with the given error.
+    def __init__(self, file, method, error):
+        self.file = file
+        self.method = method
+        self.error = error
+
+    def __getattr__(self, name):
+        if name == self.method:
+            raise OSError(self.error, os.strerror(self.error))
+        return getattr(self.file, name)
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *args):
+        self.file.close()
+
+
+class CopyFilteredTreeTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.source = os.path.join(self.directory.name, 'source')
+        self.destination = os.path.join(self.directory.name, 'destination')
+        os.makedirs(os.path.join(self.source, 'boot'))
+        os.mkdir(self.destination)
+        for path in ['boot/damaged.img', 'boot/kernel', 'readme.txt']:
+            with open(os.path.join(self.source, path), 'wb') as file:
+                file.write(path.encode() * 1000)
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def open_failing(self, path, method, error):
+        # Returns a replacement for open, which fails to read or write path.
+        def fake_open(file, *args, **kwargs):
+            opened_file = open(file, *args, **kwargs)
+            if file == path:
+                return FailingFile(opened_file, method, error)
+            return opened_file
+        return unittest.mock.patch('iso.open', fake_open, create=True)
+
+    def test_filters(self):
+        skipped_files = iso.copy_filtered_tree(self.source, self.destination, ['boot'], ['*.img'])
+        self.assertEqual(skipped_files, [])
+        self.assertEqual(sorted(iso.get_file_list(self.destination)), ['boot/kernel'])
+
+    def test_directories(self):
+        # The same directories as with copy_tree, including empty ones, with their modes and times.
+        os.mkdir(os.path.join(self.source, 'empty'), 0o750)
+        os.utime(os.path.join(self.source, 'empty'), (1000000000, 1000000000))
+        os.utime(os.path.join(self.source, 'boot'), (1000000000, 1000000000))
+        copy_tree_destination = os.path.join(self.directory.name, 'copy_tree')
+        iso.copy_iso_contents(self.source, copy_tree_destination, sync=False)
+        iso.copy_iso_contents(self.source, self.destination, sync=False, fsync_files=True)
+
+        def get_tree(path):
+            return sorted((os.path.relpath(root, path), sorted(dirs), sorted(files)) for root, dirs, files in
+                          os.walk(path))
+
+        self.assertEqual(get_tree(self.destination), get_tree(copy_tree_destination))
+        for directory in ['empty', 'boot']:
+            source_stat = os.stat(os.path.join(self.source, directory))
+            destination_stat = os.stat(os.path.join(self.destination, directory))
+            self.assertEqual(destination_stat.st_mode, source_stat.st_mode)
+            self.assertEqual(destination_stat.st_mtime, source_stat.st_mtime)
+
+    def test_filtered_directories(self):
+        # Empty directories are only created if they match include and don't match exclude.
+        os.makedirs(os.path.join(self.source, 'docs/empty'))
+        iso.copy_filtered_tree(self.source, self.destination, ['boot', 'docs'], ['docs/empty'])
+        self.assertTrue(os.path.isdir(os.path.join(self.destination, 'docs')))
+        self.assertFalse(os.path.exists(os.path.join(self.destination, 'docs/empty')))
+        shutil.rmtree(self.destination)
+        iso.copy_filtered_tree(self.source, self.destination, ['boot'])
+        self.assertEqual(os.listdir(self.destination), ['boot'])
+
+    def test_source_read_error(self):
+        with self.open_failing(os.path.join(self.source, 'boot/damaged.img'), 'read', errno.EIO):
+            with self.assertRaises(iso.SourceReadError):
+                iso.copy_filtered_tree(self.source, self.destination)
+
+    def test_source_read_error_best_effort(self):
+        with self.open_failing(os.path.join(self.source, 'boot/damaged.img'), 'read', errno.EIO):
+            skipped_files = iso.copy_filtered_tree(self.source, self.destination, best_effort=True)
+        self.assertEqual(skipped_files, ['boot/damaged.img'])
+        # The partial copy of the damaged file is removed.
+        self.assertEqual(sorted(iso.get_file_list(self.destination)), ['boot/kernel', 'readme.txt'])
+
+    def test_destination_write_error_best_effort(self):
+        with self.open_failing(os.path.join(self.destination, 'boot/kernel'), 'write', errno.ENOSPC):
+            with self.assertRaises(OSError) as context:
+                iso.copy_filtered_tree(self.source, self.destination, best_effort=True)
+        self.assertNotIsInstance(context.exception, iso.SourceReadError)
+        self.assertEqual(context.exception.errno, errno.ENOSPC)
+
+    def test_out_of_inodes_best_effort(self):
+        filesystem_stats = types.SimpleNamespace(f_flag=0, f_files=1000, f_favail=0, f_bavail=1000)
+        with self.open_failing(os.path.join(self.destination, 'boot/kernel'), 'write', errno.ENOSPC), \
+                unittest.mock.patch('os.statvfs', return_value=filesystem_stats):
+            with self.assertRaises(iso.OutOfInodesError):
+                iso.copy_iso_contents(self.source, self.destination, sync=False, best_effort=True)
+
+    def test_disconnected(self):
+        # The filesystem isn't checked for being read-only or out of inodes when the drive was disconnected.
+        for device, error in [('', errno.ENODEV), ('', errno.ENXIO), ('sdzz', errno.EIO)]:
+            with self.subTest(device=device, error=error):
+                with self.open_failing(os.path.join(self.destination, 'boot/kernel'), 'write', error), \
+                        unittest.mock.patch('usb_info.is_connected', return_value=False), \
+                        unittest.mock.patch('os.statvfs', side_effect=AssertionError):
+                    with self.assertRaises(OSError) as context:
+                        iso.copy_iso_contents(self.source, self.destination, sync=False, best_effort=True,
+                                              device=device)
+                self.assertEqual(context.exception.errno, errno.ENODEV)
+
+    def test_read_only(self):
+        filesystem_stats = types.SimpleNamespace(f_flag=os.ST_RDONLY, f_files=0, f_favail=0, f_bavail=0)
+        with self.open_failing(os.path.join(self.destination, 'boot/kernel'), 'write', errno.EROFS), \
+                unittest.mock.patch('usb_info.is_connected', return_value=True), \
+                unittest.mock.patch('os.statvfs', return_value=filesystem_stats):
+            with self.assertRaises(iso.TargetReadOnlyError):
+                iso.copy_iso_contents(self.source, self.destination, sync=False, best_effort=True, device='sdb')
+
+
+class UpdateContentsTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.source = os.path.join(self.directory.name, 'source')
+        self.device_mountpoint = os.path.join(self.directory.name, 'usb')
+        os.makedirs(os.path.join(self.source, 'boot/grub'))
+        os.mkdir(self.device_mountpoint)
+        self.write('boot/grub/grub.cfg', b'menuentry')
+        self.write('boot/kernel', b'kernel')
+        self.write('readme.txt', b'readme')
+        # A symlink to a directory, which is copied as a symlink instead of being walked into.
+        os.symlink('boot/grub', os.path.join(self.source, 'grub'))
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint),
+                         {'added': 4, 'updated': 0, 'deleted': 0, 'unchanged': 0})
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def write(self, path, data):
+        with open(os.path.join(self.source, path), 'wb') as file:
+            file.write(data)
+
+    def read(self, path):
+        with open(os.path.join(self.device_mountpoint, path), 'rb') as file:
+            return file.read()
+
+    def test_copy(self):
+        self.assertEqual(self.read('boot/grub/grub.cfg'), b'menuentry')
+        self.assertEqual(os.readlink(os.path.join(self.device_mountpoint, 'grub')), 'boot/grub')
+
+    def test_unchanged(self):
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint),
+                         {'added': 0, 'updated': 0, 'deleted': 0, 'unchanged': 4})
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint, compare_hashes=True),
+                         {'added': 0, 'updated': 0, 'deleted': 0, 'unchanged': 4})
+
+    def test_changed(self):
+        self.write('boot/kernel', b'new kernel')
+        os.remove(os.path.join(self.source, 'grub'))
+        os.symlink('boot', os.path.join(self.source, 'grub'))
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint),
+                         {'added': 0, 'updated': 2, 'deleted': 0, 'unchanged': 2})
+        self.assertEqual(self.read('boot/kernel'), b'new kernel')
+        self.assertEqual(os.readlink(os.path.join(self.device_mountpoint, 'grub')), 'boot')
+
+    def test_changed_same_size(self):
+        # Only the hashes tell the files apart, since the modification time is kept.
+        stat = os.stat(os.path.join(self.source, 'boot/kernel'))
+        self.write('boot/kernel', b'KERNEL')
+        os.utime(os.path.join(self.source, 'boot/kernel'), ns=(stat.st_atime_ns, stat.st_mtime_ns))
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint, compare_hashes=True),
+                         {'added': 0, 'updated': 1, 'deleted': 0, 'unchanged': 3})
+        self.assertEqual(self.read('boot/kernel'), b'KERNEL')
+
+    def test_type_changes(self):
+        # boot/grub becomes a file, readme.txt a directory, and the symlink a directory.
+        shutil.rmtree(os.path.join(self.source, 'boot/grub'))
+        self.write('boot/grub', b'grub')
+        os.remove(os.path.join(self.source, 'readme.txt'))
+        os.makedirs(os.path.join(self.source, 'readme.txt/en'))
+        self.write('readme.txt/en/readme', b'readme')
+        os.remove(os.path.join(self.source, 'grub'))
+        os.mkdir(os.path.join(self.source, 'grub'))
+        counts = iso.update_contents(self.source, self.device_mountpoint, delete=True)
+        self.assertEqual(counts, {'added': 1, 'updated': 1, 'deleted': 2, 'unchanged': 1})
+        self.assertEqual(self.read('boot/grub'), b'grub')
+        self.assertEqual(self.read('readme.txt/en/readme'), b'readme')
+        self.assertTrue(os.path.isdir(os.path.join(self.device_mountpoint, 'grub')))
+        self.assertFalse(os.path.islink(os.path.join(self.device_mountpoint, 'grub')))
+
+    def test_delete(self):
+        os.remove(os.path.join(self.source, 'grub'))
+        shutil.rmtree(os.path.join(self.source, 'boot'))
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint, delete=True),
+                         {'added': 0, 'updated': 0, 'deleted': 3, 'unchanged': 1})
+        self.assertEqual(sorted(os.listdir(self.device_mountpoint)), ['USBMAKER_FILES.txt', 'readme.txt'])
+        self.assertEqual(iso.read_file_list(self.device_mountpoint), {'readme.txt'})
+
+    def test_delete_only_copied_files(self):
+        # Files added after copying (like the bootloaders and the info file) aren't in the file list,
+        # so they're kept, with the directories they're in.
+        for path in ['USBMAKER_INFO.txt', 'boot/ldlinux.sys', 'syslinux.cfg']:
+            with open(os.path.join(self.device_mountpoint, path), 'wb') as file:
+                file.write(b'added')
+        shutil.rmtree(os.path.join(self.source, 'boot'))
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint, delete=True),
+                         {'added': 0, 'updated': 0, 'deleted': 2, 'unchanged': 2})
+        self.assertEqual(sorted(iso.get_file_list(self.device_mountpoint)),
+                         ['USBMAKER_FILES.txt', 'USBMAKER_INFO.txt', 'boot/ldlinux.sys', 'readme.txt', 'syslinux.cfg'])
+
+    def test_without_file_list(self):
+        # Nothing is deleted from a drive without a file list, since it's unknown what was copied.
+        os.remove(os.path.join(self.device_mountpoint, 'USBMAKER_FILES.txt'))
+        os.remove(os.path.join(self.source, 'readme.txt'))
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint, delete=True),
+                         {'added': 0, 'updated': 0, 'deleted': 0, 'unchanged': 3})
+        self.assertTrue(os.path.isfile(os.path.join(self.device_mountpoint, 'readme.txt')))
+
+    def test_kept_files_stay_in_the_list(self):
+        os.remove(os.path.join(self.source, 'readme.txt'))
+        iso.update_contents(self.source, self.device_mountpoint)
+        self.assertIn('readme.txt', iso.read_file_list(self.device_mountpoint))
+        self.assertEqual(iso.update_contents(self.source, self.device_mountpoint, delete=True)['deleted'], 1)
+
+
+class GetDifferencesTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.source = os.path.join(self.directory.name, 'source')
+        self.device_mountpoint = os.path.join(self.directory.name, 'usb')
+        for directory in [self.source, self.device_mountpoint]:
+            os.makedirs(os.path.join(directory, 'boot'))
+            self.write(directory, 'boot/kernel', b'kernel')
+            self.write(directory, 'readme.txt', b'readme')
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def write(self, directory, path, data):
+        with open(os.path.join(directory, path), 'wb') as file:
+            file.write(data)
+
+    def test_no_differences(self):
+        self.assertEqual(iso.get_differences(self.source, self.device_mountpoint),
+                         {'added': [], 'removed': [], 'modified': []})
+
+    def test_differences(self):
+        self.write(self.source, 'boot/initrd', b'initrd')
+        self.write(self.device_mountpoint, 'ldlinux.sys', b'syslinux')
+        # The same size, but different contents.
+        self.write(self.device_mountpoint, 'boot/kernel', b'KERNEL')
+        self.write(self.device_mountpoint, 'readme.txt', b'readme, longer')
+        self.assertEqual(iso.get_differences(self.source, self.device_mountpoint),
+                         {'added': ['boot/initrd'], 'removed': ['ldlinux.sys'], 'modified': ['boot/kernel',
+                                                                                            'readme.txt']})
+
+
+class GetRequiredSizeTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.iso_path = os.path.join(self.directory.name, 'image.iso')
+        with open(self.iso_path, 'wb') as iso_file:
+            iso_file.write(b'\0' * 2000)
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def test_dd(self):
+        # The image is written as it is.
+        self.assertEqual(iso.get_required_size(self.iso_path, 'dd'), 2000)
+
+    def test_iso(self):
+        # 1MiB before the partition, plus 5% for the filesystem.
+        self.assertEqual(iso.get_required_size(self.iso_path), 1048576 + 2100)
+
+    def test_extracted(self):
+        # Symbolic links aren't counted, since their targets are already counted.
+        contents = os.path.join(self.directory.name, 'contents')
+        os.makedirs(os.path.join(contents, 'boot'))
+        for path, size in [['boot/kernel', 1500], ['readme.txt', 500]]:
+            with open(os.path.join(contents, path), 'wb') as file:
+                file.write(b'\0' * size)
+        os.symlink('boot/kernel', os.path.join(contents, 'kernel'))
+        self.assertEqual(iso.get_required_size(contents), 1048576 + 2100)
+
+
+class WriteFileListTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        os.makedirs(os.path.join(self.directory.name, 'boot/grub'))
+        open(os.path.join(self.directory.name, 'boot/kernel'), 'w').close()
+        os.symlink('boot', os.path.join(self.directory.name, 'link'))
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def test_file_list(self):
+        iso.write_file_list(self.directory.name)
+        self.assertEqual(iso.read_file_list(self.directory.name), {'boot/', 'boot/grub/', 'boot/kernel', 'link'})
+        # A second list doesn't include the first one.
+        iso.write_file_list(self.directory.name)
+        self.assertEqual(iso.read_file_list(self.directory.name), {'boot/', 'boot/grub/', 'boot/kernel', 'link'})
+
+    def test_no_file_list(self):
+        self.assertEqual(iso.read_file_list(self.directory.name), set())
+
+
+class WriteInfoFileTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.iso_path = os.path.join(self.directory.name, 'image.iso')
+        with open(self.iso_path, 'wb') as iso_file:
+            iso_file.write(b'iso contents')
+        self.device_mountpoint = os.path.join(self.directory.name, 'usb')
+        os.mkdir(self.device_mountpoint)
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def read_info_file(self, filename='USBMAKER_INFO.txt'):
+        with open(os.path.join(self.device_mountpoint, filename), mode='r', encoding='utf_8') as info_file:
+            return dict(line.rstrip('\n').split(': ', 1) for line in info_file)
+
+    def test_contents(self):
+        iso.write_info_file(self.device_mountpoint, self.iso_path)
+        info = self.read_info_file()
+        self.assertEqual(info['Source'], 'image.iso')
+        self.assertEqual(info['SHA256'], hashlib.sha256(b'iso contents').hexdigest())
+        self.assertEqual(info['USBMaker version'], version.VERSION)
+        self.assertRegex(info['Created'], '^[0-9]{4}-[0-9]{2}-[0-9]{2} [0-9]{2}:[0-9]{2}:[0-9]{2} [+-][0-9]{4}$')
+
+    def test_options(self):
+        iso.write_info_file(self.device_mountpoint, self.iso_path, 'info.txt', {'Owner': 'IT'}, 'md5')
+        info = self.read_info_file('info.txt')
+        self.assertEqual(info['MD5'], hashlib.md5(b'iso contents').hexdigest())
+        self.assertEqual(info['Owner'], 'IT')
+
+
+@unittest.skipUnless(shutil.which('tar') is not None, 'tar is needed')
+class ExtractArchiveTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.device_mountpoint = os.path.join(self.directory.name, 'usb')
+        os.mkdir(self.device_mountpoint)
+        self.archive = os.path.join(self.directory.name, 'data.tar.gz')
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def test_extract(self):
+        source = os.path.join(self.directory.name, 'data')
+        os.makedirs(os.path.join(source, 'docs'))
+        with open(os.path.join(source, 'docs', 'readme.txt'), 'w') as readme:
+            readme.write('readme')
+        with tarfile.open(self.archive, 'w:gz') as archive:
+            archive.add(os.path.join(source, 'docs'), 'docs')
+
+        iso.extract_archive(self.archive, self.device_mountpoint)
+        with open(os.path.join(self.device_mountpoint, 'docs', 'readme.txt')) as readme:
+            self.assertEqual(readme.read(), 'readme')
+
+    def test_corrupted(self):
+        with open(self.archive, 'wb') as archive:
+            archive.write(b'not an archive')
+        with self.assertRaises(iso.ExtractionError):
+            iso.extract_archive(self.archive, self.device_mountpoint)
@@ -0,0 +1,88 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+import os
+import tempfile
+import iso9660
+from tests import iso_builder
+
+# An El Torito validation entry, a default entry and a final section header for EFI.
+EFI_BOOT_CATALOG = bytes([1, 0]) + bytes(30) + bytes([0x88]) + bytes(31) + bytes([0x91, 0xEF]) + bytes(30)
+
+
+class Iso9660Test(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.iso_path = os.path.join(self.directory.name, 'image.iso')
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def test_volume_info(self):
+        iso_builder.build_iso(self.iso_path, 'TEST_LABEL', {'file': b'data'})
+        info = iso9660.get_volume_info(self.iso_path)
+        self.assertEqual(info['label'], 'TEST_LABEL')
+        self.assertEqual(info['size'], os.path.getsize(self.iso_path))
+        self.assertFalse(info['bootable'])
+        self.assertFalse(info['efi'])
+
+    def test_boot_record(self):
+        iso_builder.build_iso(self.iso_path, boot_catalog=EFI_BOOT_CATALOG)
+        info = iso9660.get_volume_info(self.iso_path)
+        self.assertTrue(info['bootable'])
+        self.assertTrue(info['efi'])
+
+    def test_not_an_iso_file(self):
+        with open(self.iso_path, 'wb') as iso_file:
+            iso_file.write(bytes(65536))
+        with self.assertRaises(iso9660.InvalidIsoError):
+            iso9660.get_volume_info(self.iso_path)
+        with open(self.iso_path, 'rb') as iso_file:
+            with self.assertRaises(iso9660.InvalidIsoError):
+                iso9660.get_root_directory_record(iso_file)
+
+    def test_list_contents(self):
+        iso_builder.build_iso(self.iso_path, files={'readme': b'text', 'boot': {'grub.cfg': b'x' * 3000}})
+        self.assertEqual(iso9660.list_contents(self.iso_path),
+                         [{'path': '/BOOT', 'size': 0, 'type': 'directory'},
+                          {'path': '/README', 'size': 4, 'type': 'file'},
+                          {'path': '/BOOT/GRUB.CFG', 'size': 3000, 'type': 'file'}])
+
+    def test_list_contents_joliet(self):
+        iso_builder.build_iso(self.iso_path, files={'Long file name.txt': b'', 'EFI': {'boot': {}}}, joliet=True)
+        self.assertEqual(iso9660.list_contents(self.iso_path),
+                         [{'path': '/EFI', 'size': 0, 'type': 'directory'},
+                          {'path': '/Long file name.txt', 'size': 0, 'type': 'file'},
+                          {'path': '/EFI/boot', 'size': 0, 'type': 'directory'}])
+
+    def test_list_contents_loop(self):
+        extents = iso_builder.build_iso(self.iso_path, files={'EFI': {'boot': {}}})
+        # The record of /EFI/BOOT (after the ones of /EFI itself and of the root) is changed to
+        # point to the root, so walking the directories would never end.
+        with open(self.iso_path, 'r+b') as iso_file:
+            iso_file.seek(extents['/EFI'] * iso_builder.SECTOR_SIZE + 68 + 2)
+            iso_file.write(iso_builder.both_endian(extents[''], 4))
+        with self.assertRaises(iso9660.InvalidIsoError):
+            iso9660.list_contents(self.iso_path)
+
+    def test_has_efi_boot_entry(self):
+        self.assertTrue(iso9660.has_efi_boot_entry(EFI_BOOT_CATALOG))
+        self.assertTrue(iso9660.has_efi_boot_entry(bytes([1, 0xEF]) + bytes(30)))
+        # Only BIOS entries.
+        self.assertFalse(iso9660.has_efi_boot_entry(bytes([1, 0]) + bytes(62)))
+        self.assertFalse(iso9660.has_efi_boot_entry(b''))
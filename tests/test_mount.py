@@ -0,0 +1,153 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+import unittest.mock
+import os
+import tempfile
+import mount
+
+
+class GetTempPathTest(unittest.TestCase):
+    def test_default_parent(self):
+        with unittest.mock.patch('os.getpid', return_value=1234):
+            self.assertEqual(mount.get_temp_path('usb'), os.path.join(tempfile.gettempdir(), 'usbmaker1234-usb'))
+
+    def test_parent(self):
+        with tempfile.TemporaryDirectory() as directory, unittest.mock.patch('os.getpid', return_value=1234):
+            self.assertEqual(mount.get_temp_path('iso', directory, 'test'), os.path.join(directory, 'test1234-iso'))
+
+    def test_invalid_parent(self):
+        with tempfile.TemporaryDirectory() as directory:
+            with self.assertRaises(mount.TempDirError):
+                mount.get_temp_path('usb', os.path.join(directory, 'missing'))
+
+            with open(os.path.join(directory, 'file'), mode='w') as file:
+                file.write('')
+            with self.assertRaises(mount.TempDirError):
+                mount.get_temp_path('usb', os.path.join(directory, 'file'))
+
+            # Not writable.
+            with unittest.mock.patch('os.access', return_value=False):
+                with self.assertRaises(mount.TempDirError):
+                    mount.get_temp_path('usb', directory)
+
+
+class GetDefaultMountOptionsTest(unittest.TestCase):
+    def test_fat(self):
+        self.assertEqual(mount.get_default_mount_options('FAT32'), 'utf8,shortname=mixed')
+
+    def test_exfat_kernel(self):
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=True):
+            self.assertEqual(mount.get_default_mount_options('exFAT'), 'iocharset=utf8')
+
+    def test_exfat_fuse(self):
+        # exfat-fuse fails to mount with iocharset.
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=False):
+            self.assertEqual(mount.get_default_mount_options('exFAT'), '')
+
+    def test_other(self):
+        self.assertEqual(mount.get_default_mount_options('ext4'), '')
+
+
+class CanMountTest(unittest.TestCase):
+    def test_kernel(self):
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=True) as is_kernel_filesystem:
+            self.assertTrue(mount.can_mount('FAT32'))
+            is_kernel_filesystem.assert_called_with('vfat')
+            self.assertTrue(mount.can_mount('ext4'))
+            is_kernel_filesystem.assert_called_with('ext4')
+
+    def test_exfat_fuse(self):
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=False), \
+                unittest.mock.patch('shutil.which', return_value='/sbin/mount.exfat-fuse'):
+            self.assertTrue(mount.can_mount('exFAT'))
+
+    def test_unsupported(self):
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=False), \
+                unittest.mock.patch('shutil.which', return_value=None):
+            for filesystem in ['FAT16', 'exFAT', 'NTFS', 'Btrfs']:
+                self.assertFalse(mount.can_mount(filesystem))
+
+
+class GetNtfsDriverTest(unittest.TestCase):
+    def test_ntfs3(self):
+        # ntfs3 is preferred, even if ntfs-3g is installed.
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=True) as is_kernel_filesystem, \
+                unittest.mock.patch('shutil.which', return_value='/bin/ntfs-3g'):
+            self.assertEqual(mount.get_ntfs_driver(), 'ntfs3')
+        is_kernel_filesystem.assert_called_once_with('ntfs3')
+
+    def test_ntfs_3g(self):
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=False), \
+                unittest.mock.patch('shutil.which', return_value='/bin/ntfs-3g'):
+            self.assertEqual(mount.get_ntfs_driver(), 'ntfs-3g')
+
+    def test_not_found(self):
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=False), \
+                unittest.mock.patch('shutil.which', return_value=None):
+            with self.assertRaises(mount.NtfsDriverNotFoundError):
+                mount.get_ntfs_driver()
+
+
+class MountTest(unittest.TestCase):
+    def test_options(self):
+        with unittest.mock.patch('mount.is_kernel_filesystem', return_value=False), \
+                unittest.mock.patch('os.makedirs'), unittest.mock.patch('subprocess.run') as run:
+            mount.mount('sdb1', '/mnt/usb', 'exfat', 'noatime')
+        run.assert_called_once_with(['mount', '-o', 'noatime', '/dev/sdb1', '/mnt/usb'])
+
+    def test_ntfs_driver(self):
+        with unittest.mock.patch('mount.get_ntfs_driver', return_value='ntfs-3g'), \
+                unittest.mock.patch('os.makedirs'), unittest.mock.patch('subprocess.run') as run:
+            mount.mount('sdb1', '/mnt/usb', 'NTFS')
+            mount.mount('sdb1', '/mnt/usb', 'NTFS', ntfs_driver='ntfs3')
+        self.assertEqual(run.call_args_list, [unittest.mock.call(['mount', '-t', 'ntfs-3g', '/dev/sdb1', '/mnt/usb']),
+                                              unittest.mock.call(['mount', '-t', 'ntfs3', '/dev/sdb1', '/mnt/usb'])])
+
+
+MOUNTS = '''sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0
+/dev/sda2 / ext4 rw,relatime 0 0
+/dev/sdb1 /media/user/USB vfat rw,nosuid,nodev 0 0
+/dev/sdb1 /mnt vfat rw,nosuid,nodev 0 0
+/dev/sdb3 /media/user/DATA ext4 rw,nosuid,nodev 0 0
+'''
+
+
+class GetMountedPartitionsTest(unittest.TestCase):
+    def test_partitions(self):
+        # A partition mounted twice is only listed once, and the partitions of other devices are ignored.
+        with unittest.mock.patch('builtins.open', unittest.mock.mock_open(read_data=MOUNTS)), \
+                unittest.mock.patch('usb_info.get_partitions', return_value=['sdb1', 'sdb2', 'sdb3']):
+            self.assertEqual(mount.get_mounted_partitions('sdb'), ['sdb1', 'sdb3'])
+
+
+class EjectTest(unittest.TestCase):
+    def test_eject(self):
+        with unittest.mock.patch('mount.get_mounted_partitions', return_value=['sdb1', 'sdb3']), \
+                unittest.mock.patch('mount.unmount_partition') as unmount_partition, \
+                unittest.mock.patch('os.sync'), unittest.mock.patch('subprocess.run') as run:
+            self.assertEqual(mount.eject('sdb'), ['sdb1', 'sdb3'])
+        self.assertEqual(unmount_partition.call_args_list, [unittest.mock.call('sdb1'), unittest.mock.call('sdb3')])
+        run.assert_called_once_with(['eject', '/dev/sdb'])
+
+    def test_power_off(self):
+        # udisksctl is used if eject isn't installed.
+        with unittest.mock.patch('mount.get_mounted_partitions', return_value=[]), unittest.mock.patch('os.sync'), \
+                unittest.mock.patch('subprocess.run', side_effect=[FileNotFoundError, None]) as run:
+            self.assertEqual(mount.eject('sdb'), [])
+        self.assertEqual(run.call_args.args[0], ['udisksctl', 'power-off', '--block-device', '/dev/sdb'])
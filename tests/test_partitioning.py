@@ -0,0 +1,278 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+from unittest import mock
+import os
+import shutil
+import subprocess
+import tempfile
+import image
+import partitioning
+
+
+class GetPartitionEndTest(unittest.TestCase):
+    def test_reserved_space(self):
+        with mock.patch('usb_info.get_size', return_value=8 * 1048576):
+            self.assertEqual(partitioning.get_partition_end('sdb', 2 * 1048576), '6MiB')
+
+    def test_rounded_down(self):
+        with mock.patch('usb_info.get_size', return_value=8 * 1048576):
+            self.assertEqual(partitioning.get_partition_end('sdb', 2 * 1048576 + 1), '5MiB')
+
+    def test_smallest_partition(self):
+        # The partition starts at 1MiB, so an end at 2MiB leaves a 1MiB partition.
+        with mock.patch('usb_info.get_size', return_value=8 * 1048576):
+            self.assertEqual(partitioning.get_partition_end('sdb', 6 * 1048576), '2MiB')
+
+    def test_insufficient_space(self):
+        with mock.patch('usb_info.get_size', return_value=8 * 1048576):
+            with self.assertRaises(partitioning.InsufficientSpaceError):
+                partitioning.get_partition_end('sdb', 6 * 1048576 + 1)
+            with self.assertRaises(partitioning.InsufficientSpaceError):
+                partitioning.get_partition_end('sdb', -1)
+
+
+class GetDeterministicGuidTest(unittest.TestCase):
+    def test_same_name(self):
+        self.assertEqual(partitioning.get_deterministic_guid('image-partition1'),
+                         partitioning.get_deterministic_guid('image-partition1'))
+
+    def test_different_names(self):
+        self.assertNotEqual(partitioning.get_deterministic_guid('image-partition1'),
+                            partitioning.get_deterministic_guid('image-partition2'))
+
+    def test_format(self):
+        self.assertRegex(partitioning.get_deterministic_guid('image'),
+                         '^[0-9a-f]{8}-([0-9a-f]{4}-){3}[0-9a-f]{12}$')
+
+
+class DiscardDeviceTest(unittest.TestCase):
+    def test_supported(self):
+        with mock.patch('usb_info.supports_discard', return_value=True), mock.patch('subprocess.run') as run:
+            self.assertTrue(partitioning.discard_device('sdb'))
+        run.assert_called_once_with(['blkdiscard', '/dev/sdb'])
+
+    def test_unsupported(self):
+        with mock.patch('usb_info.supports_discard', return_value=False), mock.patch('subprocess.run') as run:
+            self.assertFalse(partitioning.discard_device('sdb'))
+        run.assert_not_called()
+
+
+class WaitForPartitionTest(unittest.TestCase):
+    def test_appears(self):
+        with mock.patch('os.path.exists', side_effect=[False, False, True]) as exists, \
+                mock.patch('time.sleep') as sleep:
+            self.assertTrue(partitioning.wait_for_partition('nvme0n1', '1'))
+        exists.assert_called_with('/dev/nvme0n1p1')
+        # The delay doubles each time.
+        self.assertEqual(sleep.call_args_list, [mock.call(0.1), mock.call(0.2)])
+
+    def test_timeout(self):
+        with mock.patch('os.path.exists', return_value=False), mock.patch('time.sleep'), \
+                mock.patch('time.monotonic', side_effect=[0, 0, 5, 11]):
+            self.assertFalse(partitioning.wait_for_partition('sdb', '1', 10))
+
+
+class RescanDeviceTest(unittest.TestCase):
+    def test_rescan(self):
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            os.makedirs(os.path.join(directory, 'sdb', 'device'))
+            with open(os.path.join(directory, 'sdb', 'device', 'rescan'), mode='w') as rescan_file:
+                rescan_file.write('')
+
+            self.assertTrue(partitioning.rescan_device('sdb'))
+            with open(os.path.join(directory, 'sdb', 'device', 'rescan'), mode='r') as rescan_file:
+                self.assertEqual(rescan_file.read(), '1')
+
+    def test_not_rescannable(self):
+        # Devices that aren't SCSI devices (for example, loop devices) don't have a rescan file.
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            os.makedirs(os.path.join(directory, 'loop0'))
+            self.assertFalse(partitioning.rescan_device('loop0'))
+
+
+class PartprobeTest(unittest.TestCase):
+    def test_partprobe(self):
+        with mock.patch('subprocess.run') as run:
+            partitioning.partprobe('sdb')
+        self.assertEqual(run.call_args_list, [mock.call(['partprobe', '/dev/sdb']), mock.call(['udevadm', 'settle'])])
+
+    def test_not_installed(self):
+        # udevadm settle is still run when partprobe isn't installed.
+        with mock.patch('subprocess.run', side_effect=FileNotFoundError) as run:
+            partitioning.partprobe('sdb')
+        self.assertEqual(run.call_count, 2)
+
+
+class GetDefaultPartitionTableTest(unittest.TestCase):
+    def test_uefi(self):
+        with tempfile.TemporaryDirectory() as directory:
+            os.makedirs(os.path.join(directory, 'firmware', 'efi'))
+            self.assertEqual(partitioning.get_default_partition_table(directory), 'gpt')
+
+    def test_bios(self):
+        with tempfile.TemporaryDirectory() as directory:
+            os.makedirs(os.path.join(directory, 'firmware'))
+            self.assertEqual(partitioning.get_default_partition_table(directory), 'msdos')
+
+
+class CreatePartitionTableTest(unittest.TestCase):
+    def test_default_entries(self):
+        with mock.patch('subprocess.run') as run:
+            partitioning.create_partition_table('sdb', 'gpt')
+        run.assert_called_once_with(['parted', '-s', '/dev/sdb', 'mktable', 'gpt'])
+
+    def test_entries(self):
+        # parted always creates 128 entries, so sfdisk is used for the others.
+        with mock.patch('subprocess.run') as run:
+            partitioning.create_partition_table('sdb', 'gpt', 256)
+        self.assertEqual(run.call_args.args[0], ['sfdisk', '/dev/sdb'])
+        self.assertEqual(run.call_args.kwargs['input'], b'label: gpt\ntable-length: 256\n')
+
+    def test_invalid_entries(self):
+        # Fewer than 128, more than fit before 1MiB, or not filling whole sectors.
+        for gpt_entries in [124, 8132, 130]:
+            with mock.patch('subprocess.run') as run:
+                with self.assertRaises(partitioning.InvalidPartitionTableError):
+                    partitioning.create_partition_table('sdb', 'gpt', gpt_entries)
+            run.assert_not_called()
+        self.assertTrue(partitioning.is_valid_gpt_entries(128))
+        self.assertTrue(partitioning.is_valid_gpt_entries(8128))
+
+
+class SetDiskIdentifierTest(unittest.TestCase):
+    def test_mbr(self):
+        for identifier in ['1234abcd', '0x1234abcd']:
+            with mock.patch('subprocess.run') as run:
+                partitioning.set_disk_identifier('sdb', 'msdos', identifier)
+            run.assert_called_once_with(['sfdisk', '--disk-id', '/dev/sdb', '0x1234abcd'])
+
+    def test_gpt(self):
+        guid = '0b0e8f9a-3c4d-4e5f-8a9b-0c1d2e3f4a5b'
+        with mock.patch('subprocess.run') as run:
+            partitioning.set_disk_identifier('sdb', 'gpt', guid)
+        run.assert_called_once_with(['sfdisk', '--disk-id', '/dev/sdb', guid])
+
+    def test_invalid(self):
+        for table, identifier in [['msdos', '1234abc'], ['msdos', '1234abcg'], ['msdos', '12345678-1234'],
+                                  ['gpt', '1234abcd'], ['gpt', '0b0e8f9a-3c4d-4e5f-8a9b-0c1d2e3f4a5'],
+                                  ['loop', '1234abcd']]:
+            with mock.patch('subprocess.run') as run:
+                with self.assertRaises(partitioning.InvalidDiskIdentifierError):
+                    partitioning.set_disk_identifier('sdb', table, identifier)
+            run.assert_not_called()
+
+
+class SetPartitionAttributesTest(unittest.TestCase):
+    def test_attributes(self):
+        with mock.patch('subprocess.run') as run:
+            partitioning.set_partition_attributes('sdb', '1', partitioning.get_partition_attributes('0, 2,62,63'))
+        run.assert_called_once_with(['sfdisk', '--part-attrs', '/dev/sdb', '1',
+                                     'RequiredPartition,LegacyBIOSBootable,GUID:62,63'])
+
+    def test_invalid(self):
+        for text in ['3', '47', '64', 'hidden', '-1']:
+            with mock.patch('subprocess.run') as run:
+                with self.assertRaises(partitioning.InvalidPartitionAttributeError):
+                    partitioning.set_partition_attributes('sdb', '1', partitioning.get_partition_attributes(text))
+            run.assert_not_called()
+
+
+class CheckAlignmentTest(unittest.TestCase):
+    def test_aligned(self):
+        with mock.patch('subprocess.run', return_value=mock.Mock(returncode=0)) as run:
+            self.assertTrue(partitioning.check_alignment('sdb', '1'))
+        self.assertEqual(run.call_args.args[0], ['parted', '-s', '/dev/sdb', 'align-check', 'optimal', '1'])
+
+    def test_misaligned(self):
+        # parted exits with 1 if the partition isn't aligned.
+        with mock.patch('subprocess.run', return_value=mock.Mock(returncode=1)) as run:
+            self.assertFalse(partitioning.check_alignment('sdb', '2', 'minimal'))
+        self.assertEqual(run.call_args.args[0], ['parted', '-s', '/dev/sdb', 'align-check', 'minimal', '2'])
+
+
+@unittest.skipUnless(os.geteuid() == 0 and shutil.which('losetup') is not None,
+                     'loop devices can only be set up by root')
+class WipeDeviceTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.image_path = os.path.join(self.directory.name, 'image.img')
+        image.create_image(self.image_path, 8388608)
+        with open(self.image_path, 'r+b') as image_file:
+            image_file.write(b'\xff' * 8388608)
+        self.device = image.attach_image(self.image_path)
+
+    def tearDown(self):
+        image.detach_image(self.device)
+        self.directory.cleanup()
+
+    def get_wipefs_calls(self, use_wipefs):
+        orig_run = subprocess.run
+        with mock.patch('subprocess.run', side_effect=orig_run) as run:
+            partitioning.wipe_device(self.device, use_wipefs)
+        return [call for call in run.call_args_list if call.args[0][0] == 'wipefs']
+
+    def check_wiped(self):
+        with open(self.image_path, 'rb') as image_file:
+            data = image_file.read()
+        self.assertEqual(data[:1048576], bytes(1048576))
+        self.assertEqual(data[-1048576:], bytes(1048576))
+        self.assertEqual(data[1048576:-1048576], b'\xff' * (8388608 - 2 * 1048576))
+
+    @unittest.skipUnless(shutil.which('wipefs') is not None, 'wipefs is needed')
+    def test_wipefs(self):
+        calls = self.get_wipefs_calls(True)
+        self.assertEqual(calls[-1].args[0][-1], '/dev/' + self.device)
+        self.assertEqual(calls[-1].kwargs['timeout'], 60)
+        self.check_wiped()
+
+    def test_skip_wipefs(self):
+        self.assertEqual(self.get_wipefs_calls(False), [])
+        self.check_wiped()
+
+
+@unittest.skipUnless(os.geteuid() == 0 and shutil.which('losetup') is not None and
+                     shutil.which('parted') is not None and shutil.which('sfdisk') is not None,
+                     'root, losetup, parted and sfdisk are needed')
+class DeterministicPartitionTableTest(unittest.TestCase):
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    def build(self, name):
+        # Creates a disk image with a GPT partition table with GUIDs derived from "image",
+        # and returns the data of the primary and backup tables.
+        path = os.path.join(self.directory.name, name)
+        image.create_image(path, 16777216)
+        device = image.attach_image(path)
+        try:
+            partitioning.create_partition_table(device, 'gpt')
+            partitioning.set_disk_identifier(device, 'gpt', partitioning.get_deterministic_guid('image'))
+            partitioning.create_partition(device, 'fat32')
+            partitioning.set_partition_guid(device, '1', partitioning.get_deterministic_guid('image-partition1'))
+        finally:
+            image.detach_image(device)
+
+        with open(path, 'rb') as image_file:
+            data = image_file.read()
+        return data[:1048576] + data[-1048576:]
+
+    def test_identical(self):
+        self.assertEqual(self.build('first.img'), self.build('second.img'))
@@ -0,0 +1,178 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+from unittest import mock
+import os
+import tempfile
+import usb_info
+
+
+class GetPartitionNameTest(unittest.TestCase):
+    def test_appended(self):
+        self.assertEqual(usb_info.get_partition_name('sdb', '1'), 'sdb1')
+
+    def test_separated(self):
+        self.assertEqual(usb_info.get_partition_name('nvme0n1', '2'), 'nvme0n1p2')
+        self.assertEqual(usb_info.get_partition_name('mmcblk0', '1'), 'mmcblk0p1')
+        self.assertEqual(usb_info.get_partition_name('loop0', '1'), 'loop0p1')
+
+
+def write_sys_file(directory, path, value):
+    # Creates a file in a directory that stands in for /sys/block.
+    os.makedirs(os.path.dirname(os.path.join(directory, path)), exist_ok=True)
+    with open(os.path.join(directory, path), mode='w') as sys_file:
+        sys_file.write(value + '\n')
+
+
+class GetSizeTest(unittest.TestCase):
+    def test_size(self):
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/size', '30310400')
+            write_sys_file(directory, 'sdb/queue/hw_sector_size', '512')
+            self.assertEqual(usb_info.get_size('sdb'), 15518924800)
+
+    def test_4kn(self):
+        # The size is still counted in 512 byte sectors on devices with 4096 byte sectors.
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/size', '30310400')
+            write_sys_file(directory, 'sdb/queue/hw_sector_size', '4096')
+            self.assertEqual(usb_info.get_size('sdb'), 15518924800)
+            self.assertEqual(usb_info.get_block_size('sdb'), 4096)
+
+
+class GetWriteBlockSizeTest(unittest.TestCase):
+    def test_optimal_io_size(self):
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/queue/optimal_io_size', '3145728')
+            write_sys_file(directory, 'sdb/queue/minimum_io_size', '512')
+            self.assertEqual(usb_info.get_write_block_size('sdb'), 3145728)
+
+    def test_minimum_io_size(self):
+        # Devices that don't have an optimal I/O size report 0, so the minimum I/O size is used.
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/queue/optimal_io_size', '0')
+            write_sys_file(directory, 'sdb/queue/minimum_io_size', '1536')
+            self.assertEqual(usb_info.get_write_block_size('sdb'), 4193280)
+
+    def test_larger_io_size(self):
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/queue/optimal_io_size', '8388608')
+            self.assertEqual(usb_info.get_write_block_size('sdb'), 8388608)
+
+    def test_default(self):
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/queue/optimal_io_size', '0')
+            write_sys_file(directory, 'sdb/queue/minimum_io_size', '0')
+            self.assertEqual(usb_info.get_write_block_size('sdb'), 4194304)
+
+            # Without the files (for example, on older kernels).
+            self.assertEqual(usb_info.get_write_block_size('sdc'), 4194304)
+
+
+class GetSpaceUsageTest(unittest.TestCase):
+    def test_usage(self):
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/size', '32768')
+            for partition, size in [['sdb1', '2048'], ['sdb2', '28672']]:
+                write_sys_file(directory, 'sdb/' + partition + '/partition', partition[-1])
+                write_sys_file(directory, 'sdb/' + partition + '/size', size)
+            self.assertEqual(usb_info.get_space_usage('sdb'), [16777216, 15728640, 1048576])
+
+    def test_no_partitions(self):
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/size', '32768')
+            os.makedirs(os.path.join(directory, 'sdb', 'queue'))
+            self.assertEqual(usb_info.get_space_usage('sdb'), [16777216, 0, 16777216])
+
+
+class GetHoldersTest(unittest.TestCase):
+    def test_holders(self):
+        # An LVM volume on the device, and an open LUKS mapping on its second partition.
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            write_sys_file(directory, 'sdb/holders/dm-0', '')
+            os.makedirs(os.path.join(directory, 'sdb', 'sdb1', 'holders'))
+            write_sys_file(directory, 'sdb/sdb1/partition', '1')
+            write_sys_file(directory, 'sdb/sdb2/holders/dm-1', '')
+            write_sys_file(directory, 'sdb/sdb2/partition', '2')
+            self.assertEqual(sorted(usb_info.get_holders('sdb')), ['dm-0', 'dm-1'])
+
+    def test_no_holders(self):
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.SYS_BLOCK_PATH', directory):
+            os.makedirs(os.path.join(directory, 'sdb', 'holders'))
+            os.makedirs(os.path.join(directory, 'sdb', 'sdb1', 'holders'))
+            write_sys_file(directory, 'sdb/sdb1/partition', '1')
+            self.assertEqual(usb_info.get_holders('sdb'), [])
+
+
+class GetProcessesUsingTest(unittest.TestCase):
+    def test_processes(self):
+        with tempfile.TemporaryDirectory() as sys_directory, tempfile.TemporaryDirectory() as proc_directory, \
+                mock.patch('usb_info.SYS_BLOCK_PATH', sys_directory), mock.patch('usb_info.PROC_PATH', proc_directory):
+            write_sys_file(sys_directory, 'sdb/sdb1/partition', '1')
+            # Each process has a directory with its name and links to its open files.
+            for pid, name, files in [['1234', 'gvfsd-metadata', ['/dev/null', '/dev/sdb1']],
+                                     ['2345', 'dd', ['/dev/sdb', '/dev/sdb']],
+                                     ['3456', 'bash', ['/dev/sdc', '/dev/sdb10']]]:
+                write_sys_file(proc_directory, pid + '/comm', name)
+                os.makedirs(os.path.join(proc_directory, pid, 'fd'))
+                for fd, path in enumerate(files):
+                    os.symlink(path, os.path.join(proc_directory, pid, 'fd', str(fd)))
+            # Directories that aren't processes are ignored, and so are processes that can't be read.
+            os.makedirs(os.path.join(proc_directory, 'sys'))
+            os.makedirs(os.path.join(proc_directory, '4567'))
+
+            self.assertEqual(sorted(usb_info.get_processes_using('sdb')), ['dd (2345)', 'gvfsd-metadata (1234)'])
+
+
+class GetPersistentPathsTest(unittest.TestCase):
+    def test_paths(self):
+        with tempfile.TemporaryDirectory() as directory:
+            for name, link, target in [['by-label', 'USB', '/dev/sdb1'], ['by-label', 'OTHER', '/dev/sdc1'],
+                                       ['by-uuid', '1234-ABCD', '/dev/sdb1'], ['by-partuuid', 'abcd-01', '/dev/sdb1']]:
+                os.makedirs(os.path.join(directory, name), exist_ok=True)
+                os.symlink(target, os.path.join(directory, name, link))
+
+            with mock.patch('usb_info.DEV_DISK_PATH', directory):
+                self.assertEqual(usb_info.get_persistent_paths('sdb', '1'),
+                                 [os.path.join(directory, 'by-label', 'USB'),
+                                  os.path.join(directory, 'by-uuid', '1234-ABCD'),
+                                  os.path.join(directory, 'by-partuuid', 'abcd-01')])
+
+    def test_no_links(self):
+        # udev may not be running (for example, in a container).
+        with tempfile.TemporaryDirectory() as directory, mock.patch('usb_info.DEV_DISK_PATH', directory):
+            self.assertEqual(usb_info.get_persistent_paths('sdb', '1'), [])
+
+
+class WaitForDeviceTest(unittest.TestCase):
+    def test_connected(self):
+        # The drive that was already connected is ignored.
+        id_lists = [['usb-Old_Drive_1234-0:0'], ['usb-Old_Drive_1234-0:0'],
+                    ['usb-Old_Drive_1234-0:0', 'usb-New_Drive_5678-0:0']]
+        with mock.patch('usb_info.get_id_list', side_effect=id_lists), mock.patch('time.sleep'):
+            self.assertEqual(usb_info.wait_for_device(), 'usb-New_Drive_5678-0:0')
+
+    def test_serial(self):
+        id_lists = [[], ['usb-Other_Drive_1111-0:0'], ['usb-Other_Drive_1111-0:0', 'usb-New_Drive_5678-0:0']]
+        with mock.patch('usb_info.get_id_list', side_effect=id_lists), mock.patch('time.sleep'):
+            self.assertEqual(usb_info.wait_for_device(serial='5678'), 'usb-New_Drive_5678-0:0')
+
+    def test_timeout(self):
+        with mock.patch('usb_info.get_id_list', return_value=[]), mock.patch('time.sleep'), \
+                mock.patch('time.monotonic', side_effect=[0, 0, 30, 61]):
+            self.assertEqual(usb_info.wait_for_device(60), '')
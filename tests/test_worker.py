@@ -0,0 +1,546 @@
+#   Copyright © 2017 Joaquim Monteiro
+#
+#   This file is part of USBMaker.
+#
+#   USBMaker is free software: you can redistribute it and/or modify
+#   it under the terms of the GNU General Public License as published by
+#   the Free Software Foundation, either version 3 of the License, or
+#   (at your option) any later version.
+#
+#   USBMaker is distributed in the hope that it will be useful,
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#   GNU General Public License for more details.
+#
+#   You should have received a copy of the GNU General Public License
+#   along with USBMaker.  If not, see <https://www.gnu.org/licenses/>.
+
+import unittest
+from unittest import mock
+import os
+import errno
+import subprocess
+import tempfile
+import tarfile
+import distutils.errors
+import worker
+import partitioning
+import formatting
+import mount
+import iso
+import checksum
+
+
+class WorkerTest(unittest.TestCase):
+    # The steps that need a real device are replaced, so the worker only runs its own logic.
+    def setUp(self):
+        self.directory = tempfile.TemporaryDirectory()
+        self.source = os.path.join(self.directory.name, 'source')
+        os.mkdir(self.source)
+        os.mkdir(os.path.join(self.directory.name, 'usb'))
+        self.connected = True
+        self.status = []
+        self.enabled = []
+        self.worker = worker.Worker(self.enabled.append, lambda progress: None, self.status.append,
+                                    lambda badblocks_file: None)
+
+        for name in ['mount.unmount_all_partitions', 'mount.mount', 'mount.unmount', 'partitioning.wipe_device',
+                     'partitioning.create_partition_table', 'partitioning.create_partition_wrapper',
+                     'partitioning.partprobe', 'formatting.create_filesystem']:
+            mock.patch(name).start()
+        mock.patch('partitioning.wait_for_partition', return_value=True).start()
+        mock.patch('partitioning.check_alignment', return_value=True).start()
+        mock.patch('usb_info.is_connected', side_effect=lambda device: self.connected).start()
+        # A 16MiB device, with 1MiB left before the partition.
+        mock.patch('usb_info.get_space_usage', return_value=[16777216, 15728640, 1048576]).start()
+        mock.patch('mount.get_temp_path',
+                   side_effect=lambda name, parent='': os.path.join(parent or self.directory.name, name)).start()
+        self.addCleanup(mock.patch.stopall)
+
+    def tearDown(self):
+        self.directory.cleanup()
+
+    # The notes added to the status when formatting or creating a drive from an iso file is completed.
+    space_note = '\nSpace used by the partition: 15MiB, unallocated: 1MiB.'
+
+    def format(self):
+        self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0)
+
+    def make_bootable_iso(self):
+        self.worker.make_bootable_iso('sdz', self.source, 'fat32', 'msdos', 'bios', ['syslinux', ''], 'USB', -1, 0,
+                                      '', ['', '', ''], ['', '', ''], '', 0)
+
+    def test_copy_error(self):
+        # copy_tree replaces the OSError with a DistutilsFileError, which must still be reported.
+        def copy_tree(*args, **kwargs):
+            try:
+                raise OSError(errno.ENOSPC, os.strerror(errno.ENOSPC), 'file')
+            except OSError as error:
+                raise distutils.errors.DistutilsFileError('could not write to \'file\': ' + error.strerror)
+
+        with mock.patch('distutils.dir_util.copy_tree', side_effect=copy_tree):
+            self.make_bootable_iso()
+        self.assertIn('could not write to \'file\': No space left on device', self.status[-1])
+        self.assertTrue(self.status[-1].startswith('Error: '))
+        self.assertEqual(self.enabled[-1], True)
+
+    def test_io_error(self):
+        formatting.create_filesystem.side_effect = OSError(errno.EIO, os.strerror(errno.EIO))
+        self.format()
+        self.assertEqual(self.status[-1], 'Error: creating the filesystem of partition 1 failed: reading or writing '
+                                          'the device failed (input/output error), which usually means that it is '
+                                          'failing.')
+        self.assertEqual(self.enabled[-1], True)
+
+    def test_disconnected(self):
+        def create_filesystem(*args, **kwargs):
+            self.connected = False
+            raise OSError(errno.EIO, os.strerror(errno.EIO))
+
+        formatting.create_filesystem.side_effect = create_filesystem
+        self.format()
+        self.assertEqual(self.status[-1], 'Error: the device was disconnected.')
+        self.assertEqual(self.enabled[-1], True)
+
+    def test_module_errors(self):
+        partitioning.create_partition_wrapper.side_effect = partitioning.InsufficientSpaceError
+        self.format()
+        self.assertEqual(self.status[-1], 'Error: the device is too small for the partition.')
+
+        partitioning.create_partition_wrapper.side_effect = None
+        formatting.create_filesystem.side_effect = formatting.InvalidOptionError
+        self.make_bootable_iso()
+        self.assertEqual(self.status[-1], 'Error: an option of the filesystem is invalid.')
+        self.assertEqual(self.enabled[-1], True)
+
+    def test_completed(self):
+        self.format()
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+        self.assertEqual(self.enabled, [False, True])
+
+    def test_check_filesystem(self):
+        with mock.patch('formatting.check_filesystem', return_value=False):
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'check_filesystem': True})
+        self.assertEqual(self.status[-1], 'Error: the new filesystem has errors, which usually means that the usb '
+                                          'drive is failing.')
+        self.assertEqual(self.enabled[-1], True)
+
+        # The drive is still created without the checker, but the user is told.
+        with mock.patch('formatting.check_filesystem', return_value=None):
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'check_filesystem': True})
+        self.assertEqual(self.status[-1], 'Completed.\nThe filesystem wasn\'t checked, since fsck.fat isn\'t '
+                                          'installed.' + self.space_note)
+
+    def test_ext4_features(self):
+        self.worker.format('sdz', 'ext4', 'msdos', 'USB', -1, 0, '', 0, {'ext4_features': '^64bit, ^metadata_csum'})
+        self.assertEqual(formatting.create_filesystem.call_args.kwargs['features'], ['^64bit', '^metadata_csum'])
+
+    def test_filesystem_errors(self):
+        # An error in one partition doesn't hide the error of another one.
+        errors = [('1', formatting.InvalidOptionError()),
+                  ('2', subprocess.CalledProcessError(1, ['mkfs.ext4', '/dev/sdz2']))]
+        with mock.patch('formatting.create_filesystems', return_value=errors):
+            self.format()
+        self.assertEqual(self.status[-1], 'Error: creating the filesystem of partition 1 failed: an option of the '
+                                          'filesystem is invalid.\n'
+                                          'Error: creating the filesystem of partition 2 failed: mkfs.ext4 failed '
+                                          '(exit status 1).')
+        self.assertEqual(self.enabled[-1], True)
+
+    def test_persistent_paths(self):
+        with mock.patch('usb_info.get_persistent_paths', return_value=['/dev/disk/by-label/USB',
+                                                                         '/dev/disk/by-uuid/1234-ABCD']):
+            self.format()
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note + '\nThe partition can be found at: '
+                                          '/dev/disk/by-label/USB, /dev/disk/by-uuid/1234-ABCD')
+        # udev has to create the links first.
+        partitioning.partprobe.assert_called_with('sdz')
+
+    def test_discard(self):
+        options = {'discard': True}
+        with mock.patch('partitioning.discard_device', return_value=True) as discard_device:
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, options)
+        discard_device.assert_called_with('sdz')
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+
+        # A device that doesn't support discard is still formatted.
+        with mock.patch('partitioning.discard_device', return_value=False):
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, options)
+        self.assertEqual(self.status[-1], 'Completed.\nThe device doesn\'t support discard (TRIM), so it wasn\'t '
+                                          'discarded.' + self.space_note)
+
+    def test_rescan(self):
+        image_path = self.create_image()
+        with mock.patch('dd.dd', return_value=4096), \
+                mock.patch('partitioning.rescan_device', return_value=True) as rescan_device:
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'rescan': True})
+        rescan_device.assert_called_with('sdz')
+        self.assertEqual(self.status[-1], 'Completed.')
+
+        # It isn't an error if the device can't be rescanned.
+        with mock.patch('dd.dd', return_value=4096), mock.patch('partitioning.rescan_device', return_value=False):
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'rescan': True})
+        self.assertEqual(self.status[-1], 'Completed.\nThe device couldn\'t be rescanned, so the new partitions may '
+                                          'only appear after reconnecting it.')
+
+        with mock.patch('dd.dd', return_value=4096), mock.patch('partitioning.rescan_device') as rescan_device:
+            self.worker.make_bootable_dd('sdz', image_path, 0, '')
+        rescan_device.assert_not_called()
+
+    def test_alignment(self):
+        partitioning.check_alignment.return_value = False
+        self.format()
+        partitioning.check_alignment.assert_called_with('sdz', '1')
+        self.assertEqual(self.status[-1], 'Completed.\nThe partition isn\'t optimally aligned, so writing to it may '
+                                          'be slower.' + self.space_note)
+
+    def test_disk_identifier(self):
+        with mock.patch('partitioning.set_disk_identifier') as set_disk_identifier:
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'disk_identifier': '1234abcd'})
+        set_disk_identifier.assert_called_with('sdz', 'msdos', '1234abcd')
+
+    def test_invalid_disk_identifier(self):
+        # The device isn't changed if the identifier can't be set.
+        with mock.patch('partitioning.set_disk_identifier') as set_disk_identifier:
+            self.worker.format('sdz', 'fat32', 'gpt', 'USB', -1, 0, '', 0, {'disk_identifier': '1234abcd'})
+        self.assertEqual(self.status[-1], 'Error: the disk identifier of a GPT partition table must be a GUID.')
+        self.assertEqual(self.enabled[-1], True)
+        partitioning.wipe_device.assert_not_called()
+        set_disk_identifier.assert_not_called()
+
+    def test_deterministic_name(self):
+        with mock.patch('partitioning.set_disk_identifier') as set_disk_identifier, \
+                mock.patch('partitioning.set_partition_guid') as set_partition_guid, \
+                mock.patch('partitioning.change_partition_name'):
+            self.worker.format('sdz', 'fat32', 'gpt', 'USB', -1, 0, '', 0, {'deterministic_name': 'image'})
+        set_disk_identifier.assert_called_with('sdz', 'gpt', partitioning.get_deterministic_guid('image'))
+        set_partition_guid.assert_called_with('sdz', '1', partitioning.get_deterministic_guid('image-partition1'))
+
+        # MBR partitions don't have a GUID, and the disk signature is shorter.
+        with mock.patch('partitioning.set_disk_identifier') as set_disk_identifier, \
+                mock.patch('partitioning.set_partition_guid') as set_partition_guid:
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'deterministic_name': 'image'})
+        set_disk_identifier.assert_called_with('sdz', 'msdos', partitioning.get_deterministic_guid('image')[:8])
+        set_partition_guid.assert_not_called()
+
+        # The disk identifier option takes precedence.
+        with mock.patch('partitioning.set_disk_identifier') as set_disk_identifier:
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0,
+                               {'deterministic_name': 'image', 'disk_identifier': '1234abcd'})
+        set_disk_identifier.assert_called_once_with('sdz', 'msdos', '1234abcd')
+
+    def test_gpt_attributes(self):
+        with mock.patch('partitioning.set_partition_attributes') as set_partition_attributes, \
+                mock.patch('partitioning.change_partition_name'):
+            self.worker.format('sdz', 'fat32', 'gpt', 'USB', -1, 0, '', 0, {'gpt_attributes': '62,63'})
+        set_partition_attributes.assert_called_with('sdz', '1', [62, 63])
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+
+    def test_invalid_gpt_attributes(self):
+        self.worker.format('sdz', 'fat32', 'gpt', 'USB', -1, 0, '', 0, {'gpt_attributes': '62,64'})
+        self.assertEqual(self.status[-1], 'Error: the partition attributes must be a comma-separated list of the '
+                                          'bits 0, 1, 2 and 48 to 63.')
+        self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'gpt_attributes': '62'})
+        self.assertEqual(self.status[-1], 'Error: only the partitions of a GPT partition table have attributes.')
+        partitioning.wipe_device.assert_not_called()
+
+    def test_gpt_entries(self):
+        with mock.patch('partitioning.change_partition_name'):
+            self.worker.format('sdz', 'fat32', 'gpt', 'USB', -1, 0, '', 0, {'gpt_entries': '256'})
+        partitioning.create_partition_table.assert_called_with('sdz', 'gpt', 256)
+
+        for gpt_entries in ['130', '8132', 'many']:
+            self.worker.format('sdz', 'fat32', 'gpt', 'USB', -1, 0, '', 0, {'gpt_entries': gpt_entries})
+            self.assertEqual(self.status[-1], 'Error: the number of GPT partition entries must be a multiple of 4 '
+                                              'from 128 to 8128.')
+        self.assertEqual(partitioning.create_partition_table.call_count, 1)
+
+    def test_partition_hashes(self):
+        with mock.patch('checksum.get_partition_hashes', return_value={'sdz1': 'a' * 64, 'sdz2': 'b' * 64}):
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'partition_hashes': True})
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note + '\nSHA256 of sdz1: ' + 'a' * 64 +
+                         '\nSHA256 of sdz2: ' + 'b' * 64)
+
+    def write_sums(self, image_hash):
+        with open(os.path.join(self.directory.name, 'SHA256SUMS'), 'w') as sums:
+            sums.write(image_hash + '  image.img\n')
+
+    def test_checksum(self):
+        image_path = self.create_image()
+        self.write_sums(checksum.get_file_hash(image_path))
+        with mock.patch('dd.dd', return_value=4096) as write_image:
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'verify_checksum': True})
+        write_image.assert_called_once()
+        self.assertEqual(self.status[-1], 'Completed.')
+
+    def test_checksum_mismatch(self):
+        image_path = self.create_image()
+        self.write_sums('a' * 64)
+        with mock.patch('dd.dd') as write_image:
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'verify_checksum': True})
+        self.assertEqual(self.status[-1], 'Error: the checksum of the image doesn\'t match the one in SHA256SUMS, '
+                                          'so it\'s corrupted or was modified.')
+        write_image.assert_not_called()
+
+        # Nothing is done to the device either with the ISO Image method.
+        self.worker.make_bootable_iso('sdz', image_path, 'fat32', 'msdos', 'bios', ['syslinux', ''], 'USB', -1, 0,
+                                      '', ['', '', ''], ['', '', ''], '', 0, {'verify_checksum': True})
+        self.assertTrue(self.status[-1].startswith('Error: the checksum of the image doesn\'t match'))
+        partitioning.wipe_device.assert_not_called()
+
+    def test_sums_file_not_found(self):
+        image_path = self.create_image()
+        self.worker.make_bootable_dd('sdz', image_path, 0, '', {'verify_checksum': True})
+        self.assertEqual(self.status[-1], 'Error: no SHA256SUMS file listing the image was found next to it.')
+
+        # The sums file can be anywhere if it's given.
+        sums_file = os.path.join(self.source, 'hashes.txt')
+        with open(sums_file, 'w') as sums:
+            sums.write(checksum.get_file_hash(image_path) + '  image.img\n')
+        with mock.patch('dd.dd', return_value=4096):
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'verify_checksum': True, 'sums_file': sums_file})
+        self.assertEqual(self.status[-1], 'Completed.')
+
+    def test_check_capacity(self):
+        with mock.patch('dd.check_capacity', return_value=8 * 1073741824), \
+                mock.patch('usb_info.get_size', return_value=64 * 1073741824):
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'check_capacity': True})
+        self.assertEqual(self.status[-1], 'Error: the device can only store about 8192MiB of the 65536MiB it '
+                                          'reports, which means that it\'s counterfeit.')
+        self.assertEqual(self.enabled[-1], True)
+        partitioning.wipe_device.assert_not_called()
+
+        with mock.patch('dd.check_capacity', return_value=16777216), \
+                mock.patch('usb_info.get_size', return_value=16777216):
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'check_capacity': True})
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+
+    def test_info_file(self):
+        image_path = self.create_image()
+        with mock.patch('iso.copy_iso_contents'), mock.patch('iso.create_bootable_usb'), \
+                mock.patch('mount.mount_iso'), mock.patch('partitioning.mark_bootable'), \
+                mock.patch('partitioning.get_bootable_partitions', return_value=['sdz1']):
+            self.worker.make_bootable_iso('sdz', image_path, 'fat32', 'msdos', 'bios', ['syslinux', ''], 'USB', -1,
+                                          0, '', ['', '', ''], ['', '', ''], '', 0,
+                                          {'write_info_file': True, 'info_file_name': 'info.txt'})
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+        with open(os.path.join(self.directory.name, 'usb', 'info.txt')) as info_file:
+            info = info_file.read()
+        self.assertIn('Source: image.img\n', info)
+        self.assertIn('SHA256: ' + checksum.get_file_hash(image_path) + '\n', info)
+
+        # An extracted iso file has no hash.
+        with mock.patch('iso.create_bootable_usb'), mock.patch('partitioning.mark_bootable'), \
+                mock.patch('partitioning.get_bootable_partitions', return_value=['sdz1']):
+            self.worker.make_bootable_iso('sdz', self.source, 'fat32', 'msdos', 'bios', ['syslinux', ''], 'USB', -1,
+                                          0, '', ['', '', ''], ['', '', ''], '', 0, {'write_info_file': True})
+        self.assertEqual(self.status[-1], 'Completed.\nThe info file wasn\'t written, since there is no iso file to '
+                                          'compute the hash of.' + self.space_note)
+        self.assertFalse(os.path.exists(os.path.join(self.directory.name, 'usb', 'USBMAKER_INFO.txt')))
+
+    def test_archive(self):
+        archive_path = os.path.join(self.directory.name, 'data.tar')
+        with open(os.path.join(self.source, 'readme.txt'), 'w') as readme:
+            readme.write('readme')
+        with tarfile.open(archive_path, 'w') as archive:
+            archive.add(os.path.join(self.source, 'readme.txt'), 'readme.txt')
+
+        self.worker.format('sdz', 'ext4', 'msdos', 'USB', -1, 0, '', 0, {'archive': archive_path})
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+        self.assertTrue(os.path.isfile(os.path.join(self.directory.name, 'usb', 'readme.txt')))
+        mount.unmount.assert_called_with(os.path.join(self.directory.name, 'usb'))
+
+    def test_archive_error(self):
+        with mock.patch('iso.extract_archive', side_effect=iso.ExtractionError):
+            self.worker.format('sdz', 'ext4', 'msdos', 'USB', -1, 0, '', 0, {'archive': 'data.tar'})
+        self.assertEqual(self.status[-1], 'Error: the archive couldn\'t be extracted (it may be corrupted, or have '
+                                          'files that the filesystem doesn\'t support, like symlinks on FAT32).')
+        self.assertEqual(self.enabled[-1], True)
+
+    @unittest.skipUnless(os.geteuid() == 0, 'only root can change the owner of files')
+    def test_set_ownership(self):
+        usb_mountpoint = os.path.join(self.directory.name, 'usb')
+        open(os.path.join(usb_mountpoint, 'readme.txt'), 'w').close()
+        with mock.patch.dict('os.environ', {'SUDO_UID': '65534'}), \
+                mock.patch('uid_info.get_gid_from_uid', return_value='100'):
+            os.environ.pop('PKEXEC_UID', None)
+            self.worker.format('sdz', 'ext4', 'msdos', 'USB', -1, 0, '', 0, {'set_ownership': True})
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+        for path in [usb_mountpoint, os.path.join(usb_mountpoint, 'readme.txt')]:
+            self.assertEqual((os.stat(path).st_uid, os.stat(path).st_gid), (65534, 100))
+
+    def test_set_ownership_notes(self):
+        with mock.patch.dict('os.environ', {}), mock.patch('iso.set_ownership') as set_ownership:
+            os.environ.pop('PKEXEC_UID', None)
+            os.environ.pop('SUDO_UID', None)
+            self.worker.format('sdz', 'ext4', 'msdos', 'USB', -1, 0, '', 0, {'set_ownership': True})
+            self.assertEqual(self.status[-1], 'Completed.\nThe owner of the files wasn\'t set, since USBMaker '
+                                              'wasn\'t started with pkexec or sudo.' + self.space_note)
+
+            os.environ['SUDO_UID'] = '1000'
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'set_ownership': True})
+            self.assertEqual(self.status[-1], 'Completed.\nThe owner of the files wasn\'t set, since the filesystem '
+                                              'doesn\'t store owners.' + self.space_note)
+        set_ownership.assert_not_called()
+
+    def test_custom_config(self):
+        config_path = os.path.join(self.directory.name, 'syslinux.cfg')
+        with open(config_path, 'w') as config_file:
+            config_file.write('LABEL linux\n  KERNEL /vmlinuz\n  APPEND initrd=/initrd.img\n')
+        open(os.path.join(self.source, 'vmlinuz'), 'w').close()
+
+        with mock.patch('iso.create_bootable_usb'), mock.patch('partitioning.mark_bootable'), \
+                mock.patch('partitioning.get_bootable_partitions', return_value=['sdz1']):
+            self.worker.make_bootable_iso('sdz', self.source, 'fat32', 'msdos', 'bios', ['', 'syslinux'], 'USB', -1,
+                                          0, '', ['', '', ''], ['', '', ''], '', 0, {'custom_config': config_path})
+        self.assertEqual(self.status[-1], 'Completed.\nThe boot menu config file references files that aren\'t on '
+                                          'the usb drive: /initrd.img' + self.space_note)
+        with open(os.path.join(self.directory.name, 'usb', 'syslinux.cfg')) as config_file:
+            self.assertIn('KERNEL /vmlinuz', config_file.read())
+
+    def test_custom_config_not_found(self):
+        self.worker.make_bootable_iso('sdz', self.source, 'fat32', 'msdos', 'bios', ['', 'syslinux'], 'USB', -1, 0,
+                                      '', ['', '', ''], ['', '', ''], '', 0, {'custom_config': '/nonexistent.cfg'})
+        self.assertEqual(self.status[-1], 'Error: the boot menu config file /nonexistent.cfg doesn\'t exist.')
+        partitioning.wipe_device.assert_not_called()
+
+    def test_wait_for_reconnect(self):
+        # The device is connected again as sdy after being written to.
+        image_path = self.create_image()
+        device_id = 'usb-SanDisk_Cruzer_Blade_4C530001234567891234-0:0'
+
+        def write_image(*args, **kwargs):
+            self.connected = False
+            return 4096
+
+        with mock.patch('dd.dd', side_effect=write_image), \
+                mock.patch('usb_info.is_connected', side_effect=lambda device: device == 'sdy'), \
+                mock.patch('usb_info.wait_for_device', return_value=device_id) as wait_for_device, \
+                mock.patch('usb_info.get_block_device_name', return_value='sdy'), \
+                mock.patch('dd.dd_check', return_value=True) as dd_check:
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'wait_for_reconnect': True, 'device_id': device_id,
+                                                                     'verify': 'full'})
+        wait_for_device.assert_called_with(worker.RECONNECT_TIMEOUT, serial=device_id)
+        self.assertEqual(dd_check.call_args.args[1], 'sdy')
+        self.assertEqual(self.status[-1], 'Completed (the written image was verified).')
+
+        # It's still an error if the device doesn't come back.
+        with mock.patch('dd.dd', side_effect=write_image), mock.patch('usb_info.wait_for_device', return_value=''):
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'wait_for_reconnect': True, 'device_id': device_id})
+        self.assertEqual(self.status[-1], 'Error: the device was disconnected.')
+
+    def test_eject(self):
+        with mock.patch('mount.eject') as eject:
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'eject': True})
+        eject.assert_called_once_with('sdz')
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+
+        # The device isn't ejected after an error.
+        formatting.create_filesystem.side_effect = formatting.InvalidOptionError
+        with mock.patch('mount.eject') as eject:
+            self.worker.format('sdz', 'fat32', 'msdos', 'USB', -1, 0, '', 0, {'eject': True})
+        eject.assert_not_called()
+
+    def create_image(self):
+        image_path = os.path.join(self.directory.name, 'image.img')
+        with open(image_path, 'wb') as image_file:
+            image_file.write(bytes(4096))
+        return image_path
+
+    def test_image_changed(self):
+        image_path = self.create_image()
+
+        def write_image(filename, device, progress=None):
+            # The image is replaced while it's written.
+            os.remove(filename)
+            with open(filename, 'wb') as image_file:
+                image_file.write(bytes(8192))
+            return 8192
+
+        with mock.patch('dd.dd', side_effect=write_image):
+            self.worker.make_bootable_dd('sdz', image_path, 0, '')
+            self.assertEqual(self.status[-1], 'Completed.')
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'check_image_state': True})
+            self.assertEqual(self.status[-1], 'Error: the image was changed while it was being written.')
+
+    def test_verify(self):
+        image_path = self.create_image()
+        with mock.patch('dd.dd', return_value=4096), mock.patch('dd.dd_check', return_value=False) as dd_check:
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'verify': 'full'})
+        dd_check.assert_called_once_with(image_path, 'sdz', work_dir='')
+        self.assertEqual(self.status[-1], 'Error: the data on the device doesn\'t match the image.')
+
+    def test_verify_sampled(self):
+        image_path = self.create_image()
+        result = {'checked': [(0, 1024), (1024, 1024), (3072, 1024)], 'mismatched': [(3072, 1024)]}
+        with mock.patch('dd.dd', return_value=4096), mock.patch('dd.dd_check_sampled', return_value=result):
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'verify': 'sampled'})
+            self.assertEqual(self.status[-1], 'Error: 1 of the 3 parts of the image that were checked don\'t match '
+                                              'the data on the device.')
+            result['mismatched'] = []
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'verify': 'sampled'})
+            self.assertEqual(self.status[-1], 'Completed (3 parts of the written image were verified).')
+
+    def test_insufficient_temp_space(self):
+        image_path = self.create_image()
+        with mock.patch('dd.dd', return_value=4096), mock.patch('dd.drop_cache') as drop_cache, \
+                mock.patch('shutil.disk_usage', return_value=mock.Mock(free=4095)):
+            self.worker.make_bootable_dd('sdz', image_path, 0, '', {'verify': 'full', 'work_dir': self.source})
+        drop_cache.assert_not_called()
+        self.assertEqual(self.status[-1], 'Error: there isn\'t enough free space in ' + self.source +
+                         ' to verify the image.')
+        self.assertEqual(self.enabled[-1], True)
+
+    def test_file_list(self):
+        # The file list only has the copied files, and not the bootloader's.
+        with open(os.path.join(self.source, 'readme.txt'), 'w') as readme:
+            readme.write('readme')
+
+        def create_bootable_usb(device, usb_mountpoint, *args):
+            open(os.path.join(usb_mountpoint, 'ldlinux.sys'), 'w').close()
+
+        with mock.patch('iso.create_bootable_usb', side_effect=create_bootable_usb), \
+                mock.patch('partitioning.mark_bootable'), mock.patch('partitioning.get_bootable_partitions',
+                                                                     return_value=['sdz1']):
+            self.make_bootable_iso()
+        self.assertEqual(self.status[-1], 'Completed.' + self.space_note)
+        self.assertEqual(iso.read_file_list(os.path.join(self.directory.name, 'usb')), {'readme.txt'})
+
+    def test_update(self):
+        usb_mountpoint = os.path.join(self.directory.name, 'usb')
+        for name in ['readme.txt', 'old.txt']:
+            with open(os.path.join(self.source, name), 'w') as file:
+                file.write(name)
+        iso.update_contents(self.source, usb_mountpoint)
+        open(os.path.join(usb_mountpoint, 'ldlinux.sys'), 'w').close()
+        os.remove(os.path.join(self.source, 'old.txt'))
+        with open(os.path.join(self.source, 'new.txt'), 'w') as file:
+            file.write('new')
+
+        self.worker.make_bootable_iso('sdz', self.source, 'fat32', 'msdos', 'bios', ['', ''], 'USB', -1, 0, '',
+                                      ['', '', ''], ['', '', ''], '', 0, {'update': True, 'delete_removed': True})
+        self.assertEqual(self.status[-1], 'Completed (files added: 1, updated: 0, deleted: 1, unchanged: 1).')
+        self.assertEqual(sorted(os.listdir(usb_mountpoint)),
+                         ['USBMAKER_FILES.txt', 'ldlinux.sys', 'new.txt', 'readme.txt'])
+        partitioning.wipe_device.assert_not_called()
+        formatting.create_filesystem.assert_not_called()
+
+    def test_verify_files(self):
+        for name in ['a.txt', 'b.txt']:
+            with open(os.path.join(self.source, name), 'w') as file:
+                file.write(name)
+
+        def copy_iso_contents(iso_mountpoint, usb_mountpoint, **kwargs):
+            # b.txt isn't copied correctly.
+            with open(os.path.join(usb_mountpoint, 'a.txt'), 'w') as file:
+                file.write('a.txt')
+            with open(os.path.join(usb_mountpoint, 'b.txt'), 'w') as file:
+                file.write('B.TXT')
+
+        with mock.patch('iso.copy_iso_contents', side_effect=copy_iso_contents), \
+                mock.patch('iso.create_bootable_usb') as create_bootable_usb:
+            self.worker.make_bootable_iso('sdz', self.source, 'fat32', 'msdos', 'bios', ['', ''], 'USB', -1, 0, '',
+                                          ['', '', ''], ['', '', ''], '', 0, {'verify': 'full'})
+        self.assertEqual(self.status[-1], 'Error: 1 of the copied files don\'t match the iso file (b.txt).')
+        create_bootable_usb.assert_not_called()
+        self.assertEqual(self.enabled[-1], True)